@@ -1,3 +1,4 @@
+#![allow(deprecated)]
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use pretzel::*;
 // use errors::{Error, Result};
@@ -130,6 +131,32 @@ fn criterion_benchmark(c: &mut Criterion) {
     c.bench_function("combine_shares", |b| {
         b.iter(|| combine_shares(msg, delta, sign_shares.clone(), &shares[0], l, pad.clone()))
     });
+
+    let public_pkg = PublicPackage {
+        v: v.clone(),
+        verification_keys: verification_keys.clone(),
+        public_key: RsaPublicKey::from(&sk),
+        group_size: l,
+    };
+    let secret_pkg = SecretPackage {
+        uid: 0,
+        gid: None,
+        share: shares[0].clone(),
+    };
+    c.bench_function("key_context_new", |b| {
+        b.iter(|| context::KeyContext::new(&public_pkg))
+    });
+    let key_context = context::KeyContext::new(&public_pkg).unwrap();
+    c.bench_function("key_context_sign", |b| {
+        b.iter(|| key_context.sign(&secret_pkg, msg, pad.clone()))
+    });
+    c.bench_function("key_context_verify_proof", |b| {
+        b.iter(|| key_context.verify_proof(msg, &sign_shares[0], pad.clone()))
+    });
+    c.bench_function("key_context_combine_shares", |b| {
+        b.iter(|| key_context.combine_shares(msg, sign_shares.clone(), pad.clone()))
+    });
+
     let n = (sk.p.clone() * sk.q.clone());
     let r_privkey =
         RsaPrivateKey::from_components(n.clone(), sk.e, sk.d, vec![sk.p.clone(), sk.q.clone()])
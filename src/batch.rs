@@ -0,0 +1,933 @@
+// Signing many messages with the same share in one round and verifying many proofs at once.
+//
+// Instead of one proof per message, a signer proves with a single proof that all of its signature
+// shares were computed with the same exponent. The statements are aggregated by a random linear
+// combination, X = \prod x_j~^{\rho_j} and Y = \prod (x_{i,j}^2)^{\rho_j}, where the coefficients
+// \rho_j are derived from the hash of all the shares, and log_v(v_i) = log_X(Y) is then proven the
+// same way as in `sign_with_share`. The hash and the length of the challenge are those of the
+// `ProofVersion` of the batch share.
+
+use crate::session::{SigningContext, PROTOCOL_VERSION};
+use crate::transcript::{ProofPolicy, ProofVersion, Transcript};
+use crate::{
+    check_raw_proof, combine_partial_exponentiations, digest_msg, factorial, proof_challenge,
+    PaddingScheme, PartialMessageSignature, RsaSecretShare, RsaVerificationKey, SecretPackage,
+    SigningError,
+};
+use num_bigint::{BigUint, ModInverse, RandBigInt};
+use num_integer::Integer;
+use num_traits::{One, Pow, Zero};
+use rand_chacha::{rand_core::SeedableRng, ChaCha20Rng};
+use rayon::prelude::*;
+use rsa::hazmat::uint_to_zeroizing_be_pad;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// Length of the coefficients of the random linear combination.
+const COEFFICIENT_BYTES: usize = 16;
+
+/// Signature shares of a single signer over a batch of messages with one proof of correctness.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchPartialSignature {
+    pub id: usize,
+    pub xis: Vec<BigUint>,
+    pub z: BigUint,
+    pub c: BigUint,
+    pub proof_version: ProofVersion,
+}
+
+impl SecretPackage {
+    pub fn sign_batch(
+        &self,
+        messages: &[&[u8]],
+        max_signers: u16,
+        v: BigUint,
+        vi: &RsaVerificationKey,
+        padding_scheme: PaddingScheme,
+    ) -> Result<BatchPartialSignature, SigningError> {
+        if messages.is_empty() {
+            return Err(SigningError::MessageCannotBeSigned);
+        }
+        let delta = factorial(max_signers as usize);
+        Ok(sign_batch_with_share(
+            messages,
+            delta,
+            &self.share,
+            &v,
+            vi,
+            padding_scheme,
+            ProofVersion::CURRENT,
+        ))
+    }
+}
+
+fn digest_batch(
+    messages: &[&[u8]],
+    scheme: PaddingScheme,
+    n: &BigUint,
+    key_bytes_size: usize,
+) -> Vec<BigUint> {
+    messages
+        .par_iter()
+        .map(|msg| digest_msg(msg, scheme, n, key_bytes_size))
+        .collect()
+}
+
+/// x_j~ = x_j^{4 \delta} mod n
+fn tilde(xs: &[BigUint], delta: usize, n: &BigUint) -> Vec<BigUint> {
+    let exponent = BigUint::from(4 * delta);
+    xs.par_iter().map(|x| x.modpow(&exponent, n)).collect()
+}
+
+/// \prod bases_j^{coefficients_j} mod n
+fn aggregate(bases: &[BigUint], coefficients: &[BigUint], n: &BigUint) -> BigUint {
+    bases
+        .par_iter()
+        .zip(coefficients.par_iter())
+        .map(|(base, coefficient)| base.modpow(coefficient, n))
+        .reduce(BigUint::one, |acc, value| (acc * value).mod_floor(n))
+}
+
+/// The coefficients bind all the shares, so that a signer cannot choose the shares after knowing
+/// the coefficients. The transcript goes on into `batch_challenge`.
+fn batch_coefficients(
+    proof_version: ProofVersion,
+    v: &BigUint,
+    vi: &RsaVerificationKey,
+    x_tildes: &[BigUint],
+    xis_squared: &[BigUint],
+) -> (Transcript, Vec<BigUint>) {
+    let mut transcript = Transcript::with_hash(b"pretzel-batch-proof", proof_version.hash());
+    transcript.append_u64(b"id", vi.id as u64);
+    transcript.append_biguint(b"v", v);
+    transcript.append_biguint(b"vi", &vi.key);
+    transcript.append_u64(b"messages", x_tildes.len() as u64);
+    for (x_tilde, xi_squared) in x_tildes.iter().zip(xis_squared) {
+        transcript.append_biguint(b"x~", x_tilde);
+        transcript.append_biguint(b"xi^2", xi_squared);
+    }
+    let coefficients = x_tildes
+        .iter()
+        .map(|_| transcript.challenge(b"rho", 8 * COEFFICIENT_BYTES))
+        .collect();
+    (transcript, coefficients)
+}
+
+fn batch_challenge(
+    proof_version: ProofVersion,
+    mut transcript: Transcript,
+    x_aggregate: &BigUint,
+    y_aggregate: &BigUint,
+    v_prime: &BigUint,
+    x_prime: &BigUint,
+) -> BigUint {
+    transcript.append_biguint(b"X", x_aggregate);
+    transcript.append_biguint(b"Y", y_aggregate);
+    transcript.append_biguint(b"v'", v_prime);
+    transcript.append_biguint(b"X'", x_prime);
+    transcript.challenge(b"c", proof_version.challenge_bits())
+}
+
+/// x_{i,j} = x_j^{2 \delta s_i} for every message in parallel, with one aggregated proof
+pub fn sign_batch_with_share(
+    messages: &[&[u8]],
+    delta: usize,
+    share: &RsaSecretShare,
+    v: &BigUint,
+    vi: &RsaVerificationKey,
+    scheme: PaddingScheme,
+    proof_version: ProofVersion,
+) -> BatchPartialSignature {
+    let n = &share.n;
+    let xs = digest_batch(messages, scheme, n, share.key_bytes_size);
+
+    let mut exponent = BigUint::from(2u8);
+    exponent *= BigUint::from(delta);
+    exponent *= &share.share;
+    let xis: Vec<BigUint> = xs.par_iter().map(|x| x.modpow(&exponent, n)).collect();
+    let two = BigUint::from(2u8);
+    let xis_squared: Vec<BigUint> = xis.par_iter().map(|xi| xi.modpow(&two, n)).collect();
+    let x_tildes = tilde(&xs, delta, n);
+
+    let (transcript, coefficients) =
+        batch_coefficients(proof_version, v, vi, &x_tildes, &xis_squared);
+    let x_aggregate = aggregate(&x_tildes, &coefficients, n);
+    let y_aggregate = aggregate(&xis_squared, &coefficients, n);
+
+    let bound = two.pow(n.bits() + 2 * proof_version.challenge_bits()) - BigUint::one();
+    let mut rng = ChaCha20Rng::from_entropy();
+    let r = rng.gen_biguint_range(&BigUint::zero(), &bound);
+    let v_prime = v.modpow(&r, n);
+    let x_prime = x_aggregate.modpow(&r, n);
+
+    let c = batch_challenge(
+        proof_version,
+        transcript,
+        &x_aggregate,
+        &y_aggregate,
+        &v_prime,
+        &x_prime,
+    );
+    let z = &share.share * &c + r;
+
+    BatchPartialSignature {
+        id: share.id,
+        xis,
+        z,
+        c,
+        proof_version,
+    }
+}
+
+fn inverse(value: BigUint, n: &BigUint) -> Option<BigUint> {
+    value.mod_inverse(n).and_then(|inv| inv.to_biguint())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn verify_batch_proof(
+    messages: &[&[u8]],
+    v: &BigUint,
+    delta: usize,
+    vi: &RsaVerificationKey,
+    bps: &BatchPartialSignature,
+    n: &BigUint,
+    key_bytes_size: usize,
+    scheme: PaddingScheme,
+    policy: &ProofPolicy,
+) -> bool {
+    let proof_version = bps.proof_version;
+    if messages.len() != bps.xis.len()
+        || messages.is_empty()
+        || !policy.accepts(&proof_version)
+        || bps.c.bits() > proof_version.challenge_bits()
+    {
+        return false;
+    }
+    let xs = digest_batch(messages, scheme, n, key_bytes_size);
+    let two = BigUint::from(2u8);
+    let xis_squared: Vec<BigUint> = bps.xis.par_iter().map(|xi| xi.modpow(&two, n)).collect();
+    let x_tildes = tilde(&xs, delta, n);
+
+    let (transcript, coefficients) =
+        batch_coefficients(proof_version, v, vi, &x_tildes, &xis_squared);
+    let x_aggregate = aggregate(&x_tildes, &coefficients, n);
+    let y_aggregate = aggregate(&xis_squared, &coefficients, n);
+
+    // v^z * v_i^{-c} and X^z * Y^{-c}
+    let Some(vi_inverse) = inverse(vi.key.modpow(&bps.c, n), n) else {
+        return false;
+    };
+    let Some(y_inverse) = inverse(y_aggregate.modpow(&bps.c, n), n) else {
+        return false;
+    };
+    let v_prime = (v.modpow(&bps.z, n) * vi_inverse).mod_floor(n);
+    let x_prime = (x_aggregate.modpow(&bps.z, n) * y_inverse).mod_floor(n);
+
+    bps.c
+        == batch_challenge(
+            proof_version,
+            transcript,
+            &x_aggregate,
+            &y_aggregate,
+            &v_prime,
+            &x_prime,
+        )
+}
+
+/// Combine the batch shares into one signature per message, in the order of the messages.
+///
+/// A signer counts once, its later batch shares are ignored. Every signature is checked by
+/// y^e = x, so fewer shares than the threshold are an error.
+pub fn combine_batch_shares(
+    messages: &[&[u8]],
+    delta: usize,
+    batch_shares: Vec<BatchPartialSignature>,
+    key_share: &RsaSecretShare,
+    l: usize,
+    scheme: PaddingScheme,
+) -> Result<Vec<Vec<u8>>, SigningError> {
+    if batch_shares.is_empty()
+        || batch_shares
+            .iter()
+            .any(|bps| bps.xis.len() != messages.len())
+    {
+        return Err(SigningError::SigningError);
+    }
+    let mut ids = HashSet::new();
+    let batch_shares: Vec<&BatchPartialSignature> = batch_shares
+        .iter()
+        .filter(|bps| ids.insert(bps.id))
+        .collect();
+    let n = &key_share.n;
+    let xs = digest_batch(messages, scheme, n, key_share.key_bytes_size);
+    xs.par_iter()
+        .enumerate()
+        .map(|(j, x)| {
+            let partials: Vec<(usize, &BigUint)> = batch_shares
+                .iter()
+                .map(|bps| (bps.id, &bps.xis[j]))
+                .collect();
+            let signature =
+                combine_partial_exponentiations(x, delta, &partials, n, &key_share.e, l)?;
+            if &signature.modpow(&key_share.e, n) != x {
+                return Err(SigningError::SigningError);
+            }
+            uint_to_zeroizing_be_pad(signature, key_share.key_bytes_size)
+                .map_err(|_| SigningError::SigningError)
+        })
+        .collect()
+}
+
+/// A proof to be checked by `verify_proofs_batch`.
+#[derive(Debug, Clone, Copy)]
+pub struct BatchVerificationEntry<'a> {
+    pub msg: &'a [u8],
+    pub vi: &'a RsaVerificationKey,
+    pub pms: &'a PartialMessageSignature,
+    /// The context the proof is bound to, e.g. `SigningContext::new` for the session and signer
+    pub context: Option<&'a SigningContext>,
+}
+
+struct PreparedEntry<'a> {
+    index: usize,
+    message: usize,
+    vi: &'a RsaVerificationKey,
+    pms: &'a PartialMessageSignature,
+    xi_squared: BigUint,
+    v_prime: &'a BigUint,
+    x_prime: &'a BigUint,
+}
+
+enum Check<'a> {
+    Valid,
+    Invalid(usize),
+    Combined(PreparedEntry<'a>),
+}
+
+fn product<I: ParallelIterator<Item = BigUint>>(values: I, n: &BigUint) -> BigUint {
+    values.reduce(BigUint::one, |acc, value| (acc * value).mod_floor(n))
+}
+
+/// Verify the proofs of many partial signatures, from any signers and over any messages.
+///
+/// The challenge hash is checked for every proof separately, while the group equations
+/// v^z = v^r * v_i^c and x~^z = x~^r * (x_i^2)^c of all the proofs are combined with random
+/// 128-bit exponents into one equation for v and one for x~. Every message and every verification
+/// key is then raised to a single exponent. When the combined equations do not hold, the entries
+/// are halved until the invalid ones are found. The proofs without commitments are verified one by
+/// one. Every proof is checked against the context of its entry and has to be of a version the
+/// policy accepts.
+///
+/// The proofs are accepted up to a factor of -1 in the commitments. The exponents are odd, so a
+/// single negated commitment is caught, but the signs of two negated commitments cancel, while
+/// `verify_raw_proof` rejects each of them. Such a proof differs from a valid one only by the
+/// signs, a wrong share is still caught when the signatures are checked by y^e = x.
+///
+/// Returns the sorted indices of the entries whose proofs do not verify.
+pub fn verify_proofs_batch(
+    entries: &[BatchVerificationEntry],
+    v: &BigUint,
+    delta: usize,
+    n: &BigUint,
+    key_bytes_size: usize,
+    scheme: PaddingScheme,
+    policy: &ProofPolicy,
+) -> Result<(), Vec<usize>> {
+    // every message is encoded only once, no matter how many signers signed it
+    let mut message_ids: HashMap<&[u8], usize> = HashMap::new();
+    let mut messages: Vec<&[u8]> = vec![];
+    let entry_messages: Vec<usize> = entries
+        .iter()
+        .map(|entry| {
+            *message_ids.entry(entry.msg).or_insert_with(|| {
+                messages.push(entry.msg);
+                messages.len() - 1
+            })
+        })
+        .collect();
+    let xs = digest_batch(&messages, scheme, n, key_bytes_size);
+    // the hash is over the unreduced x~, see `verify_raw_proof`
+    let x_tildes: Vec<BigUint> = xs.par_iter().map(|x| x.pow(4 * delta)).collect();
+
+    let two = BigUint::from(2u8);
+    let checks: Vec<Check> = entries
+        .par_iter()
+        .zip(entry_messages.par_iter())
+        .enumerate()
+        .map(|(index, (entry, &message))| {
+            if entry.context.is_some_and(|context| {
+                context.protocol_version != PROTOCOL_VERSION || context.signer_id != entry.pms.id
+            }) {
+                return Check::Invalid(index);
+            }
+            let Some((v_prime, x_prime)) = &entry.pms.commitments else {
+                return match check_raw_proof(
+                    &xs[message],
+                    v,
+                    delta,
+                    entry.vi,
+                    entry.pms,
+                    n,
+                    entry.context,
+                    policy,
+                ) {
+                    true => Check::Valid,
+                    false => Check::Invalid(index),
+                };
+            };
+            let xi_squared = entry.pms.xi.modpow(&two, n);
+            if v_prime >= n
+                || x_prime >= n
+                || !policy.accepts(&entry.pms.proof_version)
+                || entry.pms.c.bits() > entry.pms.proof_version.challenge_bits()
+                || proof_challenge(
+                    entry.pms.proof_version,
+                    entry.context,
+                    v,
+                    &x_tildes[message],
+                    entry.vi,
+                    &xi_squared,
+                    v_prime,
+                    x_prime,
+                ) != entry.pms.c
+            {
+                return Check::Invalid(index);
+            }
+            Check::Combined(PreparedEntry {
+                index,
+                message,
+                vi: entry.vi,
+                pms: entry.pms,
+                xi_squared,
+                v_prime,
+                x_prime,
+            })
+        })
+        .collect();
+
+    let mut invalid = vec![];
+    let mut combined = vec![];
+    for check in checks {
+        match check {
+            Check::Valid => (),
+            Check::Invalid(index) => invalid.push(index),
+            Check::Combined(entry) => combined.push(entry),
+        }
+    }
+    let x_tildes: Vec<BigUint> = x_tildes.into_par_iter().map(|x| x.mod_floor(n)).collect();
+    find_invalid(&combined, v, &x_tildes, n, &mut invalid);
+
+    if invalid.is_empty() {
+        return Ok(());
+    }
+    invalid.sort_unstable();
+    Err(invalid)
+}
+
+fn find_invalid(
+    entries: &[PreparedEntry],
+    v: &BigUint,
+    x_tildes: &[BigUint],
+    n: &BigUint,
+    invalid: &mut Vec<usize>,
+) {
+    if entries.is_empty() || combined_check(entries, v, x_tildes, n) {
+        return;
+    }
+    if entries.len() == 1 {
+        invalid.push(entries[0].index);
+        return;
+    }
+    let (left, right) = entries.split_at(entries.len() / 2);
+    find_invalid(left, v, x_tildes, n, invalid);
+    find_invalid(right, v, x_tildes, n, invalid);
+}
+
+fn combined_check(
+    entries: &[PreparedEntry],
+    v: &BigUint,
+    x_tildes: &[BigUint],
+    n: &BigUint,
+) -> bool {
+    let mut rng = ChaCha20Rng::from_entropy();
+    let rhos: Vec<BigUint> = entries
+        .iter()
+        .map(|_| rng.gen_biguint(8 * COEFFICIENT_BYTES) | BigUint::one())
+        .collect();
+
+    // the exponents of the bases shared by several entries
+    let mut v_exponent = BigUint::zero();
+    let mut vi_exponents: HashMap<&BigUint, BigUint> = HashMap::new();
+    let mut x_tilde_exponents: HashMap<usize, BigUint> = HashMap::new();
+    for (entry, rho) in entries.iter().zip(&rhos) {
+        let rho_z = rho * &entry.pms.z;
+        v_exponent += &rho_z;
+        *x_tilde_exponents
+            .entry(entry.message)
+            .or_insert_with(BigUint::zero) += rho_z;
+        *vi_exponents
+            .entry(&entry.vi.key)
+            .or_insert_with(BigUint::zero) += rho * &entry.pms.c;
+    }
+
+    // v^{\sum \rho z} = \prod (v^r)^\rho * \prod v_i^{\sum \rho c}
+    let lhs_v = v.modpow(&v_exponent, n);
+    let rhs_v = (product(
+        entries
+            .par_iter()
+            .zip(rhos.par_iter())
+            .map(|(entry, rho)| entry.v_prime.modpow(rho, n)),
+        n,
+    ) * product(
+        vi_exponents
+            .par_iter()
+            .map(|(key, exponent)| key.modpow(exponent, n)),
+        n,
+    ))
+    .mod_floor(n);
+
+    // \prod x~^{\sum \rho z} = \prod (x~^r)^\rho * (x_i^2)^{\rho c}
+    let lhs_x = product(
+        x_tilde_exponents
+            .par_iter()
+            .map(|(&message, exponent)| x_tildes[message].modpow(exponent, n)),
+        n,
+    );
+    let rhs_x = product(
+        entries.par_iter().zip(rhos.par_iter()).map(|(entry, rho)| {
+            entry.x_prime.modpow(rho, n) * entry.xi_squared.modpow(&(rho * &entry.pms.c), n)
+        }),
+        n,
+    );
+
+    lhs_v == rhs_v && lhs_x == rhs_x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::KeyContext;
+    use crate::session::random_session_id;
+    use crate::transcript::{ProofHash, ProofVersion};
+    use crate::{combine_shares, deal, load_key, verify_raw_proof, PublicPackage};
+    use rsa::traits::PublicKeyParts;
+    use rsa::Pkcs1v15Sign;
+
+    #[test]
+    fn that_batch_signatures_match_the_single_message_ones() {
+        let max_signers = 3;
+        let padding_scheme = PaddingScheme::PKCS1v15;
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), max_signers, 2);
+        let public_pkg = &public_pkgs[0];
+        let delta = factorial(max_signers as usize);
+        let messages: Vec<&[u8]> = vec![b"first", b"second", b"third", b"fourth"];
+
+        let batch_shares: Vec<BatchPartialSignature> = [0, 2]
+            .iter()
+            .map(|&i| {
+                let bps = secret_pkgs[i]
+                    .sign_batch(
+                        &messages,
+                        max_signers,
+                        public_pkg.v.clone(),
+                        &public_pkg.verification_keys[i],
+                        padding_scheme,
+                    )
+                    .unwrap();
+                assert!(verify_batch_proof(
+                    &messages,
+                    &public_pkg.v,
+                    delta,
+                    &public_pkg.verification_keys[i],
+                    &bps,
+                    &secret_pkgs[i].share.n,
+                    secret_pkgs[i].share.key_bytes_size,
+                    padding_scheme,
+                    &ProofPolicy::default(),
+                ));
+                bps
+            })
+            .collect();
+
+        // the same signer twice is below the threshold
+        let duplicated = vec![batch_shares[0].clone(), batch_shares[0].clone()];
+        assert!(combine_batch_shares(
+            &messages,
+            delta,
+            duplicated,
+            &secret_pkgs[0].share,
+            max_signers as usize,
+            padding_scheme,
+        )
+        .is_err());
+        assert!(combine_batch_shares(
+            &messages,
+            delta,
+            vec![],
+            &secret_pkgs[0].share,
+            max_signers as usize,
+            padding_scheme,
+        )
+        .is_err());
+
+        let mut with_duplicate = batch_shares.clone();
+        with_duplicate.push(batch_shares[1].clone());
+        let signatures = combine_batch_shares(
+            &messages,
+            delta,
+            with_duplicate,
+            &secret_pkgs[0].share,
+            max_signers as usize,
+            padding_scheme,
+        )
+        .unwrap();
+        assert_eq!(signatures.len(), messages.len());
+
+        for (msg, signature) in messages.iter().zip(&signatures) {
+            assert_eq!(
+                public_pkg
+                    .public_key
+                    .verify(Pkcs1v15Sign::new_unprefixed(), msg, signature),
+                Ok(())
+            );
+            let pms = (0..2)
+                .map(|i| {
+                    secret_pkgs[i]
+                        .sign(
+                            msg,
+                            max_signers,
+                            public_pkg.v.clone(),
+                            &public_pkg.verification_keys[i],
+                            padding_scheme,
+                        )
+                        .unwrap()
+                })
+                .collect();
+            let single = combine_shares(
+                msg,
+                delta,
+                pms,
+                &secret_pkgs[0].share,
+                max_signers as usize,
+                padding_scheme,
+            )
+            .unwrap();
+            assert_eq!(&single, signature);
+        }
+    }
+
+    #[test]
+    fn that_batch_proof_detects_a_single_bad_share() {
+        let max_signers = 2;
+        let padding_scheme = PaddingScheme::PKCS1v15;
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), max_signers, 2);
+        let public_pkg = &public_pkgs[0];
+        let delta = factorial(max_signers as usize);
+        let messages: Vec<&[u8]> = vec![b"first", b"second", b"third"];
+
+        let mut bps = secret_pkgs[1]
+            .sign_batch(
+                &messages,
+                max_signers,
+                public_pkg.v.clone(),
+                &public_pkg.verification_keys[1],
+                padding_scheme,
+            )
+            .unwrap();
+        bps.xis[1] = bps.xis[0].clone();
+        assert!(!verify_batch_proof(
+            &messages,
+            &public_pkg.v,
+            delta,
+            &public_pkg.verification_keys[1],
+            &bps,
+            &secret_pkgs[1].share.n,
+            secret_pkgs[1].share.key_bytes_size,
+            padding_scheme,
+            &ProofPolicy::default(),
+        ));
+        assert!(!verify_batch_proof(
+            &messages[..2],
+            &public_pkg.v,
+            delta,
+            &public_pkg.verification_keys[1],
+            &bps,
+            &secret_pkgs[1].share.n,
+            secret_pkgs[1].share.key_bytes_size,
+            padding_scheme,
+            &ProofPolicy::default(),
+        ));
+    }
+
+    #[test]
+    fn that_batch_proof_follows_its_version() {
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 2, 2);
+        let public_pkg = &public_pkgs[0];
+        let messages: Vec<&[u8]> = vec![b"first", b"second"];
+        let version = ProofVersion::with_hash(ProofHash::Sha3_512);
+        let bps = sign_batch_with_share(
+            &messages,
+            factorial(2),
+            &secret_pkgs[0].share,
+            &public_pkg.v,
+            &public_pkg.verification_keys[0],
+            PaddingScheme::PKCS1v15,
+            version,
+        );
+        assert!(bps.c.bits() > 256);
+        let verify = |policy: &ProofPolicy| {
+            verify_batch_proof(
+                &messages,
+                &public_pkg.v,
+                factorial(2),
+                &public_pkg.verification_keys[0],
+                &bps,
+                public_pkg.public_key.n(),
+                public_pkg.public_key.size(),
+                PaddingScheme::PKCS1v15,
+                policy,
+            )
+        };
+        assert!(verify(&ProofPolicy::only(version)));
+        assert!(!verify(&ProofPolicy::default()));
+    }
+
+    /// Every signer of a 3-out-of-3 group signs every message.
+    fn sign_by_everyone(
+        messages: &[&[u8]],
+    ) -> (
+        Vec<SecretPackage>,
+        PublicPackage,
+        Vec<PartialMessageSignature>,
+    ) {
+        let max_signers = 3;
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), max_signers, 3);
+        let public_pkg = public_pkgs[0].clone();
+        let pms = messages
+            .iter()
+            .flat_map(|msg| {
+                secret_pkgs.iter().map(|pkg| {
+                    pkg.sign(
+                        msg,
+                        max_signers,
+                        public_pkg.v.clone(),
+                        &public_pkg.verification_keys[pkg.uid],
+                        PaddingScheme::PKCS1v15,
+                    )
+                    .unwrap()
+                })
+            })
+            .collect();
+        (secret_pkgs, public_pkg, pms)
+    }
+
+    fn verify_all(
+        messages: &[&[u8]],
+        secret_pkgs: &[SecretPackage],
+        public_pkg: &PublicPackage,
+        pms: &[PartialMessageSignature],
+    ) -> Result<(), Vec<usize>> {
+        let entries: Vec<BatchVerificationEntry> = pms
+            .iter()
+            .enumerate()
+            .map(|(index, pms)| BatchVerificationEntry {
+                msg: messages[index / secret_pkgs.len()],
+                vi: &public_pkg.verification_keys[index % secret_pkgs.len()],
+                pms,
+                context: None,
+            })
+            .collect();
+        verify_proofs_batch(
+            &entries,
+            &public_pkg.v,
+            factorial(secret_pkgs.len()),
+            &secret_pkgs[0].share.n,
+            secret_pkgs[0].share.key_bytes_size,
+            PaddingScheme::PKCS1v15,
+            &ProofPolicy::default(),
+        )
+    }
+
+    #[test]
+    fn that_batch_verification_accepts_valid_proofs() {
+        let messages: Vec<&[u8]> = vec![b"first", b"second", b"third"];
+        let (secret_pkgs, public_pkg, mut pms) = sign_by_everyone(&messages);
+        // proofs without the commitments are verified separately
+        pms[4].commitments = None;
+        assert_eq!(
+            verify_all(&messages, &secret_pkgs, &public_pkg, &pms),
+            Ok(())
+        );
+    }
+
+    /// The proof `index` made over -v^r, the squares of the commitments are still right. Returns
+    /// whether `verify_raw_proof` accepts it.
+    fn negate_commitment(
+        messages: &[&[u8]],
+        secret_pkgs: &[SecretPackage],
+        public_pkg: &PublicPackage,
+        pms: &mut [PartialMessageSignature],
+        index: usize,
+    ) -> bool {
+        let n = &secret_pkgs[0].share.n;
+        let delta = factorial(secret_pkgs.len());
+        let share = &secret_pkgs[index % secret_pkgs.len()].share;
+        let vi = &public_pkg.verification_keys[share.id - 1];
+        let x = digest_msg(
+            messages[index / secret_pkgs.len()],
+            PaddingScheme::PKCS1v15,
+            n,
+            share.key_bytes_size,
+        );
+        let pms = &mut pms[index];
+        let (v_prime, x_prime) = pms.commitments.clone().unwrap();
+        let negated = n - &v_prime;
+        let r = &pms.z - &share.share * &pms.c;
+        pms.c = proof_challenge(
+            pms.proof_version,
+            None,
+            &public_pkg.v,
+            &x.pow(4 * delta),
+            vi,
+            &pms.xi.modpow(&BigUint::from(2u8), n),
+            &negated,
+            &x_prime,
+        );
+        pms.z = &share.share * &pms.c + r;
+        pms.commitments = Some((negated, x_prime));
+        verify_raw_proof(&x, &public_pkg.v, delta, vi, pms, n)
+    }
+
+    #[test]
+    fn that_batch_verification_rejects_negated_commitments() {
+        let messages: Vec<&[u8]> = vec![b"first", b"second"];
+        let (secret_pkgs, public_pkg, mut pms) = sign_by_everyone(&messages);
+        assert!(!negate_commitment(
+            &messages,
+            &secret_pkgs,
+            &public_pkg,
+            &mut pms,
+            2
+        ));
+        assert_eq!(
+            verify_all(&messages, &secret_pkgs, &public_pkg, &pms),
+            Err(vec![2])
+        );
+    }
+
+    #[test]
+    fn that_two_negated_commitments_cancel() {
+        let messages: Vec<&[u8]> = vec![b"first", b"second"];
+        let (secret_pkgs, public_pkg, mut pms) = sign_by_everyone(&messages);
+        for index in [1, 4] {
+            assert!(!negate_commitment(
+                &messages,
+                &secret_pkgs,
+                &public_pkg,
+                &mut pms,
+                index
+            ));
+        }
+        // accepted up to the sign, as documented
+        assert_eq!(
+            verify_all(&messages, &secret_pkgs, &public_pkg, &pms),
+            Ok(())
+        );
+        // the shares themselves are right, so they still combine
+        let signature = combine_shares(
+            messages[1],
+            factorial(secret_pkgs.len()),
+            pms[3..].to_vec(),
+            &secret_pkgs[0].share,
+            secret_pkgs.len(),
+            PaddingScheme::PKCS1v15,
+        )
+        .unwrap();
+        assert_eq!(
+            public_pkg
+                .public_key
+                .verify(Pkcs1v15Sign::new_unprefixed(), messages[1], &signature),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn that_batch_verification_pinpoints_invalid_proofs() {
+        let messages: Vec<&[u8]> = vec![b"first", b"second", b"third"];
+        let (secret_pkgs, public_pkg, mut pms) = sign_by_everyone(&messages);
+        pms[1].xi = pms[0].xi.clone();
+        pms[5].z += 1u8;
+        pms[7].commitments = None;
+        pms[7].c += 1u8;
+        assert_eq!(
+            verify_all(&messages, &secret_pkgs, &public_pkg, &pms),
+            Err(vec![1, 5, 7])
+        );
+    }
+
+    #[test]
+    fn that_session_bound_proofs_are_batch_verified() {
+        let messages: Vec<&[u8]> = vec![b"first", b"second"];
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 3, 2);
+        let public_pkg = &public_pkgs[0];
+        let key_context = KeyContext::new(public_pkg).unwrap();
+        let session = random_session_id();
+        let mut pms = vec![];
+        let mut contexts = vec![];
+        for msg in &messages {
+            for secret_pkg in &secret_pkgs {
+                pms.push(
+                    key_context
+                        .sign_in_session(secret_pkg, msg, PaddingScheme::PKCS1v15, &session)
+                        .unwrap(),
+                );
+                contexts.push(SigningContext::new(
+                    public_pkg,
+                    session,
+                    secret_pkg.share.id,
+                ));
+            }
+        }
+        // the last proof without its commitments
+        pms[5].commitments = None;
+        let other_session = SigningContext::new(public_pkg, random_session_id(), 1);
+        let verify = |contexts: &[Option<&SigningContext>], policy: &ProofPolicy| {
+            let entries: Vec<BatchVerificationEntry> = pms
+                .iter()
+                .zip(contexts)
+                .enumerate()
+                .map(|(index, (pms, &context))| BatchVerificationEntry {
+                    msg: messages[index / 3],
+                    vi: &public_pkg.verification_keys[index % 3],
+                    pms,
+                    context,
+                })
+                .collect();
+            verify_proofs_batch(
+                &entries,
+                &public_pkg.v,
+                factorial(3),
+                public_pkg.public_key.n(),
+                public_pkg.public_key.size(),
+                PaddingScheme::PKCS1v15,
+                policy,
+            )
+        };
+
+        let bound: Vec<_> = contexts.iter().map(Some).collect();
+        assert_eq!(verify(&bound, &ProofPolicy::default()), Ok(()));
+        // without the context, in another session or for another signer
+        let mut wrong = bound.clone();
+        wrong[0] = None;
+        wrong[3] = Some(&other_session);
+        wrong[4] = Some(&contexts[3]);
+        wrong[5] = None;
+        assert_eq!(
+            verify(&wrong, &ProofPolicy::default()),
+            Err(vec![0, 3, 4, 5])
+        );
+        // nor in a version the policy does not accept
+        let policy = ProofPolicy::only(ProofVersion::with_hash(ProofHash::Sha3_256));
+        assert_eq!(verify(&bound, &policy), Err(vec![0, 1, 2, 3, 4, 5]));
+    }
+}
@@ -0,0 +1,460 @@
+// Command-line tool for the signing ceremonies.
+//
+// Every role works only with files, so that the ceremony can run on air-gapped machines:
+//
+//   dealer:   pretzel keygen --n 5 --k 3 --bits 3072 --out ceremony/
+//   combiner: pretzel session > session.txt
+//   signer:   pretzel sign --share ceremony/share-1.enc --public ceremony/public.json --message m --session $(cat session.txt)
+//   anyone:   pretzel verify-partial --public ceremony/public.json --message m --session ... --partial partial-1.json
+//   combiner: pretzel combine --public ceremony/public.json --message m --session ... partial-1.json partial-3.json ...
+//   anyone:   pretzel verify --public ceremony/public.json --message m --signature signature.bin
+//
+// The proofs of the partial signatures are bound to the session, a fresh one for every signature,
+// so they cannot be replayed into another signing. `--proof-hash` picks the hash of the proofs, the
+// default is SHA-256, the verifiers accept only that hash. The shares are encrypted under a password, see
+// `storage`. The message is signed as
+// RSASSA-PKCS1-v1_5 with SHA-256, so the final signature verifies with any RSA implementation,
+// e.g. `openssl dgst -sha256 -verify public.pem -signature signature.bin m`.
+
+use clap::{Parser, Subcommand, ValueEnum};
+use pretzel::context::KeyContext;
+use pretzel::format::Versioned;
+use pretzel::session::{random_session_id, SessionId};
+use pretzel::storage::KdfParams;
+use pretzel::transcript::ProofHash;
+use pretzel::{
+    generate_with_dealer, PaddingScheme, PartialMessageSignature, PublicPackage, SecretPackage,
+};
+use rsa::pkcs8::LineEnding;
+use rsa::Pkcs1v15Sign;
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use zeroize::Zeroizing;
+
+const PASSWORD_ENV: &str = "PRETZEL_PASSWORD";
+
+#[derive(Parser)]
+#[command(name = "pretzel", version, about = "Threshold RSA signatures (Shoup)")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Generate a key, split it into n encrypted shares and write the public package
+    Keygen {
+        /// Number of the shares
+        #[arg(long)]
+        n: u16,
+        /// Number of the shares needed for a signature
+        #[arg(long)]
+        k: u16,
+        #[arg(long, default_value_t = 3072)]
+        bits: usize,
+        #[arg(long, default_value = ".")]
+        out: PathBuf,
+        /// One password for all the shares, or one line per share
+        #[arg(long)]
+        password_file: Option<PathBuf>,
+    },
+    /// Print a fresh session id for the signers and the combiner of one signature
+    Session,
+    /// Produce a partial signature with one share
+    Sign {
+        #[arg(long)]
+        share: PathBuf,
+        #[arg(long)]
+        public: PathBuf,
+        #[arg(long)]
+        message: PathBuf,
+        #[arg(long, value_parser = parse_session)]
+        session: SessionId,
+        /// Hash of the proofs, the signers and the verifiers have to agree on it
+        #[arg(long, value_enum, default_value_t = ProofHashName::Sha256)]
+        proof_hash: ProofHashName,
+        /// Defaults to partial-<id>.json
+        #[arg(long)]
+        out: Option<PathBuf>,
+        #[arg(long)]
+        password_file: Option<PathBuf>,
+    },
+    /// Check the proof of a partial signature
+    VerifyPartial {
+        #[arg(long)]
+        public: PathBuf,
+        #[arg(long)]
+        message: PathBuf,
+        #[arg(long, value_parser = parse_session)]
+        session: SessionId,
+        /// Hash of the proofs, the signers and the verifiers have to agree on it
+        #[arg(long, value_enum, default_value_t = ProofHashName::Sha256)]
+        proof_hash: ProofHashName,
+        #[arg(long)]
+        partial: PathBuf,
+    },
+    /// Combine the partial signatures into the final signature
+    Combine {
+        #[arg(long)]
+        public: PathBuf,
+        #[arg(long)]
+        message: PathBuf,
+        #[arg(long, value_parser = parse_session)]
+        session: SessionId,
+        /// Hash of the proofs, the signers and the verifiers have to agree on it
+        #[arg(long, value_enum, default_value_t = ProofHashName::Sha256)]
+        proof_hash: ProofHashName,
+        #[arg(long, default_value = "signature.bin")]
+        out: PathBuf,
+        #[arg(required = true)]
+        partials: Vec<PathBuf>,
+    },
+    /// Check the final signature
+    Verify {
+        #[arg(long)]
+        public: PathBuf,
+        #[arg(long)]
+        message: PathBuf,
+        #[arg(long)]
+        signature: PathBuf,
+    },
+    /// Write the public key in PEM, to stdout without --out
+    ExportPubkey {
+        #[arg(long)]
+        public: PathBuf,
+        #[arg(long, value_enum, default_value_t = PemFormat::Spki)]
+        format: PemFormat,
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+enum PemFormat {
+    /// SubjectPublicKeyInfo, "PUBLIC KEY"
+    Spki,
+    /// "RSA PUBLIC KEY"
+    Pkcs1,
+    /// The whole public package, "THRESHOLD RSA PUBLIC KEY"
+    Extended,
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+enum ProofHashName {
+    Sha256,
+    Sha384,
+    Sha512,
+    #[value(name = "sha3-256")]
+    Sha3_256,
+    #[value(name = "sha3-384")]
+    Sha3_384,
+    #[value(name = "sha3-512")]
+    Sha3_512,
+    Blake3,
+}
+
+impl From<ProofHashName> for ProofHash {
+    fn from(name: ProofHashName) -> Self {
+        match name {
+            ProofHashName::Sha256 => ProofHash::Sha256,
+            ProofHashName::Sha384 => ProofHash::Sha384,
+            ProofHashName::Sha512 => ProofHash::Sha512,
+            ProofHashName::Sha3_256 => ProofHash::Sha3_256,
+            ProofHashName::Sha3_384 => ProofHash::Sha3_384,
+            ProofHashName::Sha3_512 => ProofHash::Sha3_512,
+            ProofHashName::Blake3 => ProofHash::Blake3,
+        }
+    }
+}
+
+type CliResult<T> = Result<T, Box<dyn Error>>;
+
+/// The session id in hex, as printed by `pretzel session`.
+fn parse_session(hex: &str) -> Result<SessionId, String> {
+    let mut session = SessionId::default();
+    if hex.len() != 2 * session.len() || !hex.is_ascii() {
+        return Err(format!("expected {} hex digits", 2 * session.len()));
+    }
+    for (byte, digits) in session.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).map_err(|e| e.to_string())?;
+        *byte = u8::from_str_radix(digits, 16).map_err(|e| e.to_string())?;
+    }
+    Ok(session)
+}
+
+fn session() {
+    let hex: String = random_session_id()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    println!("{hex}");
+}
+
+/// DigestInfo of SHA-256 of the file, the shares pad it with PKCS#1 v1.5.
+fn encoded_message(path: &Path) -> CliResult<Vec<u8>> {
+    let digest = Sha256::digest(fs::read(path)?);
+    let mut message = Pkcs1v15Sign::new::<Sha256>().prefix.into_vec();
+    message.extend_from_slice(&digest);
+    Ok(message)
+}
+
+fn read_public(path: &Path) -> CliResult<PublicPackage> {
+    Ok(PublicPackage::from_json(&fs::read_to_string(path)?)?)
+}
+
+fn read_partial(path: &Path) -> CliResult<PartialMessageSignature> {
+    Ok(PartialMessageSignature::from_json(&fs::read_to_string(
+        path,
+    )?)?)
+}
+
+/// The passwords come from the file, the environment, or stdin, in this order.
+fn read_passwords(password_file: Option<&Path>) -> CliResult<Vec<Zeroizing<String>>> {
+    let data = match password_file {
+        Some(path) => Zeroizing::new(fs::read_to_string(path)?),
+        None => match std::env::var(PASSWORD_ENV) {
+            Ok(password) => Zeroizing::new(password),
+            Err(_) => {
+                eprint!("Password: ");
+                std::io::stderr().flush()?;
+                let mut line = Zeroizing::new(String::new());
+                std::io::stdin().lock().read_line(&mut line)?;
+                line
+            }
+        },
+    };
+    let passwords: Vec<Zeroizing<String>> = data
+        .lines()
+        .map(|line| Zeroizing::new(line.to_owned()))
+        .collect();
+    if passwords.is_empty() || passwords.iter().any(|password| password.is_empty()) {
+        return Err("empty password".into());
+    }
+    Ok(passwords)
+}
+
+fn keygen(n: u16, k: u16, bits: usize, out: &Path, password_file: Option<&Path>) -> CliResult<()> {
+    if k == 0 || k > n {
+        return Err(format!("k = {k} has to be between 1 and n = {n}").into());
+    }
+    let passwords = read_passwords(password_file)?;
+    if passwords.len() != 1 && passwords.len() != n as usize {
+        return Err(format!("expected 1 or {n} passwords, got {}", passwords.len()).into());
+    }
+    fs::create_dir_all(out)?;
+
+    let (secret_pkgs, public_pkgs) = generate_with_dealer(n, k, bits)?;
+    fs::write(out.join("public.json"), public_pkgs[0].to_json()?)?;
+    for (i, secret_pkg) in secret_pkgs.iter().enumerate() {
+        let password = &passwords[i % passwords.len()];
+        let path = out.join(format!("share-{}.enc", secret_pkg.share.id));
+        secret_pkg.save_encrypted(&path, password.as_bytes(), KdfParams::default())?;
+        println!("{}", path.display());
+    }
+    println!("{}", out.join("public.json").display());
+    Ok(())
+}
+
+fn sign(
+    share: &Path,
+    public: &Path,
+    message: &Path,
+    session: &SessionId,
+    proof_hash: ProofHash,
+    out: Option<&Path>,
+    password_file: Option<&Path>,
+) -> CliResult<()> {
+    let passwords = read_passwords(password_file)?;
+    let secret_pkg = SecretPackage::load_encrypted(share, passwords[0].as_bytes())?;
+    let context = KeyContext::new(&read_public(public)?)?.with_proof_hash(proof_hash);
+    let message = encoded_message(message)?;
+
+    let partial =
+        context.sign_in_session(&secret_pkg, &message, PaddingScheme::PKCS1v15, session)?;
+    // Catches a share that does not belong to the public package
+    if !context.verify_proof_in_session(&message, &partial, PaddingScheme::PKCS1v15, session) {
+        return Err("the share does not match the public package".into());
+    }
+    let path = out
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from(format!("partial-{}.json", partial.id)));
+    fs::write(&path, partial.to_json()?)?;
+    println!("{}", path.display());
+    Ok(())
+}
+
+fn verify_partial(
+    public: &Path,
+    message: &Path,
+    session: &SessionId,
+    proof_hash: ProofHash,
+    partial: &Path,
+) -> CliResult<bool> {
+    let context = KeyContext::new(&read_public(public)?)?.with_proof_hash(proof_hash);
+    let partial = read_partial(partial)?;
+    Ok(context.verify_proof_in_session(
+        &encoded_message(message)?,
+        &partial,
+        PaddingScheme::PKCS1v15,
+        session,
+    ))
+}
+
+fn combine(
+    public: &Path,
+    message: &Path,
+    session: &SessionId,
+    proof_hash: ProofHash,
+    out: &Path,
+    partials: &[PathBuf],
+) -> CliResult<()> {
+    let public_pkg = read_public(public)?;
+    let context = KeyContext::new(&public_pkg)?.with_proof_hash(proof_hash);
+    let message = encoded_message(message)?;
+
+    let mut valid: Vec<PartialMessageSignature> = Vec::new();
+    for path in partials {
+        let partial = read_partial(path)?;
+        if !context.verify_proof_in_session(&message, &partial, PaddingScheme::PKCS1v15, session) {
+            return Err(format!("invalid partial signature {}", path.display()).into());
+        }
+        if valid.iter().any(|other| other.id == partial.id) {
+            return Err(format!("duplicate partial signature of share {}", partial.id).into());
+        }
+        valid.push(partial);
+    }
+
+    let signature = context.combine_shares(&message, valid, PaddingScheme::PKCS1v15)?;
+    if !verify_signature(&public_pkg, &message, &signature) {
+        return Err("the partial signatures do not combine, too few of them?".into());
+    }
+    fs::write(out, &signature)?;
+    println!("{}", out.display());
+    Ok(())
+}
+
+fn verify_signature(public_pkg: &PublicPackage, message: &[u8], signature: &[u8]) -> bool {
+    let digest = &message[message.len() - Sha256::output_size()..];
+    public_pkg
+        .public_key
+        .verify(Pkcs1v15Sign::new::<Sha256>(), digest, signature)
+        .is_ok()
+}
+
+fn verify(public: &Path, message: &Path, signature: &Path) -> CliResult<bool> {
+    Ok(verify_signature(
+        &read_public(public)?,
+        &encoded_message(message)?,
+        &fs::read(signature)?,
+    ))
+}
+
+fn export_pubkey(public: &Path, format: PemFormat, out: Option<&Path>) -> CliResult<()> {
+    let public_pkg = read_public(public)?;
+    let pem = match format {
+        PemFormat::Spki => public_pkg.to_public_key_pem(LineEnding::LF)?,
+        PemFormat::Pkcs1 => public_pkg.to_pkcs1_pem(LineEnding::LF)?,
+        PemFormat::Extended => public_pkg.to_extended_pem(LineEnding::LF)?,
+    };
+    match out {
+        Some(path) => fs::write(path, pem)?,
+        None => print!("{pem}"),
+    }
+    Ok(())
+}
+
+/// Prints the verdict, the exit code is 1 for an invalid signature.
+fn report(valid: bool) -> ExitCode {
+    if valid {
+        println!("OK");
+        ExitCode::SUCCESS
+    } else {
+        println!("INVALID");
+        ExitCode::FAILURE
+    }
+}
+
+fn run(cli: Cli) -> CliResult<ExitCode> {
+    match cli.command {
+        Command::Keygen {
+            n,
+            k,
+            bits,
+            out,
+            password_file,
+        } => keygen(n, k, bits, &out, password_file.as_deref())?,
+        Command::Session => session(),
+        Command::Sign {
+            share,
+            public,
+            message,
+            session,
+            proof_hash,
+            out,
+            password_file,
+        } => sign(
+            &share,
+            &public,
+            &message,
+            &session,
+            proof_hash.into(),
+            out.as_deref(),
+            password_file.as_deref(),
+        )?,
+        Command::VerifyPartial {
+            public,
+            message,
+            session,
+            proof_hash,
+            partial,
+        } => {
+            return Ok(report(verify_partial(
+                &public,
+                &message,
+                &session,
+                proof_hash.into(),
+                &partial,
+            )?))
+        }
+        Command::Combine {
+            public,
+            message,
+            session,
+            proof_hash,
+            out,
+            partials,
+        } => combine(
+            &public,
+            &message,
+            &session,
+            proof_hash.into(),
+            &out,
+            &partials,
+        )?,
+        Command::Verify {
+            public,
+            message,
+            signature,
+        } => return Ok(report(verify(&public, &message, &signature)?)),
+        Command::ExportPubkey {
+            public,
+            format,
+            out,
+        } => export_pubkey(&public, format, out.as_deref())?,
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::from(2)
+        }
+    }
+}
@@ -0,0 +1,301 @@
+// Threshold blind signatures following RFC 9474 (RSA Blind Signatures).
+//
+// The requester prepares and blinds the message with the public key, the signers sign the blinded
+// value in the raw mode (`PaddingScheme::NONE`), the combiner assembles the blind signature and
+// the requester unblinds it into a regular RSA-PSS signature.
+
+use crate::padding::emsa_pss_encode;
+use crate::{
+    combine_raw_shares, factorial, sign_raw_with_share, PartialMessageSignature, PublicPackage,
+    RsaVerificationKey, SecretPackage,
+};
+use num_bigint::{BigUint, ModInverse, RandBigInt};
+use num_integer::Integer;
+use num_traits::One;
+use rand::RngCore;
+use rand_chacha::{rand_core::SeedableRng, ChaCha20Rng};
+use rsa::hazmat::uint_to_be_pad;
+use rsa::traits::PublicKeyParts;
+use rsa::Pss;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha384};
+use thiserror::Error;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+const MSG_RANDOMIZER_LENGTH: usize = 32;
+
+/// The RSABSSA variants from RFC 9474, section 5.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BlindSignatureSuite {
+    /// RSABSSA-SHA384-PSS-Randomized
+    Sha384PssRandomized,
+    /// RSABSSA-SHA384-PSSZERO-Randomized
+    Sha384PssZeroRandomized,
+    /// RSABSSA-SHA384-PSS-Deterministic
+    Sha384PssDeterministic,
+    /// RSABSSA-SHA384-PSSZERO-Deterministic
+    Sha384PssZeroDeterministic,
+}
+
+impl BlindSignatureSuite {
+    fn salt_length(&self) -> usize {
+        match self {
+            Self::Sha384PssRandomized | Self::Sha384PssDeterministic => {
+                <Sha384 as Digest>::output_size()
+            }
+            Self::Sha384PssZeroRandomized | Self::Sha384PssZeroDeterministic => 0,
+        }
+    }
+
+    fn is_randomized(&self) -> bool {
+        matches!(
+            self,
+            Self::Sha384PssRandomized | Self::Sha384PssZeroRandomized
+        )
+    }
+}
+
+#[derive(Error, Debug, Serialize, Deserialize)]
+pub enum BlindSignatureError {
+    #[error("The message cannot be encoded for the modulus")]
+    MessageTooLong,
+    #[error("The encoded message is not invertible modulo n")]
+    InvalidInput,
+    #[error("The blinded message is not smaller than the modulus")]
+    BlindedMessageTooBig,
+    #[error("The signature shares cannot be combined")]
+    CombiningFailed,
+    #[error("The signature does not verify")]
+    InvalidSignature,
+}
+
+/// Inverse of the blinding factor, the requester keeps it until the blind signature arrives.
+///
+/// Neither `Debug` nor `Clone`, so that it does not end up in logs or in copies that outlive it.
+pub struct BlindingSecret {
+    inv: BigUint,
+}
+
+impl Zeroize for BlindingSecret {
+    fn zeroize(&mut self) {
+        self.inv.zeroize();
+    }
+}
+
+impl Drop for BlindingSecret {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl ZeroizeOnDrop for BlindingSecret {}
+
+/// `Prepare` from RFC 9474, the randomized variants prepend 32 random bytes to the message.
+///
+/// The prepared message is the one that gets blinded, finalized and eventually verified.
+pub fn prepare(suite: BlindSignatureSuite, msg: &[u8]) -> Vec<u8> {
+    if !suite.is_randomized() {
+        return msg.to_vec();
+    }
+    let mut prepared = vec![0u8; MSG_RANDOMIZER_LENGTH];
+    ChaCha20Rng::from_entropy().fill_bytes(&mut prepared);
+    prepared.extend_from_slice(msg);
+    prepared
+}
+
+/// `Blind` from RFC 9474, returns the blinded message and the secret needed for `finalize`.
+pub fn blind(
+    public_pkg: &PublicPackage,
+    suite: BlindSignatureSuite,
+    prepared_msg: &[u8],
+) -> Result<(Vec<u8>, BlindingSecret), BlindSignatureError> {
+    let n = public_pkg.public_key.n();
+    let e = public_pkg.public_key.e();
+    let mut rng = ChaCha20Rng::from_entropy();
+
+    let mut salt = vec![0u8; suite.salt_length()];
+    rng.fill_bytes(&mut salt);
+    let encoded = emsa_pss_encode::<Sha384>(&Sha384::digest(prepared_msg), n.bits() - 1, &salt)
+        .ok_or(BlindSignatureError::MessageTooLong)?;
+    let m = BigUint::from_bytes_be(&encoded);
+    if !m.gcd(n).is_one() {
+        return Err(BlindSignatureError::InvalidInput);
+    }
+
+    let (r, inv) = loop {
+        let r = rng.gen_biguint_range(&BigUint::one(), n);
+        if let Some(inv) = (&r).mod_inverse(n).and_then(|inv| inv.to_biguint()) {
+            break (r, inv);
+        }
+    };
+    let z = (m * r.modpow(e, n)).mod_floor(n);
+    let blinded_msg = uint_to_be_pad(z, public_pkg.public_key.size())
+        .map_err(|_| BlindSignatureError::MessageTooLong)?;
+    Ok((blinded_msg, BlindingSecret { inv }))
+}
+
+impl SecretPackage {
+    /// Sign the blinded message in the raw mode, the proof is the same as for `sign`.
+    pub fn blind_sign(
+        &self,
+        blinded_msg: &[u8],
+        max_signers: u16,
+        v: BigUint,
+        vi: &RsaVerificationKey,
+    ) -> Result<PartialMessageSignature, BlindSignatureError> {
+        let x = BigUint::from_bytes_be(blinded_msg);
+        if x >= self.share.n {
+            return Err(BlindSignatureError::BlindedMessageTooBig);
+        }
+        let delta = factorial(max_signers as usize);
+        Ok(sign_raw_with_share(&x, delta, &self.share, &v, vi))
+    }
+}
+
+/// Combine the partial signatures on the blinded message into the blind signature.
+///
+/// As in `BlindSign` from RFC 9474, the result is checked against the blinded message.
+pub fn combine_blind_shares(
+    blinded_msg: &[u8],
+    delta: usize,
+    sign_shares: Vec<PartialMessageSignature>,
+    public_pkg: &PublicPackage,
+    l: usize,
+) -> Result<Vec<u8>, BlindSignatureError> {
+    let n = public_pkg.public_key.n();
+    let e = public_pkg.public_key.e();
+    let x = BigUint::from_bytes_be(blinded_msg);
+    if &x >= n {
+        return Err(BlindSignatureError::BlindedMessageTooBig);
+    }
+    let s = combine_raw_shares(&x, delta, &sign_shares, n, e, l)
+        .map_err(|_| BlindSignatureError::CombiningFailed)?;
+    if s.modpow(e, n) != x {
+        return Err(BlindSignatureError::CombiningFailed);
+    }
+    uint_to_be_pad(s, public_pkg.public_key.size())
+        .map_err(|_| BlindSignatureError::CombiningFailed)
+}
+
+/// `Finalize` from RFC 9474, unblinds the signature and checks it as a RSASSA-PSS signature over
+/// the prepared message.
+pub fn finalize(
+    public_pkg: &PublicPackage,
+    suite: BlindSignatureSuite,
+    prepared_msg: &[u8],
+    blind_signature: &[u8],
+    secret: &BlindingSecret,
+) -> Result<Vec<u8>, BlindSignatureError> {
+    let n = public_pkg.public_key.n();
+    let z = BigUint::from_bytes_be(blind_signature);
+    if &z >= n {
+        return Err(BlindSignatureError::InvalidSignature);
+    }
+    let s = (z * &secret.inv).mod_floor(n);
+    let signature = uint_to_be_pad(s, public_pkg.public_key.size())
+        .map_err(|_| BlindSignatureError::InvalidSignature)?;
+    verify(public_pkg, suite, prepared_msg, &signature)?;
+    Ok(signature)
+}
+
+/// Verify the final signature, this is plain RSASSA-PSS verification.
+pub fn verify(
+    public_pkg: &PublicPackage,
+    suite: BlindSignatureSuite,
+    prepared_msg: &[u8],
+    signature: &[u8],
+) -> Result<(), BlindSignatureError> {
+    public_pkg
+        .public_key
+        .verify(
+            Pss::new_with_salt::<Sha384>(suite.salt_length()),
+            &Sha384::digest(prepared_msg),
+            signature,
+        )
+        .map_err(|_| BlindSignatureError::InvalidSignature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deal, load_key};
+
+    fn blind_sign_with_quorum(suite: BlindSignatureSuite, signers: &[usize]) -> bool {
+        let max_signers = 3;
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), max_signers, 2);
+        let public_pkg = &public_pkgs[0];
+
+        let prepared = prepare(suite, b"anonymous token");
+        let (blinded_msg, secret) = blind(public_pkg, suite, &prepared).unwrap();
+        let pms = signers
+            .iter()
+            .map(|&i| {
+                secret_pkgs[i]
+                    .blind_sign(
+                        &blinded_msg,
+                        max_signers,
+                        public_pkg.v.clone(),
+                        &public_pkg.verification_keys[i],
+                    )
+                    .unwrap()
+            })
+            .collect();
+        let blind_signature = combine_blind_shares(
+            &blinded_msg,
+            factorial(max_signers as usize),
+            pms,
+            public_pkg,
+            max_signers as usize,
+        )
+        .unwrap();
+        let signature = finalize(public_pkg, suite, &prepared, &blind_signature, &secret).unwrap();
+        verify(public_pkg, suite, &prepared, &signature).is_ok()
+    }
+
+    #[test]
+    fn that_unblinded_signature_verifies_as_pss() {
+        assert!(blind_sign_with_quorum(
+            BlindSignatureSuite::Sha384PssRandomized,
+            &[0, 2]
+        ));
+        assert!(blind_sign_with_quorum(
+            BlindSignatureSuite::Sha384PssZeroDeterministic,
+            &[1, 2]
+        ));
+    }
+
+    #[test]
+    fn that_signature_does_not_verify_for_another_message() {
+        let suite = BlindSignatureSuite::Sha384PssDeterministic;
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 2, 2);
+        let public_pkg = &public_pkgs[0];
+
+        let (blinded_msg, secret) = blind(public_pkg, suite, b"first").unwrap();
+        let pms = secret_pkgs
+            .iter()
+            .map(|pkg| {
+                pkg.blind_sign(
+                    &blinded_msg,
+                    2,
+                    public_pkg.v.clone(),
+                    &public_pkg.verification_keys[pkg.uid],
+                )
+                .unwrap()
+            })
+            .collect();
+        let blind_signature =
+            combine_blind_shares(&blinded_msg, factorial(2), pms, public_pkg, 2).unwrap();
+        assert!(finalize(public_pkg, suite, b"second", &blind_signature, &secret).is_err());
+    }
+
+    #[test]
+    fn that_blinding_secret_is_zeroized() {
+        let (_, public_pkgs) = deal(&load_key().unwrap(), 2, 2);
+        let suite = BlindSignatureSuite::Sha384PssDeterministic;
+        let (_, mut secret) = blind(&public_pkgs[0], suite, b"first").unwrap();
+        assert!(secret.inv.to_bytes_be().iter().any(|&byte| byte != 0));
+        secret.zeroize();
+        // the digits are overwritten in place, the length stays
+        assert!(secret.inv.to_bytes_be().iter().all(|&byte| byte == 0));
+    }
+}
@@ -0,0 +1,720 @@
+// Precomputation for long running signers, verifiers and combiners of a single key.
+//
+// The bases v and v_i are the same for every signature, so their powers are precomputed into
+// fixed-base tables and every exponentiation with them is reduced to multiplications only. The
+// multiplications are done in the Montgomery form.
+
+use crate::optimistic::{CombineError, ShareProof, SignatureShare};
+use crate::session::{SessionId, SigningContext};
+use crate::transcript::{ProofHash, ProofPolicy, ProofVersion};
+use crate::{
+    bezout_coefficients, bezout_root, digest_msg, factorial, interpolate_in_exponent,
+    proof_challenge, valid_partials, PaddingScheme, PartialMessageSignature, PublicPackage,
+    RsaVerificationKey, SecretPackage, SigningError,
+};
+use num_bigint::{BigInt, BigUint, ModInverse, RandBigInt};
+use num_integer::Integer;
+use num_traits::{One, Pow, Zero};
+use rand_chacha::{rand_core::SeedableRng, ChaCha20Rng};
+use rayon::prelude::*;
+use rsa::hazmat::uint_to_zeroizing_be_pad;
+use rsa::traits::PublicKeyParts;
+use std::collections::{HashMap, HashSet};
+
+/// Bits of the exponent consumed by one table lookup, the exponent is read by nibbles.
+const WINDOW_BITS: usize = 4;
+
+/// Montgomery multiplication modulo an odd n with R = 2^shift.
+#[derive(Debug, Clone)]
+struct Montgomery {
+    n: BigUint,
+    shift: usize,
+    mask: BigUint,
+    /// -n^{-1} mod R
+    n_prime: BigUint,
+    /// R^2 mod n
+    r_squared: BigUint,
+    /// R mod n, the one in the Montgomery form
+    one: BigUint,
+}
+
+impl Montgomery {
+    /// `None` for an even n, it has no inverse modulo R.
+    fn new(n: &BigUint) -> Option<Self> {
+        let shift = n.bits().div_ceil(64) * 64;
+        let r = BigUint::one() << shift;
+        let n_inverse = n
+            .clone()
+            .mod_inverse(&r)
+            .and_then(|inverse| inverse.to_biguint())?;
+        Some(Montgomery {
+            n: n.clone(),
+            shift,
+            mask: &r - BigUint::one(),
+            n_prime: &r - n_inverse,
+            r_squared: (&r * &r).mod_floor(n),
+            one: r.mod_floor(n),
+        })
+    }
+
+    /// t R^{-1} mod n for t < nR
+    fn reduce(&self, t: BigUint) -> BigUint {
+        let m = ((&t & &self.mask) * &self.n_prime) & &self.mask;
+        let u = (t + m * &self.n) >> self.shift;
+        if u >= self.n {
+            u - &self.n
+        } else {
+            u
+        }
+    }
+
+    fn mul(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        self.reduce(a * b)
+    }
+
+    fn encode(&self, a: &BigUint) -> BigUint {
+        self.reduce(a.mod_floor(&self.n) * &self.r_squared)
+    }
+
+    fn decode(&self, a: BigUint) -> BigUint {
+        self.reduce(a)
+    }
+}
+
+/// table[i][j] = g^{j 2^{4i}} in the Montgomery form
+#[derive(Debug, Clone)]
+struct FixedBaseTable {
+    base: BigUint,
+    max_bits: usize,
+    table: Vec<Vec<BigUint>>,
+}
+
+impl FixedBaseTable {
+    fn new(base: &BigUint, max_bits: usize, montgomery: &Montgomery) -> Self {
+        let windows = max_bits.div_ceil(WINDOW_BITS);
+        let mut table = Vec::with_capacity(windows);
+        let mut g = montgomery.encode(base);
+        for _ in 0..windows {
+            let mut row = Vec::with_capacity(1 << WINDOW_BITS);
+            row.push(montgomery.one.clone());
+            for j in 1..(1 << WINDOW_BITS) {
+                row.push(montgomery.mul(&row[j - 1], &g));
+            }
+            g = montgomery.mul(&row[(1 << WINDOW_BITS) - 1], &g);
+            table.push(row);
+        }
+        FixedBaseTable {
+            base: base.clone(),
+            max_bits: windows * WINDOW_BITS,
+            table,
+        }
+    }
+
+    /// g^exponent mod n, falls back to `modpow` for exponents longer than the table
+    fn pow(&self, exponent: &BigUint, montgomery: &Montgomery) -> BigUint {
+        montgomery.decode(self.pow_montgomery(exponent, montgomery))
+    }
+
+    /// g^exponent in the Montgomery form
+    ///
+    /// Every window of the table is multiplied in, the zero digits too, so the number of the
+    /// multiplications is fixed. It is not constant time though, the entry of a window is looked
+    /// up by the digit of the exponent and the `BigUint` arithmetic depends on the values.
+    fn pow_montgomery(&self, exponent: &BigUint, montgomery: &Montgomery) -> BigUint {
+        if exponent.bits() > self.max_bits {
+            return montgomery.encode(&self.base.modpow(exponent, &montgomery.n));
+        }
+        let mut bytes = exponent.to_bytes_le();
+        bytes.resize(self.table.len().div_ceil(2), 0);
+        let digits = bytes.iter().flat_map(|byte| [byte & 0x0f, byte >> 4]);
+        let mut acc = montgomery.one.clone();
+        for (row, digit) in self.table.iter().zip(digits) {
+            acc = montgomery.mul(&acc, &row[digit as usize]);
+        }
+        acc
+    }
+}
+
+/// Everything about a key that can be computed once, built from the `PublicPackage`.
+#[derive(Debug, Clone)]
+pub struct KeyContext {
+    n: BigUint,
+    e: BigUint,
+    key_bytes_size: usize,
+    group_size: usize,
+    delta: usize,
+    bezout: (BigInt, BigInt),
+    montgomery: Montgomery,
+    v: BigUint,
+    v_table: FixedBaseTable,
+    fingerprint: [u8; 32],
+    /// The version of the proofs made by `sign`
+    proof_version: ProofVersion,
+    /// The versions of the proofs `verify_proof` accepts
+    proof_policy: ProofPolicy,
+    /// The tables are built for v_i^{-1}, the proofs need v_i^{-c} only.
+    vi_inverse_tables: HashMap<usize, (RsaVerificationKey, FixedBaseTable)>,
+    /// The length of c the tables are built for, the longest of the version and the policy
+    table_challenge_bits: usize,
+}
+
+impl KeyContext {
+    pub fn new(public_pkg: &PublicPackage) -> Result<Self, SigningError> {
+        let n = public_pkg.public_key.n().clone();
+        let e = public_pkg.public_key.e().clone();
+        let delta = factorial(public_pkg.group_size);
+        let bezout = bezout_coefficients(delta, &e)?;
+        let montgomery = Montgomery::new(&n).ok_or(SigningError::SigningError)?;
+        let challenge_bits = ProofVersion::CURRENT.challenge_bits();
+        let v_table =
+            FixedBaseTable::new(&public_pkg.v, Self::z_bits(&n, challenge_bits), &montgomery);
+        let vi_inverse_tables = public_pkg
+            .verification_keys
+            .par_iter()
+            .map(|vi| {
+                let inverse = vi
+                    .key
+                    .clone()
+                    .mod_inverse(&n)
+                    .and_then(|inverse| inverse.to_biguint())
+                    .ok_or(SigningError::SigningError)?;
+                let table = FixedBaseTable::new(&inverse, challenge_bits, &montgomery);
+                Ok((vi.id, (vi.clone(), table)))
+            })
+            .collect::<Result<HashMap<_, _>, SigningError>>()?;
+
+        Ok(KeyContext {
+            key_bytes_size: public_pkg.public_key.size(),
+            group_size: public_pkg.group_size,
+            v: public_pkg.v.clone(),
+            n,
+            e,
+            delta,
+            bezout,
+            montgomery,
+            v_table,
+            fingerprint: public_pkg.fingerprint(),
+            proof_version: ProofVersion::CURRENT,
+            proof_policy: ProofPolicy::default(),
+            vi_inverse_tables,
+            table_challenge_bits: challenge_bits,
+        })
+    }
+
+    /// z = s_i c + r, where r < 2^{|n| + 2 |c|} and s_i < n
+    fn z_bits(n: &BigUint, challenge_bits: usize) -> usize {
+        n.bits() + 2 * challenge_bits + 1
+    }
+
+    /// Rebuild the tables when the version or the policy changed the length of c, the longer
+    /// exponents would fall back to `modpow`.
+    fn resize_tables(mut self) -> Self {
+        let challenge_bits = self
+            .proof_version
+            .challenge_bits()
+            .max(self.proof_policy.max_challenge_bits());
+        if challenge_bits == self.table_challenge_bits {
+            return self;
+        }
+        let montgomery = &self.montgomery;
+        self.v_table =
+            FixedBaseTable::new(&self.v, Self::z_bits(&self.n, challenge_bits), montgomery);
+        self.vi_inverse_tables
+            .par_iter_mut()
+            .for_each(|(_, (_, table))| {
+                *table = FixedBaseTable::new(&table.base, challenge_bits, montgomery);
+            });
+        self.table_challenge_bits = challenge_bits;
+        self
+    }
+
+    /// The proofs made by `sign` get the version, the default is `ProofVersion::CURRENT`. Only the
+    /// proofs of the version are accepted then, `with_proof_policy` can accept more.
+    pub fn with_proof_version(mut self, proof_version: ProofVersion) -> Result<Self, SigningError> {
+        if !proof_version.is_supported() {
+            return Err(SigningError::SigningError);
+        }
+        self.proof_version = proof_version;
+        self.proof_policy = ProofPolicy::only(proof_version);
+        Ok(self.resize_tables())
+    }
+
+    /// Transcript proofs with the hash, as with `with_proof_version`. The verification requires the
+    /// hash then, whatever hash the prover claims.
+    pub fn with_proof_hash(mut self, hash: ProofHash) -> Self {
+        let proof_version = ProofVersion::with_hash(hash);
+        self.proof_version = proof_version;
+        self.proof_policy = ProofPolicy::only(proof_version);
+        self.resize_tables()
+    }
+
+    /// The versions of the proofs the verification accepts, whatever version the prover claims.
+    pub fn with_proof_policy(mut self, proof_policy: ProofPolicy) -> Self {
+        self.proof_policy = proof_policy;
+        self.resize_tables()
+    }
+
+    /// A signer answering a peer signs only a message it encodes itself, i.e. with PKCS#1 v1.5. The
+    /// raw mode would turn a quorum of such signers into an RSA signing and decryption oracle.
+    pub fn check_remote_request(
+        &self,
+        message: &[u8],
+        padding_scheme: PaddingScheme,
+    ) -> Result<(), SigningError> {
+        match padding_scheme {
+            // RFC 8017, 9.2, at least 8 bytes of the padding string
+            PaddingScheme::PKCS1v15 if message.len() + 11 <= self.key_bytes_size => Ok(()),
+            _ => Err(SigningError::MessageCannotBeSigned),
+        }
+    }
+
+    /// Same as `SecretPackage::sign`, but v^r is taken from the table.
+    pub fn sign(
+        &self,
+        secret_pkg: &SecretPackage,
+        message: &[u8],
+        padding_scheme: PaddingScheme,
+    ) -> Result<PartialMessageSignature, SigningError> {
+        self.sign_with(secret_pkg, message, padding_scheme, None)
+    }
+
+    /// Same as `session::sign_with_share_in_context` for this key and the share of the signer.
+    pub fn sign_in_session(
+        &self,
+        secret_pkg: &SecretPackage,
+        message: &[u8],
+        padding_scheme: PaddingScheme,
+        session_id: &SessionId,
+    ) -> Result<PartialMessageSignature, SigningError> {
+        let context = self.signing_context(session_id, secret_pkg.share.id);
+        self.sign_with(secret_pkg, message, padding_scheme, Some(&context))
+    }
+
+    fn sign_with(
+        &self,
+        secret_pkg: &SecretPackage,
+        message: &[u8],
+        padding_scheme: PaddingScheme,
+        context: Option<&SigningContext>,
+    ) -> Result<PartialMessageSignature, SigningError> {
+        let x = digest_msg(message, padding_scheme, &self.n, self.key_bytes_size);
+        let xi = self.partial_exponentiation(secret_pkg, &x)?;
+        let proof = self.prove(secret_pkg, &x, &xi, context)?;
+        Ok(PartialMessageSignature {
+            id: proof.id,
+            xi,
+            z: proof.z,
+            c: proof.c,
+            commitments: proof.commitments,
+            proof_version: proof.proof_version,
+        })
+    }
+
+    /// x_i = x^{2 \delta s_i}
+    fn partial_exponentiation(
+        &self,
+        secret_pkg: &SecretPackage,
+        x: &BigUint,
+    ) -> Result<BigUint, SigningError> {
+        let share = &secret_pkg.share;
+        if !self.vi_inverse_tables.contains_key(&share.id) {
+            return Err(SigningError::SigningError);
+        }
+        let exponent = BigUint::from(2 * self.delta) * &share.share;
+        Ok(x.modpow(&exponent, &self.n))
+    }
+
+    fn prove(
+        &self,
+        secret_pkg: &SecretPackage,
+        x: &BigUint,
+        xi: &BigUint,
+        context: Option<&SigningContext>,
+    ) -> Result<ShareProof, SigningError> {
+        let share = &secret_pkg.share;
+        let Some((vi, _)) = self.vi_inverse_tables.get(&share.id) else {
+            return Err(SigningError::SigningError);
+        };
+        let n = &self.n;
+        let x_tilde = x.pow(4 * self.delta);
+        let xi_squared = xi.modpow(&BigUint::from(2u8), n);
+
+        let bound = BigUint::one() << (n.bits() + 2 * self.proof_version.challenge_bits());
+        let r = ChaCha20Rng::from_entropy().gen_biguint_range(&BigUint::zero(), &bound);
+        let v_prime = self.v_table.pow(&r, &self.montgomery);
+        let x_prime = x_tilde.modpow(&r, n);
+
+        let c = proof_challenge(
+            self.proof_version,
+            context,
+            &self.v,
+            &x_tilde,
+            vi,
+            &xi_squared,
+            &v_prime,
+            &x_prime,
+        );
+        let z = &share.share * &c + r;
+        Ok(ShareProof {
+            id: share.id,
+            z,
+            c,
+            commitments: Some((v_prime, x_prime)),
+            proof_version: self.proof_version,
+        })
+    }
+
+    /// The optimistic mode, x_i only without the proof of correctness.
+    pub fn sign_share(
+        &self,
+        secret_pkg: &SecretPackage,
+        message: &[u8],
+        padding_scheme: PaddingScheme,
+    ) -> Result<SignatureShare, SigningError> {
+        let x = digest_msg(message, padding_scheme, &self.n, self.key_bytes_size);
+        Ok(SignatureShare {
+            id: secret_pkg.share.id,
+            xi: self.partial_exponentiation(secret_pkg, &x)?,
+        })
+    }
+
+    /// The proof of a share made by `sign_share`, asked for when the optimistic combination fails.
+    /// It is bound to the session like the proofs of `sign_in_session`.
+    pub fn prove_share(
+        &self,
+        secret_pkg: &SecretPackage,
+        message: &[u8],
+        padding_scheme: PaddingScheme,
+        share: &SignatureShare,
+        session_id: &SessionId,
+    ) -> Result<ShareProof, SigningError> {
+        if share.id != secret_pkg.share.id {
+            return Err(SigningError::SigningError);
+        }
+        let x = digest_msg(message, padding_scheme, &self.n, self.key_bytes_size);
+        let context = self.signing_context(session_id, secret_pkg.share.id);
+        self.prove(secret_pkg, &x, &share.xi, Some(&context))
+    }
+
+    /// Same as `verify_proof`, v^z and v_i^{-c} are taken from the tables.
+    #[deprecated(
+        note = "a proof without a context verifies in any session, use `verify_proof_in_session`"
+    )]
+    pub fn verify_proof(
+        &self,
+        message: &[u8],
+        pms: &PartialMessageSignature,
+        padding_scheme: PaddingScheme,
+    ) -> bool {
+        self.verify_proof_with(message, pms, padding_scheme, None)
+    }
+
+    /// Same as `session::verify_proof_in_context`, the context is built for `pms.id`.
+    pub fn verify_proof_in_session(
+        &self,
+        message: &[u8],
+        pms: &PartialMessageSignature,
+        padding_scheme: PaddingScheme,
+        session_id: &SessionId,
+    ) -> bool {
+        let context = self.signing_context(session_id, pms.id);
+        self.verify_proof_with(message, pms, padding_scheme, Some(&context))
+    }
+
+    pub(crate) fn verify_proof_with(
+        &self,
+        message: &[u8],
+        pms: &PartialMessageSignature,
+        padding_scheme: PaddingScheme,
+        context: Option<&SigningContext>,
+    ) -> bool {
+        let proof_version = pms.proof_version;
+        if !self.proof_policy.accepts(&proof_version)
+            || pms.c.bits() > proof_version.challenge_bits()
+        {
+            return false;
+        }
+        let Some((vi, vi_inverse_table)) = self.vi_inverse_tables.get(&pms.id) else {
+            return false;
+        };
+        let n = &self.n;
+        let x = digest_msg(message, padding_scheme, n, self.key_bytes_size);
+        let x_tilde = x.pow(4 * self.delta);
+        let xi_squared = pms.xi.modpow(&BigUint::from(2u8), n);
+
+        let montgomery = &self.montgomery;
+        let v_prime = montgomery.decode(montgomery.mul(
+            &self.v_table.pow_montgomery(&pms.z, montgomery),
+            &vi_inverse_table.pow_montgomery(&pms.c, montgomery),
+        ));
+        let Some(xi_inverse) = xi_squared
+            .modpow(&pms.c, n)
+            .mod_inverse(n)
+            .and_then(|inverse| inverse.to_biguint())
+        else {
+            return false;
+        };
+        let x_prime = (x_tilde.modpow(&pms.z, n) * xi_inverse).mod_floor(n);
+
+        pms.c
+            == proof_challenge(
+                proof_version,
+                context,
+                &self.v,
+                &x_tilde,
+                vi,
+                &xi_squared,
+                &v_prime,
+                &x_prime,
+            )
+    }
+
+    /// Same as `combine_shares` with the cached delta and Bezout coefficients.
+    pub fn combine_shares(
+        &self,
+        message: &[u8],
+        sign_shares: Vec<PartialMessageSignature>,
+        padding_scheme: PaddingScheme,
+    ) -> Result<Vec<u8>, SigningError> {
+        let x = digest_msg(message, padding_scheme, &self.n, self.key_bytes_size);
+        let partials: Vec<(usize, &BigUint)> = sign_shares.iter().map(|s| (s.id, &s.xi)).collect();
+        if !self.valid_partials(&partials) {
+            return Err(SigningError::SigningError);
+        }
+        let w = interpolate_in_exponent(self.delta, &partials, &self.n, self.group_size);
+        let (a, b) = &self.bezout;
+        let signature = bezout_root(&w, &x, a, b, &self.n);
+        uint_to_zeroizing_be_pad(signature, self.key_bytes_size)
+            .map_err(|_| SigningError::SigningError)
+    }
+
+    /// Combine the shares without verifying them, the final signature is checked instead.
+    pub fn combine_optimistic(
+        &self,
+        message: &[u8],
+        shares: &[SignatureShare],
+        padding_scheme: PaddingScheme,
+    ) -> Result<Vec<u8>, CombineError> {
+        let x = digest_msg(message, padding_scheme, &self.n, self.key_bytes_size);
+        let partials: Vec<(usize, &BigUint)> = shares.iter().map(|s| (s.id, &s.xi)).collect();
+        let signature = self.combine_checked(&x, &partials)?;
+        Ok(uint_to_zeroizing_be_pad(signature, self.key_bytes_size)
+            .map_err(|_| SigningError::SigningError)?
+            .to_vec())
+    }
+
+    /// Verify all the proofs in the session and combine the valid partial signatures only.
+    ///
+    /// Fails with the ids of the invalid ones when the valid ones do not give a signature.
+    pub fn combine_robust(
+        &self,
+        message: &[u8],
+        partials: &[PartialMessageSignature],
+        padding_scheme: PaddingScheme,
+        session_id: &SessionId,
+    ) -> Result<Vec<u8>, CombineError> {
+        let (valid, invalid): (Vec<_>, Vec<_>) = partials.par_iter().partition(|pms| {
+            self.verify_proof_in_session(message, pms, padding_scheme, session_id)
+        });
+        let x = digest_msg(message, padding_scheme, &self.n, self.key_bytes_size);
+        let mut ids = HashSet::new();
+        let partials: Vec<(usize, &BigUint)> = valid
+            .iter()
+            .filter(|pms| ids.insert(pms.id))
+            .map(|pms| (pms.id, &pms.xi))
+            .collect();
+        let signature = match self.combine_checked(&x, &partials) {
+            Ok(signature) => signature,
+            Err(CombineError::InvalidSignature) => {
+                return Err(CombineError::NotEnoughValidShares {
+                    invalid: invalid.iter().map(|pms| pms.id).collect(),
+                })
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(uint_to_zeroizing_be_pad(signature, self.key_bytes_size)
+            .map_err(|_| SigningError::SigningError)?
+            .to_vec())
+    }
+
+    /// Distinct ids of the key and x_i in Z_n^*, anything else would break the interpolation.
+    fn valid_partials(&self, partials: &[(usize, &BigUint)]) -> bool {
+        partials
+            .iter()
+            .all(|(id, _)| self.vi_inverse_tables.contains_key(id))
+            && valid_partials(partials, &self.n, self.group_size)
+    }
+
+    /// y = x^d, checked by y^e = x
+    fn combine_checked(
+        &self,
+        x: &BigUint,
+        partials: &[(usize, &BigUint)],
+    ) -> Result<BigUint, CombineError> {
+        // The shares may be unverified
+        if !self.valid_partials(partials) {
+            return Err(CombineError::InvalidSignature);
+        }
+        let w = interpolate_in_exponent(self.delta, partials, &self.n, self.group_size);
+        let (a, b) = &self.bezout;
+        let signature = bezout_root(&w, x, a, b, &self.n);
+        if &signature.modpow(&self.e, &self.n) != x {
+            return Err(CombineError::InvalidSignature);
+        }
+        Ok(signature)
+    }
+
+    fn signing_context(&self, session_id: &SessionId, signer_id: usize) -> SigningContext {
+        SigningContext::with_fingerprint(self.fingerprint, *session_id, signer_id)
+    }
+
+    /// See `PublicPackage::fingerprint`
+    pub fn fingerprint(&self) -> &[u8; 32] {
+        &self.fingerprint
+    }
+
+    pub fn delta(&self) -> usize {
+        self.delta
+    }
+
+    pub fn public_exponent(&self) -> &BigUint {
+        &self.e
+    }
+
+    pub fn modulus(&self) -> &BigUint {
+        &self.n
+    }
+}
+
+#[cfg(test)]
+#[allow(deprecated)]
+mod tests {
+    use super::*;
+    use crate::session::random_session_id;
+    use crate::{combine_shares, deal, load_key, verify_proof};
+    use rsa::RsaPublicKey;
+
+    #[test]
+    fn that_fixed_base_exponentiation_matches_modpow() {
+        let n = load_key().unwrap().n;
+        let montgomery = Montgomery::new(&n).unwrap();
+        let mut rng = ChaCha20Rng::from_entropy();
+        let base = rng.gen_biguint_below(&n);
+        let table = FixedBaseTable::new(&base, 300, &montgomery);
+        for bits in [0, 1, 4, 5, 255, 300, 301, 600] {
+            let exponent = rng.gen_biguint(bits);
+            assert_eq!(
+                table.pow(&exponent, &montgomery),
+                base.modpow(&exponent, &n),
+                "exponent of {bits} bits"
+            );
+        }
+    }
+
+    #[test]
+    fn that_tables_follow_the_challenge_length() {
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 3, 2);
+        let public_pkg = &public_pkgs[0];
+        let n_bits = public_pkg.public_key.n().bits();
+        let table_bits = |context: &KeyContext| {
+            let vi_bits = context
+                .vi_inverse_tables
+                .values()
+                .map(|(_, table)| table.max_bits)
+                .min()
+                .unwrap();
+            (context.v_table.max_bits, vi_bits)
+        };
+        let context = KeyContext::new(public_pkg).unwrap();
+        let (v_bits, vi_bits) = table_bits(&context);
+        assert!(v_bits >= n_bits + 2 * 256 && v_bits < n_bits + 2 * 512);
+        assert!((256..512).contains(&vi_bits));
+
+        let long = ProofVersion::Transcript {
+            hash: ProofHash::Sha512,
+            challenge_bits: 512,
+        };
+        let context = context.with_proof_version(long).unwrap();
+        let (v_bits, vi_bits) = table_bits(&context);
+        assert!(v_bits >= n_bits + 2 * 512 && vi_bits >= 512);
+        // the policy may accept longer challenges than the version makes
+        let context = KeyContext::new(public_pkg)
+            .unwrap()
+            .with_proof_policy(ProofPolicy::default().and(long));
+        assert!(table_bits(&context).1 >= 512);
+
+        let session = random_session_id();
+        let pms = context
+            .sign_in_session(&secret_pkgs[0], b"ABC", PaddingScheme::PKCS1v15, &session)
+            .unwrap();
+        assert!(context.verify_proof_in_session(b"ABC", &pms, PaddingScheme::PKCS1v15, &session));
+    }
+
+    #[test]
+    fn that_even_modulus_is_rejected() {
+        let (_, public_pkgs) = deal(&load_key().unwrap(), 3, 2);
+        let mut public_pkg = public_pkgs[0].clone();
+        let n = public_pkg.public_key.n() + 1u8;
+        public_pkg.public_key = RsaPublicKey::new_unchecked(n, public_pkg.public_key.e().clone());
+        assert!(KeyContext::new(&public_pkg).is_err());
+    }
+
+    #[test]
+    fn that_context_is_interchangeable_with_the_free_functions() {
+        let max_signers = 3;
+        let padding_scheme = PaddingScheme::PKCS1v15;
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), max_signers, 2);
+        let public_pkg = &public_pkgs[0];
+        let context = KeyContext::new(public_pkg).unwrap();
+        let msg = b"hello";
+
+        let from_context = context.sign(&secret_pkgs[0], msg, padding_scheme).unwrap();
+        assert!(verify_proof(
+            msg,
+            &public_pkg.v,
+            context.delta(),
+            &public_pkg.verification_keys[0],
+            &from_context,
+            &secret_pkgs[0].share.n,
+            secret_pkgs[0].share.key_bytes_size,
+            padding_scheme,
+        ));
+        let from_package = secret_pkgs[2]
+            .sign(
+                msg,
+                max_signers,
+                public_pkg.v.clone(),
+                &public_pkg.verification_keys[2],
+                padding_scheme,
+            )
+            .unwrap();
+        assert!(context.verify_proof(msg, &from_package, padding_scheme));
+        assert!(context.verify_proof(msg, &from_context, padding_scheme));
+        assert!(!context.verify_proof(b"another", &from_context, padding_scheme));
+
+        let shares = vec![from_context, from_package];
+        // Duplicate and unknown ids are refused instead of breaking the interpolation
+        let mut unknown = shares[1].clone();
+        unknown.id = 99;
+        for broken in [
+            vec![shares[0].clone(), shares[0].clone()],
+            vec![shares[0].clone(), unknown],
+        ] {
+            assert!(context.combine_shares(msg, broken, padding_scheme).is_err());
+        }
+        assert_eq!(
+            context
+                .combine_shares(msg, shares.clone(), padding_scheme)
+                .unwrap(),
+            combine_shares(
+                msg,
+                context.delta(),
+                shares,
+                &secret_pkgs[0].share,
+                max_signers as usize,
+                padding_scheme
+            )
+            .unwrap()
+        );
+    }
+}
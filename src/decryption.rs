@@ -0,0 +1,260 @@
+// Threshold RSA-OAEP decryption, Shoup's protocol applied to a ciphertext instead of a padded
+// message digest.
+//
+// WARNING: a decryption share is exactly a raw signature share, `sign_raw_with_share` of the
+// ciphertext, and nothing tells a decryption request from a signing request. Whoever gets the
+// shares of any value of its choice "decrypted" holds a raw RSA signature on it, so a decryption
+// service is a signing oracle for its key. A key used for decryption must never sign, and a
+// signing key must never decrypt; deal a separate key for each.
+
+use crate::padding::oaep_decode;
+use crate::{
+    combine_raw_shares, factorial, sign_raw_with_share, verify_raw_proof, PartialMessageSignature,
+    RsaSecretShare, RsaVerificationKey, SecretPackage,
+};
+use num_bigint::BigUint;
+use rsa::hazmat::uint_to_zeroizing_be_pad;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+/// A decryption share is the partial exponentiation of the ciphertext together with the same proof
+/// of correctness as a signature share.
+pub type PartialDecryption = PartialMessageSignature;
+
+#[derive(Error, Debug, Serialize, Deserialize)]
+pub enum DecryptionError {
+    #[error("The ciphertext is not smaller than the modulus")]
+    CiphertextTooBig,
+    #[error("The decryption shares cannot be combined")]
+    CombiningFailed,
+    #[error("Decryption error")]
+    DecryptionError,
+}
+
+impl SecretPackage {
+    pub fn decrypt(
+        &self,
+        ciphertext: &[u8],
+        max_signers: u16,
+        v: BigUint,
+        vi: &RsaVerificationKey,
+    ) -> Result<PartialDecryption, DecryptionError> {
+        let delta = factorial(max_signers as usize);
+        decrypt_with_share(ciphertext, delta, &self.share, &v, vi)
+    }
+}
+
+fn ciphertext_to_uint(ciphertext: &[u8], n: &BigUint) -> Result<BigUint, DecryptionError> {
+    let c = BigUint::from_bytes_be(ciphertext);
+    if &c >= n {
+        return Err(DecryptionError::CiphertextTooBig);
+    }
+    Ok(c)
+}
+
+/// c_i = c^{2 \delta s_i}, together with the proof of correctness. The key must be a decryption
+/// only key, see the top of the module.
+pub fn decrypt_with_share(
+    ciphertext: &[u8],
+    delta: usize,
+    share: &RsaSecretShare,
+    v: &BigUint,
+    vi: &RsaVerificationKey,
+) -> Result<PartialDecryption, DecryptionError> {
+    let c = ciphertext_to_uint(ciphertext, &share.n)?;
+    Ok(sign_raw_with_share(&c, delta, share, v, vi))
+}
+
+pub fn verify_decryption_proof(
+    ciphertext: &[u8],
+    v: &BigUint,
+    delta: usize,
+    vi: &RsaVerificationKey,
+    partial_decryption: &PartialDecryption,
+    n: &BigUint,
+) -> bool {
+    match ciphertext_to_uint(ciphertext, n) {
+        Ok(c) => verify_raw_proof(&c, v, delta, vi, partial_decryption, n),
+        Err(_) => false,
+    }
+}
+
+/// Combine the decryption shares and remove the OAEP (SHA-256, MGF1-SHA-256) padding.
+///
+/// The shares are expected to be verified by `verify_decryption_proof` beforehand. Duplicate or
+/// unknown ids are refused, and so are too few shares, the result is checked by m^e = c.
+pub fn combine_decryption_shares(
+    ciphertext: &[u8],
+    delta: usize,
+    decryption_shares: Vec<PartialDecryption>,
+    key_share: &RsaSecretShare,
+    l: usize,
+    label: Option<&[u8]>,
+) -> Result<Vec<u8>, DecryptionError> {
+    let c = ciphertext_to_uint(ciphertext, &key_share.n)?;
+    let m = combine_raw_shares(&c, delta, &decryption_shares, &key_share.n, &key_share.e, l)
+        .map_err(|_| DecryptionError::CombiningFailed)?;
+    if m.modpow(&key_share.e, &key_share.n) != c {
+        return Err(DecryptionError::CombiningFailed);
+    }
+    let mut em = Zeroizing::new(
+        uint_to_zeroizing_be_pad(m, key_share.key_bytes_size)
+            .map_err(|_| DecryptionError::CombiningFailed)?,
+    );
+    oaep_decode::<Sha256>(&mut em, label.unwrap_or_default())
+        .ok_or(DecryptionError::DecryptionError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generate_secret_shares, generate_verification, load_key, RSAThresholdPublicKey};
+    use rand_chacha::{rand_core::SeedableRng, ChaCha20Rng};
+    use rsa::{Oaep, RsaPublicKey};
+
+    #[test]
+    fn that_threshold_number_of_signers_decrypts_oaep_ciphertext() {
+        let l = 3;
+        let k = 2;
+        let sk = load_key().unwrap();
+        let pubkey = RSAThresholdPublicKey::from(&sk);
+        let shares = generate_secret_shares(&sk, l, k);
+        let (v, verification_keys) = generate_verification(&pubkey, shares.clone());
+        let delta = factorial(l);
+
+        let msg = b"data encryption key";
+        let mut rng = ChaCha20Rng::from_entropy();
+        let ciphertext = RsaPublicKey::from(&sk)
+            .encrypt(&mut rng, Oaep::new::<Sha256>(), msg)
+            .unwrap();
+
+        let partial_decryptions: Vec<PartialDecryption> = [0, 2]
+            .iter()
+            .map(|&i| {
+                decrypt_with_share(&ciphertext, delta, &shares[i], &v, &verification_keys[i])
+                    .unwrap()
+            })
+            .collect();
+        for (pd, i) in partial_decryptions.iter().zip([0, 2]) {
+            assert!(verify_decryption_proof(
+                &ciphertext,
+                &v,
+                delta,
+                &verification_keys[i],
+                pd,
+                &pubkey.n
+            ));
+        }
+
+        let combine = |partial_decryptions: Vec<PartialDecryption>| {
+            combine_decryption_shares(&ciphertext, delta, partial_decryptions, &shares[0], l, None)
+        };
+        // below the threshold, the same share twice and an unknown share
+        for partial_decryptions in [
+            vec![partial_decryptions[0].clone()],
+            vec![
+                partial_decryptions[0].clone(),
+                partial_decryptions[0].clone(),
+            ],
+            vec![partial_decryptions[0].clone(), {
+                let mut unknown = partial_decryptions[1].clone();
+                unknown.id = l + 1;
+                unknown
+            }],
+        ] {
+            assert!(matches!(
+                combine(partial_decryptions),
+                Err(DecryptionError::CombiningFailed)
+            ));
+        }
+        let plaintext = combine(partial_decryptions).unwrap();
+        assert_eq!(plaintext, msg);
+    }
+
+    #[test]
+    fn that_label_is_checked_when_unpadding() {
+        let l = 2;
+        let k = 2;
+        let sk = load_key().unwrap();
+        let pubkey = RSAThresholdPublicKey::from(&sk);
+        let shares = generate_secret_shares(&sk, l, k);
+        let (v, verification_keys) = generate_verification(&pubkey, shares.clone());
+        let delta = factorial(l);
+
+        let mut rng = ChaCha20Rng::from_entropy();
+        let ciphertext = RsaPublicKey::from(&sk)
+            .encrypt(
+                &mut rng,
+                Oaep::new_with_label::<Sha256, _>("escrow"),
+                b"hello",
+            )
+            .unwrap();
+        let partial_decryptions: Vec<PartialDecryption> = (0..l)
+            .map(|i| {
+                decrypt_with_share(&ciphertext, delta, &shares[i], &v, &verification_keys[i])
+                    .unwrap()
+            })
+            .collect();
+
+        assert!(combine_decryption_shares(
+            &ciphertext,
+            delta,
+            partial_decryptions.clone(),
+            &shares[0],
+            l,
+            None
+        )
+        .is_err());
+        assert_eq!(
+            combine_decryption_shares(
+                &ciphertext,
+                delta,
+                partial_decryptions,
+                &shares[0],
+                l,
+                Some(b"escrow")
+            )
+            .unwrap(),
+            b"hello"
+        );
+    }
+
+    #[test]
+    fn that_tampered_partial_decryption_does_not_verify() {
+        let l = 2;
+        let k = 2;
+        let sk = load_key().unwrap();
+        let pubkey = RSAThresholdPublicKey::from(&sk);
+        let shares = generate_secret_shares(&sk, l, k);
+        let (v, verification_keys) = generate_verification(&pubkey, shares.clone());
+        let delta = factorial(l);
+
+        let ciphertext = BigUint::from(0x1234_5678u32).to_bytes_be();
+        let mut pd =
+            decrypt_with_share(&ciphertext, delta, &shares[0], &v, &verification_keys[0]).unwrap();
+        pd.xi += 1u8;
+        assert!(!verify_decryption_proof(
+            &ciphertext,
+            &v,
+            delta,
+            &verification_keys[0],
+            &pd,
+            &pubkey.n
+        ));
+    }
+
+    #[test]
+    fn that_ciphertext_bigger_than_modulus_is_rejected() {
+        let sk = load_key().unwrap();
+        let shares = generate_secret_shares(&sk, 2, 2);
+        let (v, verification_keys) =
+            generate_verification(&RSAThresholdPublicKey::from(&sk), shares.clone());
+        let ciphertext = (sk.n.clone() + 1u8).to_bytes_be();
+        assert!(matches!(
+            decrypt_with_share(&ciphertext, 2, &shares[0], &v, &verification_keys[0]),
+            Err(DecryptionError::CiphertextTooBig)
+        ));
+    }
+}
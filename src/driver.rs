@@ -0,0 +1,637 @@
+// Asynchronous driver of the protocols over an arbitrary transport.
+//
+// A protocol is a state machine implementing `Protocol`, it consumes the received messages and
+// returns the messages to send. `run` moves the messages between the state machine and a
+// `Transport` until the protocol has an output or the time runs out. The driver uses only the
+// `futures` crates, so it runs on tokio, async-std or a plain executor alike.
+//
+// The state machines do the exponentiations inline, on tokio they should be run from
+// `spawn_blocking` or a dedicated thread. The dealing sends the shares as they are, the transport
+// has to be confidential and authenticated, or the packages can be sealed with `share_bundle`.
+// The encoded shares are erased once sent or received, the copies the transport makes are its own.
+//
+// There is no proactive refresh: the dealer can only deal a key again, which leaves the old shares
+// valid. A refresh where the signers rerandomize their shares without a dealer is out of scope.
+
+use crate::context::KeyContext;
+use crate::format::{FormatError, Versioned};
+use crate::session::{random_session_id, SessionId};
+use crate::{
+    deal, generate_with_dealer, KeyGenError, PaddingScheme, PartialMessageSignature, PublicPackage,
+    RSAThresholdPrivateKey, SecretPackage,
+};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::future::{select, Either};
+use futures::lock::Mutex;
+use futures::StreamExt;
+use futures_timer::Delay;
+use log::warn;
+use rsa::traits::PublicKeyParts;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::pin::pin;
+use std::time::Duration;
+use thiserror::Error;
+use zeroize::{Zeroize, Zeroizing};
+
+pub type ParticipantId = usize;
+/// Messages produced by a protocol step, addressed to the participants.
+pub type Outgoing = Vec<(ParticipantId, Vec<u8>)>;
+
+#[derive(Error, Debug, Serialize, Deserialize)]
+pub enum DriverError {
+    #[error("Transport error: {0}")]
+    Transport(String),
+    #[error("Participant {0} is unknown to the transport")]
+    UnknownParticipant(ParticipantId),
+    #[error("The protocol has timed out")]
+    Timeout,
+    #[error("Malformed message: {0}")]
+    Malformed(String),
+    #[error("The received share does not match the public package")]
+    InvalidShare,
+    #[error("Key generation failed: {0}")]
+    KeyGen(String),
+    #[error("Only PKCS#1 v1.5 encoded messages are signed for a peer")]
+    Refused,
+    #[error("Signing failed")]
+    Signing,
+}
+
+impl From<FormatError> for DriverError {
+    fn from(e: FormatError) -> Self {
+        DriverError::Malformed(e.to_string())
+    }
+}
+
+impl From<KeyGenError> for DriverError {
+    fn from(e: KeyGenError) -> Self {
+        DriverError::KeyGen(e.to_string())
+    }
+}
+
+/// Delivery of serialized messages between the participants.
+pub trait Transport {
+    fn id(&self) -> ParticipantId;
+    fn send(
+        &self,
+        to: ParticipantId,
+        message: Vec<u8>,
+    ) -> impl Future<Output = Result<(), DriverError>> + Send;
+    /// The next message for this participant together with its sender.
+    fn recv(&self) -> impl Future<Output = Result<(ParticipantId, Vec<u8>), DriverError>> + Send;
+}
+
+pub trait Protocol {
+    type Output;
+
+    fn start(&mut self) -> Result<Outgoing, DriverError>;
+    fn handle(&mut self, from: ParticipantId, message: &[u8]) -> Result<Outgoing, DriverError>;
+    /// `Some` once the protocol is over.
+    fn output(&mut self) -> Option<Self::Output>;
+}
+
+/// Run the protocol to its end, or fail after `timeout`.
+pub async fn run<P: Protocol, T: Transport>(
+    mut protocol: P,
+    transport: &T,
+    timeout: Duration,
+) -> Result<P::Output, DriverError> {
+    let rounds = async {
+        for (to, message) in protocol.start()? {
+            transport.send(to, message).await?;
+        }
+        loop {
+            if let Some(output) = protocol.output() {
+                return Ok(output);
+            }
+            let (from, message) = transport.recv().await?;
+            let message = Zeroizing::new(message);
+            for (to, message) in protocol.handle(from, &message)? {
+                transport.send(to, message).await?;
+            }
+        }
+    };
+    match select(pin!(rounds), Delay::new(timeout)).await {
+        Either::Left((result, _)) => result,
+        Either::Right(_) => Err(DriverError::Timeout),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum Message {
+    /// The packages in the versioned CBOR format
+    Deal {
+        secret_pkg: Zeroizing<Vec<u8>>,
+        public_pkg: Vec<u8>,
+    },
+    SignRequest {
+        session: SessionId,
+        message: Vec<u8>,
+        padding_scheme: PaddingScheme,
+    },
+    PartialSignature {
+        session: SessionId,
+        partial: Box<PartialMessageSignature>,
+    },
+}
+
+impl Message {
+    fn encode(&self) -> Result<Vec<u8>, DriverError> {
+        // A deal is written without growing the buffer, no copy of the share is left behind.
+        // The bytes are encoded as an array, up to two bytes each.
+        let capacity = match self {
+            Message::Deal {
+                secret_pkg,
+                public_pkg,
+            } => 2 * (secret_pkg.len() + public_pkg.len()) + 64,
+            _ => 0,
+        };
+        let mut bytes = Vec::with_capacity(capacity);
+        ciborium::ser::into_writer(self, &mut bytes)
+            .map_err(|e| DriverError::Malformed(e.to_string()))?;
+        Ok(bytes)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, DriverError> {
+        ciborium::de::from_reader(bytes).map_err(|e| DriverError::Malformed(e.to_string()))
+    }
+}
+
+/// The trusted dealer, sends `secret_pkgs[i]` to `participants[i]`.
+pub struct Dealer {
+    packages: Option<Outgoing>,
+}
+
+impl Dealer {
+    pub fn new(
+        secret_pkgs: &[SecretPackage],
+        public_pkg: &PublicPackage,
+        participants: &[ParticipantId],
+    ) -> Result<Self, DriverError> {
+        if secret_pkgs.len() != participants.len() {
+            return Err(DriverError::KeyGen(
+                "the numbers of packages and participants differ".into(),
+            ));
+        }
+        let public_pkg = public_pkg.to_cbor()?;
+        let packages = secret_pkgs
+            .iter()
+            .zip(participants)
+            .map(|(secret_pkg, &participant)| {
+                let message = Message::Deal {
+                    secret_pkg: Zeroizing::new(secret_pkg.to_cbor()?),
+                    public_pkg: public_pkg.clone(),
+                };
+                Ok((participant, message.encode()?))
+            })
+            .collect::<Result<_, DriverError>>()?;
+        Ok(Dealer {
+            packages: Some(packages),
+        })
+    }
+
+    /// Key generation, a fresh key is split among the participants.
+    pub fn generate(
+        key_bit_length: usize,
+        participants: &[ParticipantId],
+        min_signers: u16,
+    ) -> Result<Self, DriverError> {
+        let (secret_pkgs, public_pkgs) =
+            generate_with_dealer(participants.len() as u16, min_signers, key_bit_length)?;
+        Self::new(&secret_pkgs, &public_pkgs[0], participants)
+    }
+
+    /// The same key dealt anew, e.g. for other participants or another threshold. This is not a
+    /// refresh: the dealer needs the whole private key and the old shares stay valid, they still
+    /// combine into signatures of the key, so their holders have to erase them.
+    pub fn redeal(
+        private_key: &RSAThresholdPrivateKey,
+        participants: &[ParticipantId],
+        min_signers: u16,
+    ) -> Result<Self, DriverError> {
+        let (secret_pkgs, public_pkgs) = deal(private_key, participants.len() as u16, min_signers);
+        Self::new(&secret_pkgs, &public_pkgs[0], participants)
+    }
+}
+
+impl Drop for Dealer {
+    fn drop(&mut self) {
+        for (_, message) in self.packages.iter_mut().flatten() {
+            message.zeroize();
+        }
+    }
+}
+
+impl Protocol for Dealer {
+    type Output = ();
+
+    fn start(&mut self) -> Result<Outgoing, DriverError> {
+        Ok(self.packages.take().unwrap_or_default())
+    }
+
+    fn handle(&mut self, _: ParticipantId, _: &[u8]) -> Result<Outgoing, DriverError> {
+        Ok(Vec::new())
+    }
+
+    fn output(&mut self) -> Option<()> {
+        self.packages.is_none().then_some(())
+    }
+}
+
+/// The counterpart of the `Dealer`, checks the received share against v_i.
+pub struct ShareReceiver {
+    dealer: ParticipantId,
+    packages: Option<(SecretPackage, PublicPackage)>,
+}
+
+impl ShareReceiver {
+    pub fn new(dealer: ParticipantId) -> Self {
+        ShareReceiver {
+            dealer,
+            packages: None,
+        }
+    }
+}
+
+impl Protocol for ShareReceiver {
+    type Output = (SecretPackage, PublicPackage);
+
+    fn start(&mut self) -> Result<Outgoing, DriverError> {
+        Ok(Vec::new())
+    }
+
+    fn handle(&mut self, from: ParticipantId, message: &[u8]) -> Result<Outgoing, DriverError> {
+        if from != self.dealer {
+            return Ok(Vec::new());
+        }
+        let Message::Deal {
+            secret_pkg,
+            public_pkg,
+        } = Message::decode(message)?
+        else {
+            return Ok(Vec::new());
+        };
+        let secret_pkg = SecretPackage::from_cbor(&secret_pkg)?;
+        let public_pkg = PublicPackage::from_cbor(&public_pkg)?;
+        let n = public_pkg.public_key.n();
+        let share = &secret_pkg.share;
+        if !public_pkg
+            .verification_keys
+            .iter()
+            .any(|vi| vi.id == share.id && public_pkg.v.modpow(&share.share, n) == vi.key)
+        {
+            return Err(DriverError::InvalidShare);
+        }
+        self.packages = Some((secret_pkg, public_pkg));
+        Ok(Vec::new())
+    }
+
+    fn output(&mut self) -> Option<Self::Output> {
+        self.packages.take()
+    }
+}
+
+/// Asks all the signers and combines the first `threshold` valid partial signatures.
+pub struct SigningCoordinator {
+    context: KeyContext,
+    session: SessionId,
+    message: Vec<u8>,
+    padding_scheme: PaddingScheme,
+    signers: Vec<ParticipantId>,
+    threshold: usize,
+    /// The partial signatures by the share id
+    partials: HashMap<usize, PartialMessageSignature>,
+    responded: HashSet<ParticipantId>,
+    signature: Option<Vec<u8>>,
+}
+
+impl SigningCoordinator {
+    pub fn new(
+        public_pkg: &PublicPackage,
+        signers: Vec<ParticipantId>,
+        threshold: usize,
+        message: &[u8],
+        padding_scheme: PaddingScheme,
+    ) -> Result<Self, DriverError> {
+        let context = KeyContext::new(public_pkg).map_err(|_| DriverError::Signing)?;
+        // The responders would refuse the request anyway
+        context
+            .check_remote_request(message, padding_scheme)
+            .map_err(|_| DriverError::Refused)?;
+        Ok(SigningCoordinator {
+            context,
+            session: random_session_id(),
+            message: message.to_vec(),
+            padding_scheme,
+            signers,
+            threshold,
+            partials: HashMap::new(),
+            responded: HashSet::new(),
+            signature: None,
+        })
+    }
+}
+
+impl Protocol for SigningCoordinator {
+    type Output = Vec<u8>;
+
+    fn start(&mut self) -> Result<Outgoing, DriverError> {
+        let request = Message::SignRequest {
+            session: self.session,
+            message: self.message.clone(),
+            padding_scheme: self.padding_scheme,
+        }
+        .encode()?;
+        Ok(self
+            .signers
+            .iter()
+            .map(|&signer| (signer, request.clone()))
+            .collect())
+    }
+
+    fn handle(&mut self, from: ParticipantId, message: &[u8]) -> Result<Outgoing, DriverError> {
+        // Late, replayed or malformed answers are dropped, the other signers may still make it
+        if self.signature.is_some() || !self.signers.contains(&from) {
+            return Ok(Vec::new());
+        }
+        let Ok(Message::PartialSignature { session, partial }) = Message::decode(message) else {
+            warn!("Participant {from} has sent an unexpected message");
+            return Ok(Vec::new());
+        };
+        if session != self.session
+            || self.partials.contains_key(&partial.id)
+            || !self.responded.insert(from)
+        {
+            return Ok(Vec::new());
+        }
+        if !self.context.verify_proof_in_session(
+            &self.message,
+            &partial,
+            self.padding_scheme,
+            &self.session,
+        ) {
+            warn!("Participant {from} has sent an invalid partial signature");
+            return Ok(Vec::new());
+        }
+        self.partials.insert(partial.id, *partial);
+
+        if self.partials.len() == self.threshold {
+            let partials = std::mem::take(&mut self.partials).into_values().collect();
+            self.signature = Some(
+                self.context
+                    .combine_shares(&self.message, partials, self.padding_scheme)
+                    .map_err(|_| DriverError::Signing)?,
+            );
+        }
+        Ok(Vec::new())
+    }
+
+    fn output(&mut self) -> Option<Vec<u8>> {
+        self.signature.take()
+    }
+}
+
+/// Answers one signing request of the coordinator.
+pub struct SigningResponder {
+    context: KeyContext,
+    secret_pkg: SecretPackage,
+    coordinator: ParticipantId,
+    partial: Option<PartialMessageSignature>,
+}
+
+impl SigningResponder {
+    pub fn new(
+        secret_pkg: SecretPackage,
+        public_pkg: &PublicPackage,
+        coordinator: ParticipantId,
+    ) -> Result<Self, DriverError> {
+        Ok(SigningResponder {
+            context: KeyContext::new(public_pkg).map_err(|_| DriverError::Signing)?,
+            secret_pkg,
+            coordinator,
+            partial: None,
+        })
+    }
+}
+
+impl Protocol for SigningResponder {
+    /// The partial signature that was sent
+    type Output = PartialMessageSignature;
+
+    fn start(&mut self) -> Result<Outgoing, DriverError> {
+        Ok(Vec::new())
+    }
+
+    fn handle(&mut self, from: ParticipantId, message: &[u8]) -> Result<Outgoing, DriverError> {
+        if from != self.coordinator {
+            return Ok(Vec::new());
+        }
+        let Message::SignRequest {
+            session,
+            message,
+            padding_scheme,
+        } = Message::decode(message)?
+        else {
+            return Ok(Vec::new());
+        };
+        self.context
+            .check_remote_request(&message, padding_scheme)
+            .map_err(|_| DriverError::Refused)?;
+        let partial = self
+            .context
+            .sign_in_session(&self.secret_pkg, &message, padding_scheme, &session)
+            .map_err(|_| DriverError::Signing)?;
+        let response = Message::PartialSignature {
+            session,
+            partial: Box::new(partial.clone()),
+        }
+        .encode()?;
+        self.partial = Some(partial);
+        Ok(vec![(from, response)])
+    }
+
+    fn output(&mut self) -> Option<PartialMessageSignature> {
+        self.partial.take()
+    }
+}
+
+/// One endpoint of the `memory_network`.
+pub struct MemoryTransport {
+    id: ParticipantId,
+    peers: HashMap<ParticipantId, UnboundedSender<(ParticipantId, Vec<u8>)>>,
+    inbox: Mutex<UnboundedReceiver<(ParticipantId, Vec<u8>)>>,
+}
+
+/// Connected in-memory transports of the given participants, for tests and single process setups.
+pub fn memory_network(participants: &[ParticipantId]) -> Vec<MemoryTransport> {
+    let (senders, receivers): (Vec<_>, Vec<_>) = participants.iter().map(|_| unbounded()).unzip();
+    let peers: HashMap<_, _> = participants.iter().copied().zip(senders).collect();
+    participants
+        .iter()
+        .zip(receivers)
+        .map(|(&id, inbox)| MemoryTransport {
+            id,
+            peers: peers.clone(),
+            inbox: Mutex::new(inbox),
+        })
+        .collect()
+}
+
+impl Transport for MemoryTransport {
+    fn id(&self) -> ParticipantId {
+        self.id
+    }
+
+    async fn send(&self, to: ParticipantId, message: Vec<u8>) -> Result<(), DriverError> {
+        self.peers
+            .get(&to)
+            .ok_or(DriverError::UnknownParticipant(to))?
+            .unbounded_send((self.id, message))
+            .map_err(|e| DriverError::Transport(e.to_string()))
+    }
+
+    async fn recv(&self) -> Result<(ParticipantId, Vec<u8>), DriverError> {
+        self.inbox
+            .lock()
+            .await
+            .next()
+            .await
+            .ok_or_else(|| DriverError::Transport("all the senders are gone".into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::load_key;
+    use crate::transcript::ProofVersion;
+    use futures::executor::block_on;
+    use futures::future::join_all;
+    use rsa::Pkcs1v15Sign;
+
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn that_redealt_shares_are_dealt_and_sign() {
+        let network = memory_network(&[0, 1, 2, 3]);
+        let dealer = Dealer::redeal(&load_key().unwrap(), &[1, 2, 3], 2).unwrap();
+        let (dealt, received) = block_on(async {
+            futures::join!(
+                run(dealer, &network[0], TIMEOUT),
+                join_all(network[1..].iter().map(|transport| run(
+                    ShareReceiver::new(0),
+                    transport,
+                    TIMEOUT
+                )))
+            )
+        });
+        dealt.unwrap();
+        let packages: Vec<_> = received.into_iter().map(Result::unwrap).collect();
+        let public_pkg = &packages[0].1;
+
+        let message = b"ABC";
+        let coordinator = SigningCoordinator::new(
+            public_pkg,
+            vec![1, 2, 3],
+            2,
+            message,
+            PaddingScheme::PKCS1v15,
+        )
+        .unwrap();
+        let responders = packages
+            .iter()
+            .zip(&network[1..])
+            .map(|((secret_pkg, _), transport)| {
+                let responder = SigningResponder::new(secret_pkg.clone(), public_pkg, 0).unwrap();
+                run(responder, transport, TIMEOUT)
+            });
+        let (signature, partials) = block_on(async {
+            futures::join!(run(coordinator, &network[0], TIMEOUT), join_all(responders))
+        });
+        assert!(partials.iter().all(Result::is_ok));
+        assert!(public_pkg
+            .public_key
+            .verify(Pkcs1v15Sign::new_unprefixed(), message, &signature.unwrap())
+            .is_ok());
+    }
+
+    #[test]
+    fn that_missing_responders_time_out() {
+        let network = memory_network(&[0, 1, 2]);
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 2, 2);
+        let coordinator = SigningCoordinator::new(
+            &public_pkgs[0],
+            vec![1, 2],
+            2,
+            b"ABC",
+            PaddingScheme::PKCS1v15,
+        )
+        .unwrap();
+        let responder = SigningResponder::new(secret_pkgs[0].clone(), &public_pkgs[0], 0).unwrap();
+        let (signature, partial) = block_on(async {
+            futures::join!(
+                run(coordinator, &network[0], Duration::from_millis(500)),
+                run(responder, &network[1], TIMEOUT)
+            )
+        });
+        assert!(partial.is_ok());
+        assert!(matches!(signature, Err(DriverError::Timeout)));
+    }
+
+    #[test]
+    fn that_foreign_answers_are_ignored() {
+        let (_, public_pkgs) = deal(&load_key().unwrap(), 2, 2);
+        let mut coordinator = SigningCoordinator::new(
+            &public_pkgs[0],
+            vec![1, 2],
+            2,
+            b"ABC",
+            PaddingScheme::PKCS1v15,
+        )
+        .unwrap();
+        coordinator.start().unwrap();
+        assert!(coordinator.handle(1, b"garbage").unwrap().is_empty());
+        let stale = Message::PartialSignature {
+            session: random_session_id(),
+            partial: Box::new(PartialMessageSignature {
+                id: 1,
+                xi: 1u8.into(),
+                z: 1u8.into(),
+                c: 1u8.into(),
+                commitments: None,
+                proof_version: ProofVersion::CURRENT,
+            }),
+        }
+        .encode()
+        .unwrap();
+        assert!(coordinator.handle(1, &stale).unwrap().is_empty());
+        assert!(coordinator.responded.is_empty());
+        assert!(coordinator.output().is_none());
+    }
+
+    #[test]
+    fn that_raw_requests_are_refused() {
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 2, 2);
+        assert!(matches!(
+            SigningCoordinator::new(&public_pkgs[0], vec![1], 1, b"ABC", PaddingScheme::NONE),
+            Err(DriverError::Refused)
+        ));
+
+        let mut responder =
+            SigningResponder::new(secret_pkgs[0].clone(), &public_pkgs[0], 0).unwrap();
+        let request = Message::SignRequest {
+            session: random_session_id(),
+            message: vec![2; 16],
+            padding_scheme: PaddingScheme::NONE,
+        }
+        .encode()
+        .unwrap();
+        assert!(matches!(
+            responder.handle(0, &request),
+            Err(DriverError::Refused)
+        ));
+        assert!(responder.output().is_none());
+    }
+}
@@ -0,0 +1,255 @@
+// Standard encodings of the threshold public key.
+//
+// The RSA public key is exported as SubjectPublicKeyInfo or PKCS#1 RSAPublicKey, so that relying
+// parties can use any RSA implementation. The extended format carries the whole `PublicPackage`:
+//
+// ThresholdRsaPublicKey ::= SEQUENCE {
+//     version          INTEGER,  -- 1
+//     publicKey        SubjectPublicKeyInfo,
+//     groupSize        INTEGER,
+//     v                INTEGER,
+//     verificationKeys SEQUENCE OF VerificationKey
+// }
+//
+// VerificationKey ::= SEQUENCE {
+//     id  INTEGER,
+//     key INTEGER
+// }
+
+use crate::{public_key_from_components, PublicPackage, RsaVerificationKey};
+use der::asn1::Uint;
+use der::pem::PemLabel;
+use der::{Decode, DecodePem, Encode, EncodePem, Sequence};
+use num_bigint::BigUint;
+use rsa::pkcs1::{EncodeRsaPublicKey, LineEnding, ALGORITHM_OID};
+use rsa::pkcs8::spki::SubjectPublicKeyInfoOwned;
+use rsa::pkcs8::EncodePublicKey;
+use rsa::RsaPublicKey;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Version of the extended format written by this crate.
+pub const EXTENDED_FORMAT_VERSION: u8 = 1;
+
+#[derive(Error, Debug, Serialize, Deserialize)]
+pub enum EncodingError {
+    #[error("The key cannot be encoded or decoded: {0}")]
+    Der(String),
+    #[error("Unsupported version {0} of the extended public key format")]
+    UnsupportedVersion(u8),
+    #[error("The number of verification keys does not match the group size")]
+    Inconsistent,
+}
+
+impl From<der::Error> for EncodingError {
+    fn from(e: der::Error) -> Self {
+        EncodingError::Der(e.to_string())
+    }
+}
+
+impl From<rsa::pkcs8::spki::Error> for EncodingError {
+    fn from(e: rsa::pkcs8::spki::Error) -> Self {
+        EncodingError::Der(e.to_string())
+    }
+}
+
+impl From<rsa::pkcs1::Error> for EncodingError {
+    fn from(e: rsa::pkcs1::Error) -> Self {
+        EncodingError::Der(e.to_string())
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Sequence)]
+struct VerificationKeyAsn1 {
+    id: u64,
+    key: Uint,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Sequence)]
+struct ThresholdPublicKeyAsn1 {
+    version: u8,
+    public_key: SubjectPublicKeyInfoOwned,
+    group_size: u64,
+    v: Uint,
+    verification_keys: Vec<VerificationKeyAsn1>,
+}
+
+impl PemLabel for ThresholdPublicKeyAsn1 {
+    const PEM_LABEL: &'static str = "THRESHOLD RSA PUBLIC KEY";
+}
+
+fn to_uint(value: &BigUint) -> Result<Uint, EncodingError> {
+    Ok(Uint::new(&value.to_bytes_be())?)
+}
+
+fn from_uint(value: &Uint) -> BigUint {
+    BigUint::from_bytes_be(value.as_bytes())
+}
+
+impl PublicPackage {
+    /// SubjectPublicKeyInfo, the `PUBLIC KEY` PEM label
+    pub fn to_public_key_der(&self) -> Result<Vec<u8>, EncodingError> {
+        Ok(self.public_key.to_public_key_der()?.into_vec())
+    }
+
+    pub fn to_public_key_pem(&self, line_ending: LineEnding) -> Result<String, EncodingError> {
+        Ok(self.public_key.to_public_key_pem(line_ending)?)
+    }
+
+    /// PKCS#1 RSAPublicKey, the `RSA PUBLIC KEY` PEM label
+    pub fn to_pkcs1_der(&self) -> Result<Vec<u8>, EncodingError> {
+        Ok(self.public_key.to_pkcs1_der()?.into_vec())
+    }
+
+    pub fn to_pkcs1_pem(&self, line_ending: LineEnding) -> Result<String, EncodingError> {
+        Ok(self.public_key.to_pkcs1_pem(line_ending)?)
+    }
+
+    /// The extended format with v and the verification keys
+    pub fn to_extended_der(&self) -> Result<Vec<u8>, EncodingError> {
+        Ok(self.to_asn1()?.to_der()?)
+    }
+
+    pub fn to_extended_pem(&self, line_ending: LineEnding) -> Result<String, EncodingError> {
+        Ok(self.to_asn1()?.to_pem(line_ending)?)
+    }
+
+    pub fn from_extended_der(bytes: &[u8]) -> Result<Self, EncodingError> {
+        Self::from_asn1(ThresholdPublicKeyAsn1::from_der(bytes)?)
+    }
+
+    pub fn from_extended_pem(pem: &str) -> Result<Self, EncodingError> {
+        Self::from_asn1(ThresholdPublicKeyAsn1::from_pem(pem)?)
+    }
+
+    fn to_asn1(&self) -> Result<ThresholdPublicKeyAsn1, EncodingError> {
+        let spki = self.public_key.to_public_key_der()?;
+        Ok(ThresholdPublicKeyAsn1 {
+            version: EXTENDED_FORMAT_VERSION,
+            public_key: SubjectPublicKeyInfoOwned::from_der(spki.as_bytes())?,
+            group_size: self.group_size as u64,
+            v: to_uint(&self.v)?,
+            verification_keys: self
+                .verification_keys
+                .iter()
+                .map(|vk| {
+                    Ok(VerificationKeyAsn1 {
+                        id: vk.id as u64,
+                        key: to_uint(&vk.key)?,
+                    })
+                })
+                .collect::<Result<_, EncodingError>>()?,
+        })
+    }
+
+    fn from_asn1(asn1: ThresholdPublicKeyAsn1) -> Result<Self, EncodingError> {
+        if asn1.version != EXTENDED_FORMAT_VERSION {
+            return Err(EncodingError::UnsupportedVersion(asn1.version));
+        }
+        if asn1.verification_keys.len() as u64 != asn1.group_size {
+            return Err(EncodingError::Inconsistent);
+        }
+        Ok(PublicPackage {
+            public_key: public_key_from_spki(&asn1.public_key)?,
+            group_size: asn1.group_size as usize,
+            v: from_uint(&asn1.v),
+            verification_keys: asn1
+                .verification_keys
+                .iter()
+                .map(|vk| RsaVerificationKey {
+                    id: vk.id as usize,
+                    key: from_uint(&vk.key),
+                })
+                .collect(),
+        })
+    }
+}
+
+/// Import the RSA public key from SubjectPublicKeyInfo or PKCS#1 DER.
+pub fn public_key_from_der(bytes: &[u8]) -> Result<RsaPublicKey, EncodingError> {
+    SubjectPublicKeyInfoOwned::from_der(bytes)
+        .map_err(EncodingError::from)
+        .and_then(|spki| public_key_from_spki(&spki))
+        .or_else(|_| public_key_from_pkcs1(bytes))
+}
+
+/// Import the RSA public key from the `PUBLIC KEY` or the `RSA PUBLIC KEY` PEM.
+pub fn public_key_from_pem(pem: &str) -> Result<RsaPublicKey, EncodingError> {
+    let (label, bytes) = der::pem::decode_vec(pem.as_bytes()).map_err(der::Error::from)?;
+    match label {
+        SubjectPublicKeyInfoOwned::PEM_LABEL => {
+            public_key_from_spki(&SubjectPublicKeyInfoOwned::from_der(&bytes)?)
+        }
+        "RSA PUBLIC KEY" => public_key_from_pkcs1(&bytes),
+        _ => Err(EncodingError::Der(format!("unexpected PEM label {label}"))),
+    }
+}
+
+// The decoders of `rsa` cap the modulus at 4096 bits, the keys are taken apart here instead.
+fn public_key_from_spki(spki: &SubjectPublicKeyInfoOwned) -> Result<RsaPublicKey, EncodingError> {
+    if spki.algorithm.oid != ALGORITHM_OID {
+        return Err(EncodingError::Der(format!(
+            "unexpected algorithm {}",
+            spki.algorithm.oid
+        )));
+    }
+    public_key_from_pkcs1(spki.subject_public_key.raw_bytes())
+}
+
+fn public_key_from_pkcs1(bytes: &[u8]) -> Result<RsaPublicKey, EncodingError> {
+    let key = rsa::pkcs1::RsaPublicKey::from_der(bytes)?;
+    public_key_from_components(
+        BigUint::from_bytes_be(key.modulus.as_bytes()),
+        BigUint::from_bytes_be(key.public_exponent.as_bytes()),
+    )
+    .map_err(|e| EncodingError::Der(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deal, load_key};
+
+    #[test]
+    fn that_public_key_roundtrips_in_standard_formats() {
+        let (_, public_pkgs) = deal(&load_key().unwrap(), 3, 2);
+        let public_pkg = &public_pkgs[0];
+
+        let spki_pem = public_pkg.to_public_key_pem(LineEnding::LF).unwrap();
+        assert!(spki_pem.starts_with("-----BEGIN PUBLIC KEY-----"));
+        let pkcs1_pem = public_pkg.to_pkcs1_pem(LineEnding::LF).unwrap();
+        assert!(pkcs1_pem.starts_with("-----BEGIN RSA PUBLIC KEY-----"));
+
+        for key in [
+            public_key_from_pem(&spki_pem).unwrap(),
+            public_key_from_pem(&pkcs1_pem).unwrap(),
+            public_key_from_der(&public_pkg.to_public_key_der().unwrap()).unwrap(),
+            public_key_from_der(&public_pkg.to_pkcs1_der().unwrap()).unwrap(),
+        ] {
+            assert_eq!(key, public_pkg.public_key);
+        }
+    }
+
+    #[test]
+    fn that_extended_format_roundtrips() {
+        let (_, public_pkgs) = deal(&load_key().unwrap(), 3, 2);
+        let public_pkg = &public_pkgs[0];
+
+        let der = public_pkg.to_extended_der().unwrap();
+        assert_eq!(&PublicPackage::from_extended_der(&der).unwrap(), public_pkg);
+        let pem = public_pkg.to_extended_pem(LineEnding::LF).unwrap();
+        assert!(pem.starts_with("-----BEGIN THRESHOLD RSA PUBLIC KEY-----"));
+        assert_eq!(&PublicPackage::from_extended_pem(&pem).unwrap(), public_pkg);
+    }
+
+    #[test]
+    fn that_unknown_version_is_rejected() {
+        let (_, public_pkgs) = deal(&load_key().unwrap(), 2, 2);
+        let mut asn1 = public_pkgs[0].to_asn1().unwrap();
+        asn1.version = 2;
+        assert!(matches!(
+            PublicPackage::from_extended_der(&asn1.to_der().unwrap()),
+            Err(EncodingError::UnsupportedVersion(2))
+        ));
+    }
+}
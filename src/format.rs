@@ -0,0 +1,568 @@
+// Versioned serialization of the packages and the protocol messages.
+//
+// The serde derives on the structs themselves follow the field layout and the u32 digits of
+// num-bigint-dig, the formats defined here do not. Every document is a map with
+//
+//   version  unsigned integer, `FORMAT_VERSION`
+//   suite    text, `suite`, the protocol and the proof hash, e.g. "shoup00-rsa-sha256"
+//   kind     text, one of "secret-package", "public-package", "partial-signature"
+//
+// followed by the fields of the kind. Integers are unsigned big-endian byte strings without
+// leading zeros; in JSON they are written as lowercase hex strings, in CBOR (RFC 8949) as byte
+// strings.
+//
+// secret-package:    uid, gid (optional), share { id, n, e, key_bytes_size, share }
+// public-package:    n, e, v, group_size, verification_keys [{ id, key }]
+// partial-signature: id, xi, z, c, commitments (optional) [v', x'],
+//                    proof_hash (optional text), challenge_bits (optional unsigned integer)
+//
+// The proof_hash is the name of the transcript hash, "sha256", "sha384", "sha512", "sha3-256",
+// "sha3-384", "sha3-512" or "blake3", see `ProofHash::name`, and challenge_bits the length of c.
+// Both are missing for a legacy proof, with c the SHA-256 of the concatenated values. The suite of a
+// partial signature names its proof hash, the packages carry the one of `ProofVersion::CURRENT`.
+//
+// Documents written with the serde derives can be read with `from_legacy_json`.
+
+use crate::transcript::{ProofHash, ProofVersion};
+use crate::{
+    public_key_from_components, PartialMessageSignature, PublicPackage, RsaSecretShare,
+    RsaVerificationKey, SecretPackage,
+};
+use num_bigint::BigUint;
+use rsa::traits::PublicKeyParts;
+use rsa::RsaPublicKey;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+use zeroize::Zeroize;
+
+pub const FORMAT_VERSION: u16 = 1;
+const SUITE_PREFIX: &str = "shoup00-rsa-";
+
+/// Shoup's protocol 1 with the hash of the proofs of correctness, "shoup00-rsa-sha256" for
+/// `ProofVersion::CURRENT` and the legacy proofs.
+pub fn suite(proof_version: &ProofVersion) -> String {
+    format!("{SUITE_PREFIX}{}", proof_version.hash().name())
+}
+
+fn suite_hash(suite: &str) -> Option<ProofHash> {
+    suite
+        .strip_prefix(SUITE_PREFIX)
+        .and_then(ProofHash::from_name)
+}
+
+#[derive(Error, Debug, Serialize, Deserialize)]
+pub enum FormatError {
+    #[error("The document cannot be parsed: {0}")]
+    Malformed(String),
+    #[error("Unsupported format version {0}")]
+    UnsupportedVersion(u16),
+    #[error("Unsupported suite {0}")]
+    UnsupportedSuite(String),
+    #[error("Expected a document of kind {expected}, found {found}")]
+    UnexpectedKind { expected: String, found: String },
+    #[error("The document cannot be written: {0}")]
+    Serialization(String),
+    #[error("The document does not describe a valid key")]
+    InvalidKey,
+}
+
+/// Unsigned big-endian integer, hex in human readable formats, bytes otherwise
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bytes(pub Vec<u8>);
+
+impl From<&BigUint> for Bytes {
+    fn from(value: &BigUint) -> Self {
+        let bytes = value.to_bytes_be();
+        // to_bytes_be gives [0] for zero
+        Bytes(if bytes == [0] { vec![] } else { bytes })
+    }
+}
+
+impl From<&Bytes> for BigUint {
+    fn from(value: &Bytes) -> Self {
+        BigUint::from_bytes_be(&value.0)
+    }
+}
+
+impl Serialize for Bytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            let hex: String = self.0.iter().map(|b| format!("{b:02x}")).collect();
+            serializer.serialize_str(&hex)
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Bytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+        if deserializer.is_human_readable() {
+            let hex = String::deserialize(deserializer)?;
+            if hex.len() % 2 != 0 || !hex.is_ascii() {
+                return Err(D::Error::custom("invalid hex string"));
+            }
+            (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                .collect::<Result<Vec<u8>, _>>()
+                .map(Bytes)
+                .map_err(D::Error::custom)
+        } else {
+            struct BytesVisitor;
+            impl<'de> serde::de::Visitor<'de> for BytesVisitor {
+                type Value = Bytes;
+                fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                    f.write_str("a byte string")
+                }
+                fn visit_bytes<E: Error>(self, v: &[u8]) -> Result<Bytes, E> {
+                    Ok(Bytes(v.to_vec()))
+                }
+                fn visit_byte_buf<E: Error>(self, v: Vec<u8>) -> Result<Bytes, E> {
+                    Ok(Bytes(v))
+                }
+            }
+            deserializer.deserialize_byte_buf(BytesVisitor)
+        }
+    }
+}
+
+/// The fields shared by all documents, checked before the rest is parsed
+#[derive(Debug, Deserialize)]
+struct Header {
+    version: u16,
+    suite: String,
+    kind: String,
+}
+
+impl Header {
+    fn check(&self, kind: &str) -> Result<(), FormatError> {
+        if self.version != FORMAT_VERSION {
+            return Err(FormatError::UnsupportedVersion(self.version));
+        }
+        if suite_hash(&self.suite).is_none() {
+            return Err(FormatError::UnsupportedSuite(self.suite.clone()));
+        }
+        if self.kind != kind {
+            return Err(FormatError::UnexpectedKind {
+                expected: kind.to_string(),
+                found: self.kind.clone(),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SecretShareV1 {
+    pub id: u64,
+    pub n: Bytes,
+    pub e: Bytes,
+    pub key_bytes_size: u64,
+    pub share: Bytes,
+}
+
+impl Drop for SecretShareV1 {
+    fn drop(&mut self) {
+        self.share.0.zeroize();
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SecretPackageV1 {
+    pub version: u16,
+    pub suite: String,
+    pub kind: String,
+    pub uid: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u64>,
+    pub share: SecretShareV1,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VerificationKeyV1 {
+    pub id: u64,
+    pub key: Bytes,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PublicPackageV1 {
+    pub version: u16,
+    pub suite: String,
+    pub kind: String,
+    pub n: Bytes,
+    pub e: Bytes,
+    pub v: Bytes,
+    pub group_size: u64,
+    pub verification_keys: Vec<VerificationKeyV1>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PartialMessageSignatureV1 {
+    pub version: u16,
+    pub suite: String,
+    pub kind: String,
+    pub id: u64,
+    pub xi: Bytes,
+    pub z: Bytes,
+    pub c: Bytes,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commitments: Option<(Bytes, Bytes)>,
+    /// Missing for the legacy proofs, as challenge_bits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proof_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub challenge_bits: Option<u64>,
+}
+
+/// Conversion to and from the versioned documents.
+pub trait Versioned: Sized {
+    const KIND: &'static str;
+    type Document: Serialize + DeserializeOwned;
+
+    fn to_document(&self) -> Self::Document;
+    fn from_document(document: Self::Document) -> Result<Self, FormatError>;
+
+    fn to_json(&self) -> Result<String, FormatError> {
+        serde_json::to_string(&self.to_document())
+            .map_err(|e| FormatError::Serialization(e.to_string()))
+    }
+
+    fn from_json(json: &str) -> Result<Self, FormatError> {
+        let header: Header =
+            serde_json::from_str(json).map_err(|e| FormatError::Malformed(e.to_string()))?;
+        header.check(Self::KIND)?;
+        Self::from_document(
+            serde_json::from_str(json).map_err(|e| FormatError::Malformed(e.to_string()))?,
+        )
+    }
+
+    fn to_cbor(&self) -> Result<Vec<u8>, FormatError> {
+        let mut cbor = Vec::new();
+        ciborium::ser::into_writer(&self.to_document(), &mut cbor)
+            .map_err(|e| FormatError::Serialization(e.to_string()))?;
+        Ok(cbor)
+    }
+
+    fn from_cbor(cbor: &[u8]) -> Result<Self, FormatError> {
+        let header: Header =
+            ciborium::de::from_reader(cbor).map_err(|e| FormatError::Malformed(e.to_string()))?;
+        header.check(Self::KIND)?;
+        Self::from_document(
+            ciborium::de::from_reader(cbor).map_err(|e| FormatError::Malformed(e.to_string()))?,
+        )
+    }
+
+    /// Read a document written with the serde derives of the struct.
+    fn from_legacy_json(json: &str) -> Result<Self, FormatError>
+    where
+        Self: DeserializeOwned,
+    {
+        serde_json::from_str(json).map_err(|e| FormatError::Malformed(e.to_string()))
+    }
+
+    /// Rewrite a document written with the serde derives into the versioned JSON.
+    fn migrate_legacy_json(json: &str) -> Result<String, FormatError>
+    where
+        Self: DeserializeOwned,
+    {
+        Self::from_legacy_json(json)?.to_json()
+    }
+}
+
+impl Versioned for SecretPackage {
+    const KIND: &'static str = "secret-package";
+    type Document = SecretPackageV1;
+
+    fn to_document(&self) -> SecretPackageV1 {
+        let share = &self.share;
+        SecretPackageV1 {
+            version: FORMAT_VERSION,
+            suite: suite(&ProofVersion::CURRENT),
+            kind: Self::KIND.to_string(),
+            uid: self.uid as u64,
+            gid: self.gid.map(|gid| gid as u64),
+            share: SecretShareV1 {
+                id: share.id as u64,
+                n: (&share.n).into(),
+                e: (&share.e).into(),
+                key_bytes_size: share.key_bytes_size as u64,
+                share: (&share.share).into(),
+            },
+        }
+    }
+
+    fn from_document(document: SecretPackageV1) -> Result<Self, FormatError> {
+        let share = &document.share;
+        Ok(SecretPackage {
+            uid: document.uid as usize,
+            gid: document.gid.map(|gid| gid as usize),
+            share: RsaSecretShare {
+                id: share.id as usize,
+                n: (&share.n).into(),
+                e: (&share.e).into(),
+                key_bytes_size: share.key_bytes_size as usize,
+                share: (&share.share).into(),
+            },
+        })
+    }
+}
+
+impl Versioned for PublicPackage {
+    const KIND: &'static str = "public-package";
+    type Document = PublicPackageV1;
+
+    fn to_document(&self) -> PublicPackageV1 {
+        PublicPackageV1 {
+            version: FORMAT_VERSION,
+            suite: suite(&ProofVersion::CURRENT),
+            kind: Self::KIND.to_string(),
+            n: self.public_key.n().into(),
+            e: self.public_key.e().into(),
+            v: (&self.v).into(),
+            group_size: self.group_size as u64,
+            verification_keys: self
+                .verification_keys
+                .iter()
+                .map(|vk| VerificationKeyV1 {
+                    id: vk.id as u64,
+                    key: (&vk.key).into(),
+                })
+                .collect(),
+        }
+    }
+
+    fn from_document(document: PublicPackageV1) -> Result<Self, FormatError> {
+        let public_key = public_key_from_components((&document.n).into(), (&document.e).into())
+            .map_err(|_| FormatError::InvalidKey)?;
+        Ok(PublicPackage {
+            public_key,
+            v: (&document.v).into(),
+            group_size: document.group_size as usize,
+            verification_keys: document
+                .verification_keys
+                .iter()
+                .map(|vk| RsaVerificationKey {
+                    id: vk.id as usize,
+                    key: (&vk.key).into(),
+                })
+                .collect(),
+        })
+    }
+}
+
+impl Versioned for PartialMessageSignature {
+    const KIND: &'static str = "partial-signature";
+    type Document = PartialMessageSignatureV1;
+
+    fn to_document(&self) -> PartialMessageSignatureV1 {
+        PartialMessageSignatureV1 {
+            version: FORMAT_VERSION,
+            suite: suite(&self.proof_version),
+            kind: Self::KIND.to_string(),
+            id: self.id as u64,
+            xi: (&self.xi).into(),
+            z: (&self.z).into(),
+            c: (&self.c).into(),
+            commitments: self
+                .commitments
+                .as_ref()
+                .map(|(v_prime, x_prime)| (v_prime.into(), x_prime.into())),
+            proof_hash: match self.proof_version {
+                ProofVersion::Legacy => None,
+                ProofVersion::Transcript { hash, .. } => Some(hash.name().to_string()),
+            },
+            challenge_bits: match self.proof_version {
+                ProofVersion::Legacy => None,
+                ProofVersion::Transcript { challenge_bits, .. } => Some(challenge_bits as u64),
+            },
+        }
+    }
+
+    fn from_document(document: PartialMessageSignatureV1) -> Result<Self, FormatError> {
+        let proof_version = match (&document.proof_hash, document.challenge_bits) {
+            (None, None) => ProofVersion::Legacy,
+            (Some(name), Some(challenge_bits)) => ProofVersion::Transcript {
+                hash: ProofHash::from_name(name)
+                    .ok_or_else(|| FormatError::Malformed(format!("unknown proof hash {name}")))?,
+                challenge_bits: challenge_bits as usize,
+            },
+            _ => {
+                return Err(FormatError::Malformed(
+                    "proof_hash and challenge_bits go together".into(),
+                ))
+            }
+        };
+        if document.suite != suite(&proof_version) {
+            return Err(FormatError::UnsupportedSuite(document.suite.clone()));
+        }
+        Ok(PartialMessageSignature {
+            id: document.id as usize,
+            xi: (&document.xi).into(),
+            z: (&document.z).into(),
+            c: (&document.c).into(),
+            commitments: document
+                .commitments
+                .as_ref()
+                .map(|(v_prime, x_prime)| (v_prime.into(), x_prime.into())),
+            proof_version,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deal, load_key, PaddingScheme};
+
+    #[test]
+    fn that_packages_roundtrip_in_json_and_cbor() {
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 3, 2);
+        let secret_pkg = &secret_pkgs[1];
+        let public_pkg = &public_pkgs[0];
+
+        let json = secret_pkg.to_json().unwrap();
+        assert_eq!(&SecretPackage::from_json(&json).unwrap(), secret_pkg);
+        let cbor = secret_pkg.to_cbor().unwrap();
+        assert_eq!(&SecretPackage::from_cbor(&cbor).unwrap(), secret_pkg);
+
+        let json = public_pkg.to_json().unwrap();
+        assert!(json.contains(&format!("\"n\":\"{:x}\"", public_pkg.public_key.n())));
+        assert_eq!(&PublicPackage::from_json(&json).unwrap(), public_pkg);
+        let cbor = public_pkg.to_cbor().unwrap();
+        assert_eq!(&PublicPackage::from_cbor(&cbor).unwrap(), public_pkg);
+
+        let pms = secret_pkg
+            .sign(
+                b"hello",
+                3,
+                public_pkg.v.clone(),
+                &public_pkg.verification_keys[1],
+                PaddingScheme::PKCS1v15,
+            )
+            .unwrap();
+        let decoded = PartialMessageSignature::from_cbor(&pms.to_cbor().unwrap()).unwrap();
+        assert_eq!(
+            (
+                &decoded.xi,
+                &decoded.z,
+                &decoded.c,
+                &decoded.commitments,
+                &decoded.proof_version
+            ),
+            (
+                &pms.xi,
+                &pms.z,
+                &pms.c,
+                &pms.commitments,
+                &pms.proof_version
+            )
+        );
+    }
+
+    #[test]
+    fn that_proof_version_is_written_explicitly() {
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 2, 2);
+        let public_pkg = &public_pkgs[0];
+        let mut pms = secret_pkgs[0]
+            .sign(
+                b"hello",
+                2,
+                public_pkg.v.clone(),
+                &public_pkg.verification_keys[0],
+                PaddingScheme::PKCS1v15,
+            )
+            .unwrap();
+        let json = pms.to_json().unwrap();
+        assert!(json.contains("\"suite\":\"shoup00-rsa-sha256\""));
+        assert!(json.contains("\"proof_hash\":\"sha256\",\"challenge_bits\":256"));
+
+        for version in [
+            ProofVersion::with_hash(ProofHash::Sha3_384),
+            ProofVersion::Legacy,
+        ] {
+            pms.proof_version = version;
+            let json = pms.to_json().unwrap();
+            assert!(json.contains(&suite(&version)));
+            let decoded = PartialMessageSignature::from_json(&json).unwrap();
+            assert_eq!(decoded.proof_version, version);
+        }
+        // the suite names the hash of the proof
+        pms.proof_version = ProofVersion::with_hash(ProofHash::Sha512);
+        let json = pms.to_json().unwrap();
+        assert!(json.contains("shoup00-rsa-sha512"));
+        assert!(matches!(
+            PartialMessageSignature::from_json(
+                &json.replace("shoup00-rsa-sha512", "shoup00-rsa-sha256")
+            ),
+            Err(FormatError::UnsupportedSuite(_))
+        ));
+        pms.proof_version = ProofVersion::Legacy;
+        // a legacy proof has neither field
+        let json = pms.to_json().unwrap();
+        assert!(!json.contains("proof_hash") && !json.contains("challenge_bits"));
+
+        let written = |hash: &str, fields: &str| {
+            PartialMessageSignature::from_json(
+                &json
+                    .replace("shoup00-rsa-sha256", &format!("shoup00-rsa-{hash}"))
+                    .replacen("\"kind\"", &format!("{fields}\"kind\""), 1),
+            )
+        };
+        assert_eq!(
+            written(
+                "blake3",
+                "\"proof_hash\":\"blake3\",\"challenge_bits\":512,"
+            )
+            .unwrap()
+            .proof_version,
+            ProofVersion::Transcript {
+                hash: ProofHash::Blake3,
+                challenge_bits: 512
+            }
+        );
+        assert!(matches!(
+            written("sha256", "\"proof_hash\":\"md5\",\"challenge_bits\":128,"),
+            Err(FormatError::Malformed(_))
+        ));
+        assert!(matches!(
+            written("sha256", "\"proof_hash\":\"sha256\","),
+            Err(FormatError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn that_header_is_checked() {
+        let (secret_pkgs, _) = deal(&load_key().unwrap(), 2, 2);
+        let json = secret_pkgs[0].to_json().unwrap();
+
+        assert!(matches!(
+            PublicPackage::from_json(&json),
+            Err(FormatError::UnexpectedKind { .. })
+        ));
+        assert!(matches!(
+            SecretPackage::from_json(&json.replace("\"version\":1", "\"version\":2")),
+            Err(FormatError::UnsupportedVersion(2))
+        ));
+        assert!(matches!(
+            SecretPackage::from_json(&json.replace("shoup00-rsa-sha256", "shoup00-rsa-sha1")),
+            Err(FormatError::UnsupportedSuite(_))
+        ));
+    }
+
+    #[test]
+    fn that_legacy_json_is_migrated() {
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 2, 2);
+        let legacy = serde_json::to_string(&public_pkgs[0]).unwrap();
+        let migrated = PublicPackage::migrate_legacy_json(&legacy).unwrap();
+        assert_eq!(PublicPackage::from_json(&migrated).unwrap(), public_pkgs[0]);
+
+        let legacy = serde_json::to_string(&secret_pkgs[0]).unwrap();
+        assert_eq!(
+            SecretPackage::from_legacy_json(&legacy).unwrap(),
+            secret_pkgs[0]
+        );
+    }
+}
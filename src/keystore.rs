@@ -0,0 +1,388 @@
+// Storage of the secret and public packages by key id.
+//
+// `FileKeyStore` keeps every key in its own directory under the root, the packages are written in
+// the versioned CBOR format, optionally with the secret package encrypted under a password:
+//
+//   <root>/<key id>/public.cbor
+//   <root>/<key id>/secret.cbor   or   <root>/<key id>/secret.enc
+//
+// `MemoryKeyStore` is meant for tests and short lived processes.
+
+use crate::format::{FormatError, Versioned};
+use crate::storage::{KdfParams, StorageError};
+use crate::{PublicPackage, SecretPackage};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+const MAX_KEY_ID_LENGTH: usize = 128;
+const PUBLIC_FILE: &str = "public.cbor";
+const SECRET_FILE: &str = "secret.cbor";
+const ENCRYPTED_SECRET_FILE: &str = "secret.enc";
+
+#[derive(Error, Debug, Serialize, Deserialize)]
+pub enum KeyStoreError {
+    #[error("Invalid key id {0:?}, only ASCII letters, digits, '.', '_' and '-' are allowed")]
+    InvalidKeyId(String),
+    #[error("The key store cannot be accessed: {0}")]
+    Io(String),
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error(transparent)]
+    Format(#[from] FormatError),
+}
+
+impl From<std::io::Error> for KeyStoreError {
+    fn from(e: std::io::Error) -> Self {
+        KeyStoreError::Io(e.to_string())
+    }
+}
+
+/// Key ids are used as file names, so they are restricted to a safe subset of ASCII.
+pub fn check_key_id(key_id: &str) -> Result<(), KeyStoreError> {
+    let valid = !key_id.is_empty()
+        && key_id.len() <= MAX_KEY_ID_LENGTH
+        && !key_id.starts_with('.')
+        && key_id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"._-".contains(&b));
+    if !valid {
+        return Err(KeyStoreError::InvalidKeyId(key_id.to_string()));
+    }
+    Ok(())
+}
+
+pub trait KeyStore: Send + Sync {
+    /// Store the secret package, replacing the previous one.
+    fn put_secret(&self, key_id: &str, package: &SecretPackage) -> Result<(), KeyStoreError>;
+    fn get_secret(&self, key_id: &str) -> Result<Option<SecretPackage>, KeyStoreError>;
+    /// Store the public package, replacing the previous one.
+    fn put_public(&self, key_id: &str, package: &PublicPackage) -> Result<(), KeyStoreError>;
+    fn get_public(&self, key_id: &str) -> Result<Option<PublicPackage>, KeyStoreError>;
+    /// Ids of the keys with at least one package, sorted.
+    fn list(&self) -> Result<Vec<String>, KeyStoreError>;
+    /// Remove both packages of the key, returns whether there was anything to remove.
+    fn delete(&self, key_id: &str) -> Result<bool, KeyStoreError>;
+}
+
+#[derive(Default)]
+struct MemoryEntry {
+    secret: Option<SecretPackage>,
+    public: Option<PublicPackage>,
+}
+
+#[derive(Default)]
+pub struct MemoryKeyStore {
+    entries: Mutex<BTreeMap<String, MemoryEntry>>,
+}
+
+impl MemoryKeyStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn with_entries<T>(&self, f: impl FnOnce(&mut BTreeMap<String, MemoryEntry>) -> T) -> T {
+        let mut entries = self
+            .entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        f(&mut entries)
+    }
+}
+
+impl KeyStore for MemoryKeyStore {
+    fn put_secret(&self, key_id: &str, package: &SecretPackage) -> Result<(), KeyStoreError> {
+        check_key_id(key_id)?;
+        self.with_entries(|entries| {
+            entries.entry(key_id.to_string()).or_default().secret = Some(package.clone())
+        });
+        Ok(())
+    }
+
+    fn get_secret(&self, key_id: &str) -> Result<Option<SecretPackage>, KeyStoreError> {
+        check_key_id(key_id)?;
+        Ok(self.with_entries(|entries| entries.get(key_id).and_then(|e| e.secret.clone())))
+    }
+
+    fn put_public(&self, key_id: &str, package: &PublicPackage) -> Result<(), KeyStoreError> {
+        check_key_id(key_id)?;
+        self.with_entries(|entries| {
+            entries.entry(key_id.to_string()).or_default().public = Some(package.clone())
+        });
+        Ok(())
+    }
+
+    fn get_public(&self, key_id: &str) -> Result<Option<PublicPackage>, KeyStoreError> {
+        check_key_id(key_id)?;
+        Ok(self.with_entries(|entries| entries.get(key_id).and_then(|e| e.public.clone())))
+    }
+
+    fn list(&self) -> Result<Vec<String>, KeyStoreError> {
+        Ok(self.with_entries(|entries| entries.keys().cloned().collect()))
+    }
+
+    fn delete(&self, key_id: &str) -> Result<bool, KeyStoreError> {
+        check_key_id(key_id)?;
+        Ok(self.with_entries(|entries| entries.remove(key_id).is_some()))
+    }
+}
+
+/// Filesystem backend, the files are replaced atomically and on Unix they are readable only by
+/// the owner.
+pub struct FileKeyStore {
+    root: PathBuf,
+    password: Option<(Zeroizing<Vec<u8>>, KdfParams)>,
+}
+
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+impl FileKeyStore {
+    /// The secret packages are stored unencrypted, the protection is left to the filesystem.
+    pub fn new<P: Into<PathBuf>>(root: P) -> Result<Self, KeyStoreError> {
+        let root = root.into();
+        create_private_dir(&root)?;
+        Ok(FileKeyStore {
+            root,
+            password: None,
+        })
+    }
+
+    /// The secret packages are encrypted under the password, see `storage`.
+    pub fn with_password<P: Into<PathBuf>>(
+        root: P,
+        password: &[u8],
+        params: KdfParams,
+    ) -> Result<Self, KeyStoreError> {
+        let mut store = Self::new(root)?;
+        store.password = Some((Zeroizing::new(password.to_vec()), params));
+        Ok(store)
+    }
+
+    fn key_dir(&self, key_id: &str) -> Result<PathBuf, KeyStoreError> {
+        check_key_id(key_id)?;
+        Ok(self.root.join(key_id))
+    }
+
+    fn secret_file(&self) -> &'static str {
+        match self.password {
+            Some(_) => ENCRYPTED_SECRET_FILE,
+            None => SECRET_FILE,
+        }
+    }
+
+    fn read(&self, key_id: &str, file: &str) -> Result<Option<Zeroizing<Vec<u8>>>, KeyStoreError> {
+        match fs::read(self.key_dir(key_id)?.join(file)) {
+            Ok(data) => Ok(Some(Zeroizing::new(data))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write(&self, key_id: &str, file: &str, data: &[u8]) -> Result<(), KeyStoreError> {
+        let dir = self.key_dir(key_id)?;
+        create_private_dir(&dir)?;
+        write_atomically(&dir, file, data)
+    }
+}
+
+fn create_private_dir(dir: &Path) -> Result<(), KeyStoreError> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(dir)?;
+    Ok(())
+}
+
+/// Write a temporary file next to the target, sync it and rename it over the target.
+fn write_atomically(dir: &Path, file: &str, data: &[u8]) -> Result<(), KeyStoreError> {
+    let tmp_path = dir.join(format!(
+        ".{file}.{}.{}.tmp",
+        std::process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let result = options.open(&tmp_path).and_then(|mut handle| {
+        handle.write_all(data)?;
+        handle.sync_all()?;
+        fs::rename(&tmp_path, dir.join(file))
+    });
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e.into());
+    }
+    // Persist the rename itself, directories cannot be opened for syncing on Windows
+    #[cfg(unix)]
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+impl KeyStore for FileKeyStore {
+    fn put_secret(&self, key_id: &str, package: &SecretPackage) -> Result<(), KeyStoreError> {
+        let data = Zeroizing::new(match &self.password {
+            Some((password, params)) => package.export_encrypted(password, *params)?,
+            None => package.to_cbor()?,
+        });
+        self.write(key_id, self.secret_file(), &data)
+    }
+
+    fn get_secret(&self, key_id: &str) -> Result<Option<SecretPackage>, KeyStoreError> {
+        let Some(data) = self.read(key_id, self.secret_file())? else {
+            return Ok(None);
+        };
+        Ok(Some(match &self.password {
+            Some((password, _)) => SecretPackage::import_encrypted(&data, password)?,
+            None => SecretPackage::from_cbor(&data)?,
+        }))
+    }
+
+    fn put_public(&self, key_id: &str, package: &PublicPackage) -> Result<(), KeyStoreError> {
+        self.write(key_id, PUBLIC_FILE, &package.to_cbor()?)
+    }
+
+    fn get_public(&self, key_id: &str) -> Result<Option<PublicPackage>, KeyStoreError> {
+        let Some(data) = self.read(key_id, PUBLIC_FILE)? else {
+            return Ok(None);
+        };
+        Ok(Some(PublicPackage::from_cbor(&data)?))
+    }
+
+    fn list(&self) -> Result<Vec<String>, KeyStoreError> {
+        let mut key_ids = Vec::new();
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            let Some(key_id) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if check_key_id(&key_id).is_err() || !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let dir = entry.path();
+            if [PUBLIC_FILE, SECRET_FILE, ENCRYPTED_SECRET_FILE]
+                .iter()
+                .any(|file| dir.join(file).exists())
+            {
+                key_ids.push(key_id);
+            }
+        }
+        key_ids.sort();
+        Ok(key_ids)
+    }
+
+    fn delete(&self, key_id: &str) -> Result<bool, KeyStoreError> {
+        let dir = self.key_dir(key_id)?;
+        let mut removed = false;
+        for file in [PUBLIC_FILE, SECRET_FILE, ENCRYPTED_SECRET_FILE] {
+            match fs::remove_file(dir.join(file)) {
+                Ok(()) => removed = true,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        // Leaves the directory in place if something else was put there
+        let _ = fs::remove_dir(&dir);
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deal, load_key};
+
+    fn exercise(store: &dyn KeyStore) {
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 2, 2);
+
+        assert_eq!(store.get_secret("tenant-1").unwrap(), None);
+        store.put_secret("tenant-1", &secret_pkgs[0]).unwrap();
+        store.put_public("tenant-1", &public_pkgs[0]).unwrap();
+        store.put_public("tenant-0", &public_pkgs[1]).unwrap();
+        assert_eq!(
+            store.get_secret("tenant-1").unwrap().as_ref(),
+            Some(&secret_pkgs[0])
+        );
+        assert_eq!(
+            store.get_public("tenant-1").unwrap().as_ref(),
+            Some(&public_pkgs[0])
+        );
+        store.put_secret("tenant-1", &secret_pkgs[1]).unwrap();
+        assert_eq!(
+            store.get_secret("tenant-1").unwrap().as_ref(),
+            Some(&secret_pkgs[1])
+        );
+        assert_eq!(store.list().unwrap(), ["tenant-0", "tenant-1"]);
+
+        assert!(store.delete("tenant-1").unwrap());
+        assert!(!store.delete("tenant-1").unwrap());
+        assert_eq!(store.get_secret("tenant-1").unwrap(), None);
+        assert_eq!(store.list().unwrap(), ["tenant-0"]);
+
+        for key_id in ["", "../escape", ".hidden", "a/b"] {
+            assert!(matches!(
+                store.get_public(key_id),
+                Err(KeyStoreError::InvalidKeyId(_))
+            ));
+        }
+    }
+
+    fn temp_root(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("pretzel-keystore-{name}-{}", std::process::id()))
+    }
+
+    #[test]
+    fn that_memory_key_store_stores_packages() {
+        exercise(&MemoryKeyStore::new());
+    }
+
+    #[test]
+    fn that_file_key_store_stores_packages() {
+        let root = temp_root("plain");
+        exercise(&FileKeyStore::new(&root).unwrap());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn that_file_key_store_encrypts_secret_packages() {
+        let root = temp_root("encrypted");
+        let params = KdfParams {
+            m_cost: 64,
+            t_cost: 1,
+            p_cost: 1,
+        };
+        let store = FileKeyStore::with_password(&root, b"pw", params).unwrap();
+        exercise(&store);
+
+        let (secret_pkgs, _) = deal(&load_key().unwrap(), 2, 2);
+        store.put_secret("tenant-2", &secret_pkgs[0]).unwrap();
+        let path = root.join("tenant-2").join(ENCRYPTED_SECRET_FILE);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(
+                fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+                0o600
+            );
+        }
+        let wrong = FileKeyStore::with_password(&root, b"other", params).unwrap();
+        assert!(matches!(
+            wrong.get_secret("tenant-2"),
+            Err(KeyStoreError::Storage(StorageError::Decryption))
+        ));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
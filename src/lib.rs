@@ -15,10 +15,11 @@ use thiserror::Error;
 // use std::error::Error
 // use num_modular::*;
 use crypto_bigint::*;
+use crypto_primes::hazmat::Sieve;
 use crypto_primes::*;
 // use num_prime::nt_funcs::*;
 use num_bigint::algorithms::extended_gcd;
-use num_integer::Integer;
+use num_integer::{Integer, Roots};
 use num_traits::{CheckedSub, One, Pow, Zero};
 use rand::prelude::*;
 use rand_chacha::{rand_core::SeedableRng, ChaCha20Rng, ChaCha8Rng};
@@ -42,6 +43,35 @@ use std::io::{Read, Write};
 use std::ops::{Add, Div, Mul, MulAssign, Neg, Shr, Sub};
 use std::str::FromStr;
 
+use session::SigningContext;
+use transcript::{ProofPolicy, ProofVersion, Transcript};
+
+pub mod batch;
+pub mod blind;
+pub mod context;
+pub mod decryption;
+#[cfg(feature = "async")]
+pub mod driver;
+pub mod encoding;
+pub mod format;
+pub mod keystore;
+pub mod optimistic;
+mod padding;
+pub mod pool;
+pub mod prehash;
+pub mod pvss;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod session;
+pub mod share_bundle;
+#[cfg(feature = "cms")]
+pub mod signed_data;
+pub mod signer;
+pub mod storage;
+pub mod transcript;
+#[cfg(feature = "x509")]
+pub mod x509;
+
 // FIXME reexport the RSA customized module?
 
 // FIXME Check that the geneated values/shares etc. are not ones or zeroes for example?
@@ -61,7 +91,16 @@ pub fn generate_with_dealer(
     key_bit_length: usize,
 ) -> Result<(Vec<SecretPackage>, Vec<PublicPackage>), KeyGenError> {
     let private_key = key_gen(key_bit_length, max_signers as usize, min_signers as usize)?;
-    let shares = generate_secret_shares(&private_key, max_signers as usize, min_signers as usize);
+    Ok(deal(&private_key, max_signers, min_signers))
+}
+
+/// Split an existing key into the packages handed out by the dealer.
+pub(crate) fn deal(
+    private_key: &RSAThresholdPrivateKey,
+    max_signers: u16,
+    min_signers: u16,
+) -> (Vec<SecretPackage>, Vec<PublicPackage>) {
+    let shares = generate_secret_shares(private_key, max_signers as usize, min_signers as usize);
     // pub fn generate_verification(
     let secret_pkgs = shares
         .par_iter()
@@ -73,8 +112,8 @@ pub fn generate_with_dealer(
         })
         .collect();
 
-    let public_key = RsaPublicKey::from(&private_key);
-    let (v, vkeys) = generate_verification(&RSAThresholdPublicKey::from(&private_key), shares);
+    let public_key = RsaPublicKey::from(private_key);
+    let (v, vkeys) = generate_verification(&RSAThresholdPublicKey::from(private_key), shares);
     let public_pkg = PublicPackage {
         v: v,
         verification_keys: vkeys,
@@ -82,7 +121,7 @@ pub fn generate_with_dealer(
         group_size: max_signers as usize,
     };
 
-    Ok((secret_pkgs, vec![public_pkg; max_signers as usize]))
+    (secret_pkgs, vec![public_pkg; max_signers as usize])
 }
 
 // PublicPackage: HashMap of PartialSignature VerificationKeys, VerificationKey
@@ -179,7 +218,7 @@ pub struct RsaVerificationKey {
     key: BigUint,
 }
 
-// Should PartialMessageSignature be split to the share and the verification proof?
+/// The signature share x_i with its proof of correctness, see `optimistic` for using them apart.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PartialMessageSignature {
     pub id: usize,
@@ -187,6 +226,13 @@ pub struct PartialMessageSignature {
     pub z: BigUint,
     pub c: BigUint,
     // key: RSAThresholdPublicKey,
+    /// The commitments v^r and x~^r of the proof, they are not needed by `verify_proof`, but
+    /// allow verifying many proofs at once with `batch::verify_proofs_batch`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commitments: Option<(BigUint, BigUint)>,
+    /// How c was derived, the proofs serialized without it are legacy ones.
+    #[serde(default = "ProofVersion::legacy")]
+    pub proof_version: ProofVersion,
 }
 
 // TODO move the errors to another file?
@@ -202,8 +248,14 @@ pub enum KeyGenError {
     NoInverse,
     #[error("The group is too big")]
     GroupTooBig,
+    #[error("The threshold must be between 1 and the group size")]
+    InvalidThreshold,
     #[error("Bit length does not match")]
     BitLength,
+    #[error("No primes can be taken from the pool")]
+    PoolUnavailable,
+    #[error("Keys shorter than 2048 bits are refused in the strict mode")]
+    Insecure,
 }
 
 #[derive(Error, Debug)]
@@ -259,11 +311,18 @@ impl From<RSAThresholdPrivateKey> for RsaPublicKey {
 
 impl From<&RSAThresholdPrivateKey> for RsaPublicKey {
     fn from(private_key: &RSAThresholdPrivateKey) -> Self {
-        let rsa_key: RsaPrivateKey = private_key.into();
-        RsaPublicKey::from(&rsa_key)
+        public_key_from_components(&private_key.p * &private_key.q, private_key.e.clone()).unwrap()
     }
 }
 
+/// `RsaPublicKey::new` caps the modulus at 4096 bits, below the keys of `SecurityLevel::Rsa7680`.
+pub(crate) fn public_key_from_components(
+    n: BigUint,
+    e: BigUint,
+) -> rsa::errors::Result<RsaPublicKey> {
+    RsaPublicKey::new_with_max_size(n, e, MAX_BIT_LENGTH)
+}
+
 fn print_type_of<T>(_: &T) {
     eprintln!("{:?}", std::any::type_name::<T>())
 }
@@ -275,11 +334,28 @@ pub fn key_gen(
     k: usize,
     // t: usize,
 ) -> Result<RSAThresholdPrivateKey, KeyGenError> {
-    // FIXME add bounds on l, k and t
+    check_threshold(l, k)?;
     let (p, q) = match generate_p_and_q(bit_length) {
         Ok((p, q)) => (p, q),
         Err(e) => return Err(e),
     };
+    key_from_primes(p, q, l)
+}
+
+/// 1 <= k <= l, checked before the primes are generated.
+pub(crate) fn check_threshold(l: usize, k: usize) -> Result<(), KeyGenError> {
+    if k == 0 || k > l {
+        return Err(KeyGenError::InvalidThreshold);
+    }
+    Ok(())
+}
+
+/// The rest of `key_gen` once the safe primes p and q are known.
+pub(crate) fn key_from_primes(
+    p: BigUint,
+    q: BigUint,
+    l: usize,
+) -> Result<RSAThresholdPrivateKey, KeyGenError> {
     let e: BigUint = BigUint::from(0x10001 as u32); // 65537
 
     // FIXME: compare against e directly
@@ -350,7 +426,8 @@ fn digest_msg(
     // let inner = pkcs1v15_sign_pad(&[], &msg, key_bytes_size).unwrap();
     // assert_eq!((BigUint::from_bytes_be( &inner).to_bytes_be()), inner);
     match scheme {
-        PaddingScheme::NONE => todo!(), //hashed,
+        // raw RSA, the message is expected to be already encoded and smaller than the modulus
+        PaddingScheme::NONE => BigUint::from_bytes_be(msg),
         PaddingScheme::_PSS => unimplemented!(),
         PaddingScheme::PKCS1v15 => {
             // let prefix = pkcs1v15_generate_prefix::<Sha256>();
@@ -449,6 +526,58 @@ pub fn sign_with_share(
     // let x = BigUint::from_bytes_be( &msg_digest).mod_floor(&key.n);
     // eprintln!("x = {:?}", x);
     // let xi = BigUint::from_bytes_be(msg_digest);
+    sign_raw_with_share(&x, delta, share, v, vi)
+}
+
+/// Raise an already encoded `x` to the share and prove the correctness of the result.
+///
+/// This is the part of `sign_with_share` that does not depend on the padding, hence it serves
+/// for signing as well as for decryption.
+pub fn sign_raw_with_share(
+    x: &BigUint,
+    delta: usize,
+    share: &RsaSecretShare,
+    v: &BigUint,
+    vi: &RsaVerificationKey,
+) -> PartialMessageSignature {
+    prove_raw_share(x, delta, share, v, vi, None, ProofVersion::CURRENT)
+}
+
+/// `sign_with_share` with a proof of the given version, e.g. with a longer challenge.
+pub fn sign_with_share_versioned(
+    msg: &[u8],
+    delta: usize,
+    share: &RsaSecretShare,
+    v: &BigUint,
+    vi: &RsaVerificationKey,
+    scheme: PaddingScheme,
+    proof_version: ProofVersion,
+) -> Result<PartialMessageSignature, SigningError> {
+    if !proof_version.is_supported() {
+        return Err(SigningError::SigningError);
+    }
+    let x = digest_msg(msg, scheme, &share.n, share.key_bytes_size);
+    Ok(prove_raw_share(
+        &x,
+        delta,
+        share,
+        v,
+        vi,
+        None,
+        proof_version,
+    ))
+}
+
+/// `sign_raw_with_share` with the challenge bound to the optional signing context.
+pub(crate) fn prove_raw_share(
+    x: &BigUint,
+    delta: usize,
+    share: &RsaSecretShare,
+    v: &BigUint,
+    vi: &RsaVerificationKey,
+    context: Option<&SigningContext>,
+    proof_version: ProofVersion,
+) -> PartialMessageSignature {
     let mut exponent = BigUint::from(2u8);
     exponent.mul_assign(BigUint::from(delta));
     exponent.mul_assign(share.share.clone());
@@ -460,7 +589,7 @@ pub fn sign_with_share(
 
     // calculate the proof of correctness
     let n_bits = share.n.bits();
-    let hash_length = 256;
+    let hash_length = proof_version.challenge_bits();
     let two = BigUint::from(2u8);
 
     // NOTE: not using checked_sub, because it is unlikely to underflow
@@ -471,17 +600,16 @@ pub fn sign_with_share(
     // FIXME the next exponentiation should not be modulo
     let v_prime = v.modpow(&r, &share.n);
     let x_prime = x_tilde.modpow(&r, &share.n);
-    // c =  hash(v, x_tilde, vi, xi^2, v^r, x^r)
-    // FIXME omitting the sign could be of an issue
-    let mut commit = v.to_bytes_be();
-    commit.extend(x_tilde.to_bytes_be());
-    // FIXME don't just use the key but provide some way of hashing?
-    commit.extend(vi.key.to_bytes_be());
-    commit.extend(xi_squared.to_bytes_be());
-    commit.extend(v_prime.to_bytes_be());
-    commit.extend(x_prime.to_bytes_be());
-
-    let c = BigUint::from_bytes_be(&Sha256::digest(commit));
+    let c = proof_challenge(
+        proof_version,
+        context,
+        v,
+        &x_tilde,
+        vi,
+        &xi_squared,
+        &v_prime,
+        &x_prime,
+    );
     let z = (share.share.clone().mul(c.clone())).add(r.clone());
 
     PartialMessageSignature {
@@ -489,7 +617,65 @@ pub fn sign_with_share(
         xi: xi,
         z: z,
         c: c,
+        commitments: Some((v_prime, x_prime)),
+        proof_version,
+    }
+}
+
+/// c =  hash(context, v, x_tilde, vi, xi^2, v^r, x^r)
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn proof_challenge(
+    proof_version: ProofVersion,
+    context: Option<&SigningContext>,
+    v: &BigUint,
+    x_tilde: &BigUint,
+    vi: &RsaVerificationKey,
+    xi_squared: &BigUint,
+    v_prime: &BigUint,
+    x_prime: &BigUint,
+) -> BigUint {
+    let ProofVersion::Transcript {
+        hash,
+        challenge_bits,
+    } = proof_version
+    else {
+        return legacy_proof_challenge(context, v, x_tilde, vi, xi_squared, v_prime, x_prime);
+    };
+    let mut transcript = Transcript::with_hash(b"pretzel-shoup-proof", hash);
+    if let Some(context) = context {
+        transcript.append_message(b"context", &context.encode());
     }
+    transcript.append_biguint(b"v", v);
+    transcript.append_biguint(b"x~", x_tilde);
+    transcript.append_u64(b"id", vi.id as u64);
+    transcript.append_biguint(b"vi", &vi.key);
+    transcript.append_biguint(b"xi^2", xi_squared);
+    transcript.append_biguint(b"v^r", v_prime);
+    transcript.append_biguint(b"x~^r", x_prime);
+    transcript.challenge(b"c", challenge_bits)
+}
+
+/// The plain concatenation, kept for verifying the proofs made before the transcripts
+fn legacy_proof_challenge(
+    context: Option<&SigningContext>,
+    v: &BigUint,
+    x_tilde: &BigUint,
+    vi: &RsaVerificationKey,
+    xi_squared: &BigUint,
+    v_prime: &BigUint,
+    x_prime: &BigUint,
+) -> BigUint {
+    // The encoding of the context has a fixed length
+    let mut commit = context.map(SigningContext::encode).unwrap_or_default();
+    // FIXME omitting the sign could be of an issue
+    commit.extend(v.to_bytes_be());
+    commit.extend(x_tilde.to_bytes_be());
+    // FIXME don't just use the key but provide some way of hashing?
+    commit.extend(vi.key.to_bytes_be());
+    commit.extend(xi_squared.to_bytes_be());
+    commit.extend(v_prime.to_bytes_be());
+    commit.extend(x_prime.to_bytes_be());
+    BigUint::from_bytes_be(&Sha256::digest(commit))
 }
 
 fn lambda(delta: usize, i: usize, j: usize, l: usize, subset: Vec<usize>) -> BigInt {
@@ -543,49 +729,145 @@ pub fn factorial(value: usize) -> usize {
     }
 }
 
-// Based on this API the `bit_length` should not be divided, but instead
-// the division shouldbe handled by the key gen caller
+/// p gets the extra bit of an odd `bit_length`, both are at least \sqrt{2} 2^{h - 1}, so n = pq
+/// has exactly `bit_length` bits.
 fn generate_p_and_q(bit_length: usize) -> Result<(BigUint, BigUint), KeyGenError> {
-    let min_bit_length = 3;
-    let max_bit_length = 16384;
-    let half_bit_length = bit_length / 2;
+    check_bit_length(bit_length)?;
+    let p_bit_length = bit_length.div_ceil(2);
+    let q_bit_length = bit_length / 2;
+
+    // Generate two distinct safe probably primes, each on its own thread
+    info!("Generating p and q primes..");
+    let (p, mut q) = rayon::join(
+        || generate_large_safe_prime(p_bit_length),
+        || generate_large_safe_prime(q_bit_length),
+    );
+    while p == q {
+        info!("p == q, recalculating q");
+        q = generate_large_safe_prime(q_bit_length);
+    }
+
+    if p.bits() != p_bit_length || q.bits() != q_bit_length || (&p * &q).bits() != bit_length {
+        return Err(KeyGenError::BitLength);
+    }
 
-    if half_bit_length < min_bit_length {
-        // Trying to prevent the following panic!
-        // https://docs.rs/crypto-primes/latest/src/crypto_primes/presets.rs.html#85
+    Ok((p, q))
+}
+
+/// A safe prime p >= \sqrt{2} 2^{bit_length - 1}, i.e. p^2 has `2 * bit_length` bits. The product
+/// of two such primes never falls short of the sum of their bit lengths.
+pub(crate) fn generate_large_safe_prime(bit_length: usize) -> BigUint {
+    generate_safe_prime_sized(bit_length)
+}
+
+/// Maximal bit length of the modulus.
+pub const MAX_BIT_LENGTH: usize = 16384;
+
+/// Minimal bit length of the modulus in the strict mode.
+pub const STRICT_MIN_BIT_LENGTH: usize = 2048;
+
+/// The strict mode is on, unless running the tests or built with the `insecure-small-keys`
+/// feature.
+pub const fn is_strict_mode() -> bool {
+    !cfg!(any(test, feature = "insecure-small-keys"))
+}
+
+pub(crate) fn check_bit_length(bit_length: usize) -> Result<(), KeyGenError> {
+    // Trying to prevent the following panic!
+    // https://docs.rs/crypto-primes/latest/src/crypto_primes/presets.rs.html#85
+    let min_bit_length = 6;
+    let max_bit_length = MAX_BIT_LENGTH;
+
+    if is_strict_mode() && bit_length < STRICT_MIN_BIT_LENGTH {
+        return Err(KeyGenError::Insecure);
+    }
+    if bit_length < min_bit_length {
         return Err(KeyGenError::TooSmall);
     }
-    if half_bit_length > max_bit_length {
+    if bit_length > max_bit_length {
         return Err(KeyGenError::TooBig);
     }
+    Ok(())
+}
 
-    // Use the largest available types for the initial primes generates
-    // Generate two distinct safe probably primes
-    info!("Generating p prime..");
-    // FIXME From experimenting it seems that larger values mean much slower generation times
-    // So ideally we would pick the U type based on the half_bit_length
-    // E.g. U2048 vs U16384
-    let crypto_p: U2048 = generate_safe_prime(Some(half_bit_length));
-    info!("Generating q prime..");
-    let mut crypto_q: U2048 = generate_safe_prime(Some(half_bit_length));
-    while crypto_p == crypto_q {
-        info!("p == q, recalculating q");
-        crypto_q = generate_safe_prime(Some(half_bit_length));
+/// Modulus sizes recommended for the given security strength (NIST SP 800-57).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SecurityLevel {
+    /// 112-bit security
+    Rsa2048,
+    /// 128-bit security
+    Rsa3072,
+    /// Between 128-bit and 192-bit security
+    Rsa4096,
+    /// 192-bit security
+    Rsa7680,
+}
+
+impl SecurityLevel {
+    pub fn bit_length(&self) -> usize {
+        match self {
+            Self::Rsa2048 => 2048,
+            Self::Rsa3072 => 3072,
+            Self::Rsa4096 => 4096,
+            Self::Rsa7680 => 7680,
+        }
     }
+}
 
-    // FIXME: I am a bit unsure about the converting between crypto-bigint and num-bigint
-    let p = BigUint::from_bytes_be(&crypto_p.to_be_bytes());
-    let q = BigUint::from_bytes_be(&crypto_q.to_be_bytes());
+/// `key_gen` with the modulus size given by the security level
+pub fn key_gen_with_level(
+    level: SecurityLevel,
+    l: usize,
+    k: usize,
+) -> Result<RSAThresholdPrivateKey, KeyGenError> {
+    key_gen(level.bit_length(), l, k)
+}
 
-    if p.bits() != half_bit_length || q.bits() != half_bit_length {
-        return Err(KeyGenError::BitLength);
+/// Larger crypto-bigint types make the generation much slower, so the smallest type that fits
+/// `bit_length` is picked at runtime.
+fn generate_safe_prime_sized(bit_length: usize) -> BigUint {
+    match bit_length {
+        0..=512 => generate_safe_prime_biguint::<{ U512::LIMBS }>(bit_length),
+        513..=1024 => generate_safe_prime_biguint::<{ U1024::LIMBS }>(bit_length),
+        1025..=1536 => generate_safe_prime_biguint::<{ U1536::LIMBS }>(bit_length),
+        1537..=2048 => generate_safe_prime_biguint::<{ U2048::LIMBS }>(bit_length),
+        2049..=3072 => generate_safe_prime_biguint::<{ U3072::LIMBS }>(bit_length),
+        3073..=4096 => generate_safe_prime_biguint::<{ U4096::LIMBS }>(bit_length),
+        4097..=6144 => generate_safe_prime_biguint::<{ U6144::LIMBS }>(bit_length),
+        _ => generate_safe_prime_biguint::<{ U8192::LIMBS }>(bit_length),
     }
+}
 
-    Ok((p, q))
+/// The candidates are sieved upwards from a random start in [\sqrt{2} 2^{bit_length - 1},
+/// 2^{bit_length}), the first safe prime is taken.
+fn generate_safe_prime_biguint<const L: usize>(bit_length: usize) -> BigUint
+where
+    Uint<L>: Encoding,
+{
+    // ceil(\sqrt{2} 2^{bit_length - 1}) = ceil(\sqrt{2^{2 bit_length - 1}})
+    let square = BigUint::one() << (2 * bit_length - 1);
+    let mut lower = square.sqrt();
+    if &lower * &lower < square {
+        lower += 1u8;
+    }
+    let upper = BigUint::one() << bit_length;
+    let mut rng = ChaCha20Rng::from_entropy();
+    loop {
+        let start = rng.gen_biguint_range(&lower, &upper);
+        let start = Uint::<L>::from_be_slice(&uint_to_be_pad(start, Uint::<L>::BYTES).unwrap());
+        // The sieve ends at 2^{bit_length}, then the search starts over from another point.
+        if let Some(prime) = Sieve::new(&start, bit_length, true).find(is_safe_prime) {
+            return BigUint::from_bytes_be(prime.to_be_bytes().as_ref());
+        }
+    }
 }
 
 // FIXME go through expects and fix them!
 // TODO pass the msg digest
+/// Verify a proof made without a signing context.
+#[deprecated(
+    note = "a proof without a context verifies in any session, use `session::verify_proof_in_context`"
+)]
 pub fn verify_proof(
     msg: &[u8],
     v: &BigUint,
@@ -607,6 +889,38 @@ pub fn verify_proof(
         n,
         key_bytes_size,
     );
+    verify_raw_proof(&x, v, delta, vi, pms, n)
+}
+
+/// Verify the proof of correctness of a share produced by `sign_raw_with_share`, only the
+/// `ProofVersion::CURRENT` proofs are accepted, see `KeyContext::with_proof_policy`.
+pub fn verify_raw_proof(
+    x: &BigUint,
+    v: &BigUint,
+    delta: usize,
+    vi: &RsaVerificationKey,
+    pms: &PartialMessageSignature,
+    n: &BigUint,
+) -> bool {
+    check_raw_proof(x, v, delta, vi, pms, n, None, &ProofPolicy::default())
+}
+
+/// `verify_raw_proof` of a proof bound to the optional signing context.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn check_raw_proof(
+    x: &BigUint,
+    v: &BigUint,
+    delta: usize,
+    vi: &RsaVerificationKey,
+    pms: &PartialMessageSignature,
+    n: &BigUint,
+    context: Option<&SigningContext>,
+    policy: &ProofPolicy,
+) -> bool {
+    let proof_version = pms.proof_version;
+    if !policy.accepts(&proof_version) || pms.c.bits() > proof_version.challenge_bits() {
+        return false;
+    }
     let x_tilde: BigUint = x.pow(4 * delta);
 
     let xi_squared: BigUint = pms.xi.modpow(&BigUint::from(2u8), &n);
@@ -615,44 +929,42 @@ pub fn verify_proof(
     // FIXME refactor param5 and param6 calculations
     // FIXME use checked_mul instead
     let param5 = v.modpow(&pms.z, &n);
-    let tmp1 = vi
+    let Some(tmp1) = vi
         .key
         .modpow(&pms.c, &n)
         .mod_inverse(n)
-        .expect("")
-        .to_biguint()
-        .expect("");
+        .and_then(|value| value.to_biguint())
+    else {
+        return false;
+    };
     let param5 = (param5 * tmp1).mod_floor(&n);
 
     let param6 = x_tilde.modpow(&pms.z, &n);
-    let tmp2 = pms
+    let Some(tmp2) = pms
         .xi
         .modpow(&(pms.c.clone().mul(BigUint::from(2u8))), &n)
         .mod_inverse(n)
-        .expect("")
-        .to_biguint()
-        .expect("");
+        .and_then(|value| value.to_biguint())
+    else {
+        return false;
+    };
     let param6 = (param6 * tmp2).mod_floor(&n);
 
-    let mut commit = v.to_bytes_be();
-    commit.extend(x_tilde.to_bytes_be());
-    commit.extend(vi.key.to_bytes_be());
-    commit.extend(xi_squared.to_bytes_be());
-    commit.extend(param5.to_bytes_be());
-    commit.extend(param6.to_bytes_be());
-    pms.c.cmp(&BigUint::from_bytes_be(&Sha256::digest(commit))) == Ordering::Equal
-}
-
-// FIXME: allow specifying the path
-pub fn save_key(key: &RSAThresholdPrivateKey) -> std::io::Result<()> {
-    let mut keyfile = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-    keyfile.push("resources/test/private_key.json");
-    eprintln!("keyfile path: {}", keyfile.display());
-    let mut handle = File::create(keyfile)?;
-    handle.write_all(serde_json::to_string(key).unwrap().as_bytes())?;
-    Ok(())
+    pms.c.cmp(&proof_challenge(
+        proof_version,
+        context,
+        v,
+        &x_tilde,
+        vi,
+        &xi_squared,
+        &param5,
+        &param6,
+    )) == Ordering::Equal
 }
 
+/// Test fixture of this repository, use `keystore::KeyStore` for loading the packages. Only
+/// public for the benchmarks.
+#[doc(hidden)]
 pub fn load_key() -> std::io::Result<RSAThresholdPrivateKey> {
     let mut keyfile = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     keyfile.push("resources/test/private_key.json");
@@ -684,18 +996,79 @@ pub fn combine_shares(
         &key_share.n,
         key_share.key_bytes_size,
     );
+    let signature = combine_raw_shares(&x, delta, &sign_shares, &key_share.n, &key_share.e, l)?;
+
+    // BigUint::from_bytes_be(
+    match uint_to_zeroizing_be_pad(signature, key_share.key_bytes_size) {
+        Ok(value) => Ok(value),
+        Err(_) => Err(SigningError::SigningError),
+    }
+    // .expect(""),
+    // )
+}
+
+/// Combine shares produced by `sign_raw_with_share` into `x^d mod n`.
+pub fn combine_raw_shares(
+    x: &BigUint,
+    delta: usize,
+    sign_shares: &[PartialMessageSignature],
+    n: &BigUint,
+    e: &BigUint,
+    l: usize,
+) -> Result<BigUint, SigningError> {
+    let partials: Vec<(usize, &BigUint)> = sign_shares.iter().map(|s| (s.id, &s.xi)).collect();
+    combine_partial_exponentiations(x, delta, &partials, n, e, l)
+}
+
+/// The interpolation needs distinct ids out of 1..=l and x_i in Z_n^*, otherwise it panics.
+pub(crate) fn valid_partials(partials: &[(usize, &BigUint)], n: &BigUint, l: usize) -> bool {
+    let mut ids = std::collections::HashSet::new();
+    !partials.is_empty()
+        && partials.iter().all(|(id, xi)| {
+            (1..=l).contains(id)
+                && ids.insert(*id)
+                && !xi.is_zero()
+                && *xi < n
+                && xi.gcd(n).is_one()
+        })
+}
+
+/// Combine the bare `(id, x_i)` pairs, the proofs are not needed for combining.
+pub(crate) fn combine_partial_exponentiations(
+    x: &BigUint,
+    delta: usize,
+    partials: &[(usize, &BigUint)],
+    n: &BigUint,
+    e: &BigUint,
+    l: usize,
+) -> Result<BigUint, SigningError> {
+    if !valid_partials(partials, n, l) {
+        return Err(SigningError::SigningError);
+    }
+    let w = interpolate_in_exponent(delta, partials, n, l);
+    let (a, b) = bezout_coefficients(delta, e)?;
+    Ok(bezout_root(&w, x, &a, &b, n))
+}
+
+/// w = \prod x_i^{2 \lambda_{0,i}}
+pub(crate) fn interpolate_in_exponent(
+    delta: usize,
+    partials: &[(usize, &BigUint)],
+    n: &BigUint,
+    l: usize,
+) -> BigUint {
     // eprintln!("combine shares x len: \n{:?}", x.to_bytes_be().len());
     // eprintln!("pz_x = {}", x);
 
     let mut w = BigUint::one();
     // FIXME the set is supposed to be dynamic
-    let subset = sign_shares.iter().map(|s| s.id).collect::<Vec<usize>>();
+    let subset = partials.iter().map(|(id, _)| *id).collect::<Vec<usize>>();
     // eprintln!(
     //     "The subset used for combining the signatures is: {:?}",
     //     subset
     // );
-    for (_, share) in sign_shares.iter().enumerate() {
-        let lamb = lambda(delta, 0, share.id, l, subset.clone());
+    for (id, xi) in partials.iter() {
+        let lamb = lambda(delta, 0, *id, l, subset.clone());
         // eprintln!("lambda is: {lamb}");
 
         // FIXME exponent might be negative - what then?
@@ -704,29 +1077,45 @@ pub fn combine_shares(
         // eprintln!("Combining shares: exponent: {}", exponent);
 
         w.mul_assign(match exponent.cmp(&BigInt::zero()) {
-            Ordering::Less => share
-                .xi
-                .modpow(&exponent.neg().to_biguint().expect(""), &key_share.n)
-                .mod_inverse(&key_share.n.to_bigint().expect(""))
+            Ordering::Less => xi
+                .modpow(&exponent.neg().to_biguint().expect(""), n)
+                .mod_inverse(n.to_bigint().expect(""))
                 .expect("")
                 .to_biguint()
                 .expect(""),
             Ordering::Equal => BigUint::one(),
-            Ordering::Greater => share
-                .xi
-                .modpow(&exponent.to_biguint().expect(""), &key_share.n),
+            Ordering::Greater => xi.modpow(&exponent.to_biguint().expect(""), n),
         });
-        // w.mul_assign(share.modpow(&exponent, &key_share.n));
+        // w.mul_assign(share.modpow(&exponent, n));
     }
-    // w = w.mod_floor(&key_share.n);
+    // w = w.mod_floor(n);
+    w
+}
+
+/// a, b such that e'a + eb = 1, where e' = 4 \delta^2
+pub(crate) fn bezout_coefficients(
+    delta: usize,
+    e: &BigUint,
+) -> Result<(BigInt, BigInt), SigningError> {
     let e_prime = BigUint::from(4u8).mul(delta.pow(2));
     let (_g, Some(a), Some(b)) = extended_gcd(
         std::borrow::Cow::Borrowed(&e_prime),
-        std::borrow::Cow::Borrowed(&key_share.e),
+        std::borrow::Cow::Borrowed(e),
         true,
     ) else {
-        todo!()
+        return Err(SigningError::SigningError);
     };
+    Ok((a, b))
+}
+
+/// y = w^a x^b, the e-th root of x
+pub(crate) fn bezout_root(
+    w: &BigUint,
+    x: &BigUint,
+    a: &BigInt,
+    b: &BigInt,
+    n: &BigUint,
+) -> BigUint {
     // eprintln!("a: {}", a);
     // eprintln!("e_prime: {}", e_prime);
     // eprintln!("b: {}", b);
@@ -736,17 +1125,17 @@ pub fn combine_shares(
     //     e_prime
     //         .clone()
     //         .mul(a.clone())
-    //         .add(&key_share.e.to_bigint().expect("").clone().mul(b.clone()))
+    //         .add(e.to_bigint().expect("").clone().mul(b.clone()))
     //         .cmp(&BigUint::one()),
     //     Ordering::Equal,
     //     "The Bezout's equality e'a + eb != 1 does not hold.",
     // );
     // assert_eq!(g.cmp(&BigUint::one()), Ordering::Equal);
     // let we = w.modpow(
-    //     &key_share.e.to_bigint().expect(""),
-    //     &key_share.n.to_bigint().expect(""),
+    //     e.to_bigint().expect(""),
+    //     n.to_bigint().expect(""),
     // );
-    // let xe_prime = x.modpow(&BigUint::from(e_prime), &key_share.n.to_bigint().expect(""));
+    // let xe_prime = x.modpow(&BigUint::from(e_prime), n.to_bigint().expect(""));
     // assert_eq!(
     //     we.cmp(&BigUint::zero()),
     //     Ordering::Greater,
@@ -762,7 +1151,7 @@ pub fn combine_shares(
     // // with IDs 0 and 2
     // assert_eq!(
     //     we.cmp(&xe_prime),
-    //     // .cmp(&x.modpow(&BigUint::from(e_prime), &key_share.n)),
+    //     // .cmp(&x.modpow(&BigUint::from(e_prime), n)),
     //     Ordering::Equal,
     //     "w^e != x^e'"
     // );
@@ -770,36 +1159,27 @@ pub fn combine_shares(
     // NOTE raise to the negative power is not possible at the moment
     let first = match a.cmp(&BigInt::zero()) {
         Ordering::Less => w
-            .modpow(&a.neg().to_biguint().expect(""), &key_share.n)
-            .mod_inverse(&key_share.n.to_bigint().expect(""))
+            .modpow(&a.neg().to_biguint().expect(""), n)
+            .mod_inverse(n.to_bigint().expect(""))
             .expect("")
             .to_biguint()
             .expect(""),
         Ordering::Equal => BigUint::one(),
-        Ordering::Greater => w.modpow(&a.to_biguint().expect(""), &key_share.n),
+        Ordering::Greater => w.modpow(&a.to_biguint().expect(""), n),
     };
     let second = match b.cmp(&BigInt::zero()) {
         Ordering::Less => x
-            .modpow(&b.neg().to_biguint().expect(""), &key_share.n)
-            .mod_inverse(&key_share.n)
+            .modpow(&b.neg().to_biguint().expect(""), n)
+            .mod_inverse(n)
             .expect("")
             .to_biguint()
             .expect(""),
         Ordering::Equal => BigUint::one(),
-        Ordering::Greater => x.modpow(&b.to_biguint().expect(""), &key_share.n),
+        Ordering::Greater => x.modpow(&b.to_biguint().expect(""), n),
     };
     // eprintln!("shares combined");
 
-    // BigUint::from_bytes_be(
-    match uint_to_zeroizing_be_pad(
-        first.mul(second).mod_floor(&key_share.n),
-        key_share.key_bytes_size,
-    ) {
-        Ok(value) => Ok(value),
-        Err(_) => Err(SigningError::SigningError),
-    }
-    // .expect(""),
-    // )
+    first.mul(second).mod_floor(n)
 }
 
 fn verify_signature(
@@ -851,6 +1231,7 @@ fn regular_signature(msg: &[u8], key: &RSAThresholdPrivateKey) -> BigUint {
 }
 
 #[cfg(test)]
+#[allow(deprecated)]
 mod tests {
     use super::*;
     use itertools::Itertools;
@@ -1004,6 +1385,93 @@ mod tests {
         eprintln!("{:?}", q.to_bytes_be());
     }
 
+    #[test]
+    fn that_primes_are_generated_in_the_smallest_fitting_type() {
+        for bit_length in [64, 520] {
+            let p = generate_safe_prime_sized(bit_length);
+            assert_eq!(p.bits(), bit_length);
+            assert!(is_prime(&U2048::from_be_slice(
+                &uint_to_be_pad(p, 256).unwrap()
+            )));
+        }
+        assert!(matches!(
+            generate_p_and_q(2 * 8192 + 2),
+            Err(KeyGenError::TooBig)
+        ));
+    }
+
+    #[test]
+    fn that_safe_primes_are_above_the_bound() {
+        for bit_length in [3, 5, 17, 64, 100] {
+            let p = generate_large_safe_prime(bit_length);
+            assert_eq!((&p * &p).bits(), 2 * bit_length);
+            assert!(is_safe_prime(&U512::from_be_slice(
+                &uint_to_be_pad(p, 64).unwrap()
+            )));
+        }
+    }
+
+    #[test]
+    fn that_modulus_has_exactly_the_requested_bit_length() {
+        for bit_length in [64, 65, 127] {
+            let (p, q) = generate_p_and_q(bit_length).unwrap();
+            assert_eq!((&p * &q).bits(), bit_length);
+            assert_eq!(p.bits(), bit_length.div_ceil(2));
+            assert_eq!(q.bits(), bit_length / 2);
+            assert_eq!((&q * &q).bits(), 2 * q.bits());
+        }
+        assert!(matches!(generate_p_and_q(5), Err(KeyGenError::TooSmall)));
+    }
+
+    #[test]
+    fn that_strict_mode_is_off_in_tests() {
+        assert!(!is_strict_mode());
+        assert!(check_bit_length(512).is_ok());
+        assert_eq!(SecurityLevel::Rsa3072.bit_length(), 3072);
+    }
+
+    #[test]
+    #[ignore = "generates a 7680-bit key"]
+    fn that_largest_level_keys_round_trip() {
+        use crate::context::KeyContext;
+        use crate::format::Versioned;
+        use crate::session::random_session_id;
+        use rsa::traits::PublicKeyParts;
+
+        let sk = key_gen_with_level(SecurityLevel::Rsa7680, 3, 2).unwrap();
+        let public_key = RsaPublicKey::from(&sk);
+        assert_eq!(public_key.n().bits(), 7680);
+        assert_eq!(RsaPrivateKey::from(&sk).to_public_key(), public_key);
+
+        let (secret_pkgs, public_pkgs) = deal(&sk, 3, 2);
+        let public_pkg = PublicPackage::from_json(&public_pkgs[0].to_json().unwrap()).unwrap();
+        assert_eq!(public_pkg, public_pkgs[0]);
+        let pem = public_pkg.to_extended_pem(LineEnding::LF).unwrap();
+        assert_eq!(PublicPackage::from_extended_pem(&pem).unwrap(), public_pkg);
+        let pem = public_pkg.to_public_key_pem(LineEnding::LF).unwrap();
+        assert_eq!(
+            encoding::public_key_from_pem(&pem).unwrap(),
+            public_pkg.public_key
+        );
+
+        let context = KeyContext::new(&public_pkg).unwrap();
+        let session = random_session_id();
+        let partials: Vec<_> = secret_pkgs[..2]
+            .iter()
+            .map(|secret_pkg| {
+                context
+                    .sign_in_session(secret_pkg, b"ABC", PaddingScheme::PKCS1v15, &session)
+                    .unwrap()
+            })
+            .collect();
+        let signature = context
+            .combine_robust(b"ABC", &partials, PaddingScheme::PKCS1v15, &session)
+            .unwrap();
+        assert!(public_key
+            .verify(Pkcs1v15Sign::new_unprefixed(), b"ABC", &signature)
+            .is_ok());
+    }
+
     #[test]
     fn it_works() {
         let one = Checked::new(U256::ONE);
@@ -1187,23 +1655,6 @@ mod tests {
         lambda(2, 0, 1, 2, vec![1, 2]);
     }
 
-    // #[test]
-    // fn save_keys() {
-    //     let l = 2;
-    //     let k = 2;
-    //     let t = 1;
-    //     let bit_length = 2048;
-    //     let msg = String::from("hello").into_bytes().as_slice();
-    //     // dealer's part
-    //     let sk = key_gen(bit_length, l, k).unwrap();
-    //     let _ = save_key(&sk);
-    //     let loaded_key = load_key().unwrap();
-    //     assert_eq!(sk.p, loaded_key.p);
-    //     assert_eq!(sk.q, loaded_key.q);
-    //     assert_eq!(sk.d, loaded_key.d);
-    //     assert_eq!(sk.m, loaded_key.m);
-    //     assert_eq!(sk.e, loaded_key.e);
-    // }
     #[test]
     fn show_bigint() {
         let mut rng = ChaCha20Rng::from_entropy();
@@ -1218,7 +1669,6 @@ mod tests {
         // let t = 1;
         let bit_length = 2048;
         // let sk = key_gen(bit_length, l, k).unwrap();
-        // save_key(&sk);
         let sk = load_key().unwrap();
 
         let n = sk.p.clone() * sk.q.clone();
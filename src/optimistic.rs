@@ -0,0 +1,238 @@
+// The signature shares apart from their proofs of correctness.
+//
+// In the robust mode every signer sends the share x_i with its proof and the combiner verifies all
+// the proofs before combining, see `KeyContext::sign` and `KeyContext::combine_robust`. In the
+// optimistic mode the signers send x_i only, see `KeyContext::sign_share`, and the combiner checks
+// the final signature with the public key instead, see `KeyContext::combine_optimistic`. Only when
+// that fails, the proofs are asked for with `KeyContext::prove_share` and the cheating signers are
+// found by `KeyContext::combine_robust`. Without the proof a signer does a single exponentiation.
+
+use crate::transcript::ProofVersion;
+use crate::{PartialMessageSignature, SigningError};
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// x_i = x^{2 \delta s_i} of the share `id`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignatureShare {
+    pub id: usize,
+    pub xi: BigUint,
+}
+
+/// The proof of correctness of the `SignatureShare` with the same id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareProof {
+    pub id: usize,
+    pub z: BigUint,
+    pub c: BigUint,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commitments: Option<(BigUint, BigUint)>,
+    pub proof_version: ProofVersion,
+}
+
+#[derive(Error, Debug, Serialize, Deserialize)]
+pub enum CombineError {
+    #[error("The combined signature is invalid, a signature share is wrong or missing")]
+    InvalidSignature,
+    #[error("Not enough valid partial signatures, the proofs of {invalid:?} are invalid")]
+    NotEnoughValidShares { invalid: Vec<usize> },
+    #[error("Signing error: {0}")]
+    Signing(String),
+}
+
+impl From<SigningError> for CombineError {
+    fn from(e: SigningError) -> Self {
+        CombineError::Signing(e.to_string())
+    }
+}
+
+impl PartialMessageSignature {
+    pub fn share(&self) -> SignatureShare {
+        SignatureShare {
+            id: self.id,
+            xi: self.xi.clone(),
+        }
+    }
+
+    pub fn into_parts(self) -> (SignatureShare, ShareProof) {
+        (
+            SignatureShare {
+                id: self.id,
+                xi: self.xi,
+            },
+            ShareProof {
+                id: self.id,
+                z: self.z,
+                c: self.c,
+                commitments: self.commitments,
+                proof_version: self.proof_version,
+            },
+        )
+    }
+
+    /// `None` for a share and a proof of different signers.
+    pub fn from_parts(share: SignatureShare, proof: ShareProof) -> Option<Self> {
+        if share.id != proof.id {
+            return None;
+        }
+        Some(PartialMessageSignature {
+            id: share.id,
+            xi: share.xi,
+            z: proof.z,
+            c: proof.c,
+            commitments: proof.commitments,
+            proof_version: proof.proof_version,
+        })
+    }
+}
+
+#[cfg(test)]
+#[allow(deprecated)]
+mod tests {
+    use super::*;
+    use crate::context::KeyContext;
+    use crate::session::random_session_id;
+    use crate::{deal, load_key, PaddingScheme};
+    use rsa::Pkcs1v15Sign;
+
+    #[test]
+    fn that_optimistic_shares_combine() {
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 4, 3);
+        let public_pkg = &public_pkgs[0];
+        let context = KeyContext::new(public_pkg).unwrap();
+        let shares: Vec<SignatureShare> = secret_pkgs[1..]
+            .iter()
+            .map(|secret_pkg| {
+                context
+                    .sign_share(secret_pkg, b"ABC", PaddingScheme::PKCS1v15)
+                    .unwrap()
+            })
+            .collect();
+        let signature = context
+            .combine_optimistic(b"ABC", &shares, PaddingScheme::PKCS1v15)
+            .unwrap();
+        assert!(public_pkg
+            .public_key
+            .verify(Pkcs1v15Sign::new_unprefixed(), b"ABC", &signature)
+            .is_ok());
+
+        // The share is the same as the one of the robust mode
+        let pms = context
+            .sign(&secret_pkgs[1], b"ABC", PaddingScheme::PKCS1v15)
+            .unwrap();
+        assert_eq!(pms.share(), shares[0]);
+        let (share, proof) = pms.into_parts();
+        let pms = PartialMessageSignature::from_parts(share, proof).unwrap();
+        assert!(context.verify_proof(b"ABC", &pms, PaddingScheme::PKCS1v15));
+    }
+
+    #[test]
+    fn that_wrong_share_is_found_by_the_proofs() {
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 4, 2);
+        let public_pkg = &public_pkgs[0];
+        let context = KeyContext::new(public_pkg).unwrap();
+        let mut shares: Vec<SignatureShare> = secret_pkgs
+            .iter()
+            .map(|secret_pkg| {
+                context
+                    .sign_share(secret_pkg, b"ABC", PaddingScheme::PKCS1v15)
+                    .unwrap()
+            })
+            .collect();
+        shares[1].xi += 1u8;
+        assert!(matches!(
+            context.combine_optimistic(b"ABC", &shares[..2], PaddingScheme::PKCS1v15),
+            Err(CombineError::InvalidSignature)
+        ));
+
+        // The fallback, the signers prove the shares they have sent in the session
+        let session = random_session_id();
+        let partials: Vec<PartialMessageSignature> = shares
+            .into_iter()
+            .zip(&secret_pkgs)
+            .map(|(share, secret_pkg)| {
+                let proof = context
+                    .prove_share(
+                        secret_pkg,
+                        b"ABC",
+                        PaddingScheme::PKCS1v15,
+                        &share,
+                        &session,
+                    )
+                    .unwrap();
+                PartialMessageSignature::from_parts(share, proof).unwrap()
+            })
+            .collect();
+        assert!(!context.verify_proof_in_session(
+            b"ABC",
+            &partials[1],
+            PaddingScheme::PKCS1v15,
+            &session
+        ));
+        let signature = context
+            .combine_robust(b"ABC", &partials, PaddingScheme::PKCS1v15, &session)
+            .unwrap();
+        assert!(public_pkg
+            .public_key
+            .verify(Pkcs1v15Sign::new_unprefixed(), b"ABC", &signature)
+            .is_ok());
+        assert!(matches!(
+            context.combine_robust(b"ABC", &partials[..2], PaddingScheme::PKCS1v15, &session),
+            Err(CombineError::NotEnoughValidShares { invalid }) if invalid == vec![partials[1].id]
+        ));
+        // The proofs of another session are not replayed
+        assert!(matches!(
+            context.combine_robust(b"ABC", &partials, PaddingScheme::PKCS1v15, &random_session_id()),
+            Err(CombineError::NotEnoughValidShares { invalid }) if invalid.len() == partials.len()
+        ));
+        assert!(!context.verify_proof(b"ABC", &partials[0], PaddingScheme::PKCS1v15));
+    }
+
+    #[test]
+    fn that_malformed_shares_are_refused_without_panicking() {
+        let key = load_key().unwrap();
+        let (secret_pkgs, public_pkgs) = deal(&key, 3, 2);
+        let context = KeyContext::new(&public_pkgs[0]).unwrap();
+        let shares: Vec<SignatureShare> = secret_pkgs[..2]
+            .iter()
+            .map(|secret_pkg| {
+                context
+                    .sign_share(secret_pkg, b"ABC", PaddingScheme::PKCS1v15)
+                    .unwrap()
+            })
+            .collect();
+        let combine = |share: SignatureShare| {
+            context.combine_optimistic(b"ABC", &[shares[0].clone(), share], PaddingScheme::PKCS1v15)
+        };
+        assert!(combine(shares[1].clone()).is_ok());
+
+        let id = shares[1].id;
+        let malformed = [
+            SignatureShare {
+                id,
+                xi: BigUint::from(0u8),
+            },
+            SignatureShare {
+                id,
+                xi: key.n.clone(),
+            },
+            // Not invertible modulo n
+            SignatureShare {
+                id,
+                xi: key.p.clone(),
+            },
+            SignatureShare {
+                id: 99,
+                xi: shares[1].xi.clone(),
+            },
+            shares[0].clone(),
+        ];
+        for share in malformed {
+            assert!(matches!(
+                combine(share),
+                Err(CombineError::InvalidSignature)
+            ));
+        }
+    }
+}
@@ -0,0 +1,91 @@
+// Encoding primitives from RFC 8017 that are not exposed by the rsa crate.
+
+use sha2::digest::{Digest, FixedOutputReset};
+use subtle::{Choice, ConditionallySelectable, ConstantTimeEq};
+
+/// MGF1 as defined in RFC 8017, B.2.1, the mask is xor-ed into `out`.
+pub(crate) fn mgf1_xor<D: Digest + FixedOutputReset>(out: &mut [u8], seed: &[u8]) {
+    let mut digest = D::new();
+    for (counter, chunk) in (0u32..).zip(out.chunks_mut(<D as Digest>::output_size())) {
+        Digest::update(&mut digest, seed);
+        Digest::update(&mut digest, counter.to_be_bytes());
+        let mask = digest.finalize_reset();
+        for (byte, mask_byte) in chunk.iter_mut().zip(mask.iter()) {
+            *byte ^= mask_byte;
+        }
+    }
+}
+
+/// EME-OAEP decoding, RFC 8017, 7.1.2 step 3.
+///
+/// All the format checks are done in constant time and any failure is reported as `None`, so that
+/// the caller cannot tell them apart (Manger's attack).
+pub(crate) fn oaep_decode<D: Digest + FixedOutputReset>(
+    em: &mut [u8],
+    label: &[u8],
+) -> Option<Vec<u8>> {
+    let h_len = <D as Digest>::output_size();
+    if em.len() < 2 * h_len + 2 {
+        return None;
+    }
+    let first_byte_is_zero = em[0].ct_eq(&0u8);
+    let (seed, db) = em[1..].split_at_mut(h_len);
+    mgf1_xor::<D>(seed, db);
+    mgf1_xor::<D>(db, seed);
+
+    let label_hash = D::digest(label);
+    let hashes_are_equal = db[..h_len].ct_eq(&label_hash);
+
+    // DB = lHash || PS || 0x01 || M, where PS is a possibly empty string of zeroes
+    let mut looking_for_index = Choice::from(1u8);
+    let mut nonzero_before_one = Choice::from(0u8);
+    let mut index = 0u32;
+    for (i, byte) in db.iter().enumerate().skip(h_len) {
+        let equals_zero = byte.ct_eq(&0u8);
+        let equals_one = byte.ct_eq(&1u8);
+        index.conditional_assign(&(i as u32), looking_for_index & equals_one);
+        looking_for_index &= !equals_one;
+        nonzero_before_one |= looking_for_index & !equals_zero;
+    }
+
+    let valid = first_byte_is_zero & hashes_are_equal & !nonzero_before_one & !looking_for_index;
+    if bool::from(valid) {
+        Some(db[index as usize + 1..].to_vec())
+    } else {
+        None
+    }
+}
+
+/// EMSA-PSS encoding of an already hashed message, RFC 8017, 9.1.1.
+///
+/// `em_bits` is the bit length of the modulus minus one.
+pub(crate) fn emsa_pss_encode<D: Digest + FixedOutputReset>(
+    m_hash: &[u8],
+    em_bits: usize,
+    salt: &[u8],
+) -> Option<Vec<u8>> {
+    let h_len = <D as Digest>::output_size();
+    let em_len = em_bits.div_ceil(8);
+    if m_hash.len() != h_len || em_len < h_len + salt.len() + 2 {
+        return None;
+    }
+
+    // H = Hash(0x00 * 8 || mHash || salt)
+    let mut digest = D::new();
+    Digest::update(&mut digest, [0u8; 8]);
+    Digest::update(&mut digest, m_hash);
+    Digest::update(&mut digest, salt);
+    let h = digest.finalize_reset();
+
+    // EM = maskedDB || H || 0xbc, where DB = PS || 0x01 || salt
+    let mut em = vec![0u8; em_len];
+    let db_len = em_len - h_len - 1;
+    let (db, rest) = em.split_at_mut(db_len);
+    db[db_len - salt.len() - 1] = 0x01;
+    db[db_len - salt.len()..].copy_from_slice(salt);
+    mgf1_xor::<D>(db, &h);
+    db[0] &= 0xff >> (8 * em_len - em_bits);
+    rest[..h_len].copy_from_slice(&h);
+    rest[h_len] = 0xbc;
+    Some(em)
+}
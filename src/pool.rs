@@ -0,0 +1,450 @@
+// Pool of pre-generated safe primes, so that keys can be created on demand.
+//
+// Background threads keep the pool filled up to the target size. Every prime that leaves the pool
+// is remembered by its SHA-256 fingerprint and a prime with a known fingerprint is never accepted
+// again. When a storage is configured, the primes and the fingerprints are persisted encrypted
+// with XChaCha20-Poly1305 after every change, before a prime is handed out.
+
+use crate::{
+    check_bit_length, check_threshold, generate_large_safe_prime, key_from_primes, KeyGenError,
+    RSAThresholdPrivateKey,
+};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, Payload};
+use chacha20poly1305::XChaCha20Poly1305;
+#[cfg(not(test))]
+use log::warn;
+use num_bigint::BigUint;
+use rand_chacha::{rand_core::SeedableRng, ChaCha20Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashSet, VecDeque};
+use std::path::PathBuf;
+#[cfg(test)]
+use std::println as warn;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use thiserror::Error;
+use zeroize::{Zeroize, Zeroizing};
+
+const STORAGE_AAD: &[u8] = b"pretzel safe prime pool v1";
+const NONCE_LENGTH: usize = 24;
+
+#[derive(Error, Debug, Serialize, Deserialize)]
+pub enum PoolError {
+    #[error("The pool storage cannot be accessed: {0}")]
+    Storage(String),
+    #[error("The pool storage cannot be decrypted")]
+    Decryption,
+    #[error("The pool storage holds primes of {found} bits, expected {expected}")]
+    BitLength { expected: usize, found: usize },
+    #[error("The pool was stopped")]
+    Stopped,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PoolState {
+    bit_length: usize,
+    primes: VecDeque<BigUint>,
+    /// SHA-256 of every prime that has been handed out
+    issued: HashSet<[u8; 32]>,
+    /// Primes being generated at the moment, each one has its place in the pool reserved
+    #[serde(skip)]
+    generating: usize,
+}
+
+impl Drop for PoolState {
+    fn drop(&mut self) {
+        self.primes.iter_mut().for_each(|prime| prime.zeroize());
+    }
+}
+
+fn fingerprint(prime: &BigUint) -> [u8; 32] {
+    Sha256::digest(prime.to_bytes_be()).into()
+}
+
+/// Where and under which key the pool is persisted.
+pub struct PoolStorage {
+    pub path: PathBuf,
+    pub key: Zeroizing<[u8; 32]>,
+}
+
+impl PoolStorage {
+    fn load(&self) -> Result<Option<PoolState>, PoolError> {
+        let data = match std::fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(PoolError::Storage(e.to_string())),
+        };
+        if data.len() < NONCE_LENGTH {
+            return Err(PoolError::Decryption);
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LENGTH);
+        let plaintext = Zeroizing::new(
+            XChaCha20Poly1305::new(self.key.as_ref().into())
+                .decrypt(
+                    nonce.into(),
+                    Payload {
+                        msg: ciphertext,
+                        aad: STORAGE_AAD,
+                    },
+                )
+                .map_err(|_| PoolError::Decryption)?,
+        );
+        serde_json::from_slice(&plaintext)
+            .map(Some)
+            .map_err(|_| PoolError::Decryption)
+    }
+
+    /// Write to a temporary file first, so that a crash never leaves a truncated pool behind.
+    fn store(&self, state: &PoolState) -> Result<(), PoolError> {
+        let plaintext = Zeroizing::new(
+            serde_json::to_vec(state).map_err(|e| PoolError::Storage(e.to_string()))?,
+        );
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut ChaCha20Rng::from_entropy());
+        let ciphertext = XChaCha20Poly1305::new(self.key.as_ref().into())
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &plaintext,
+                    aad: STORAGE_AAD,
+                },
+            )
+            .map_err(|e| PoolError::Storage(e.to_string()))?;
+
+        let mut data = nonce.to_vec();
+        data.extend_from_slice(&ciphertext);
+        let tmp_path = self.path.with_extension("tmp");
+        std::fs::write(&tmp_path, data).map_err(|e| PoolError::Storage(e.to_string()))?;
+        std::fs::rename(&tmp_path, &self.path).map_err(|e| PoolError::Storage(e.to_string()))
+    }
+}
+
+struct Shared {
+    state: Mutex<PoolState>,
+    /// Signalled when a prime was added or taken, or the pool is stopping
+    changed: Condvar,
+    stop: AtomicBool,
+    target_size: usize,
+    storage: Option<PoolStorage>,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn persist(&self, state: &PoolState) -> Result<(), PoolError> {
+        match &self.storage {
+            Some(storage) => storage.store(state),
+            None => Ok(()),
+        }
+    }
+
+    /// A thread generates a prime only for a free place, so that no finished prime is thrown away
+    /// because the pool has been filled meanwhile.
+    fn fill(&self) {
+        loop {
+            let bit_length = {
+                let mut state = self.lock();
+                while state.primes.len() + state.generating >= self.target_size
+                    && !self.stop.load(Ordering::SeqCst)
+                {
+                    state = self
+                        .changed
+                        .wait(state)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner());
+                }
+                if self.stop.load(Ordering::SeqCst) {
+                    return;
+                }
+                state.generating += 1;
+                state.bit_length
+            };
+
+            let prime = generate_large_safe_prime(bit_length);
+
+            let mut state = self.lock();
+            state.generating -= 1;
+            if state.primes.contains(&prime) || state.issued.contains(&fingerprint(&prime)) {
+                continue;
+            }
+            state.primes.push_back(prime);
+            if let Err(e) = self.persist(&state) {
+                warn!("The safe prime pool cannot be persisted: {e}");
+            }
+            self.changed.notify_all();
+        }
+    }
+}
+
+/// Safe primes of `bit_length` bits generated ahead of time by background threads.
+///
+/// Dropping the pool stops the threads, it waits for the primes being generated at the moment.
+pub struct SafePrimePool {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl SafePrimePool {
+    /// In-memory pool, the generated primes are lost when the pool is dropped.
+    pub fn new(bit_length: usize, target_size: usize, threads: usize) -> Self {
+        Self::start(PoolState::default(), bit_length, target_size, threads, None)
+    }
+
+    /// Pool persisted in `storage`, the primes and fingerprints stored there are loaded first.
+    pub fn with_storage(
+        bit_length: usize,
+        target_size: usize,
+        threads: usize,
+        storage: PoolStorage,
+    ) -> Result<Self, PoolError> {
+        let state = match storage.load()? {
+            Some(state) if state.bit_length != bit_length => {
+                return Err(PoolError::BitLength {
+                    expected: bit_length,
+                    found: state.bit_length,
+                })
+            }
+            Some(state) => state,
+            None => PoolState::default(),
+        };
+        Ok(Self::start(
+            state,
+            bit_length,
+            target_size,
+            threads,
+            Some(storage),
+        ))
+    }
+
+    fn start(
+        mut state: PoolState,
+        bit_length: usize,
+        target_size: usize,
+        threads: usize,
+        storage: Option<PoolStorage>,
+    ) -> Self {
+        state.bit_length = bit_length;
+        let shared = Arc::new(Shared {
+            state: Mutex::new(state),
+            changed: Condvar::new(),
+            stop: AtomicBool::new(false),
+            target_size,
+            storage,
+        });
+        let workers = (0..threads)
+            .map(|_| {
+                let shared = Arc::clone(&shared);
+                std::thread::spawn(move || shared.fill())
+            })
+            .collect();
+        SafePrimePool { shared, workers }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shared.lock().primes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn bit_length(&self) -> usize {
+        self.shared.lock().bit_length
+    }
+
+    /// Take a prime if one is ready.
+    pub fn try_take(&self) -> Result<Option<BigUint>, PoolError> {
+        let mut state = self.shared.lock();
+        self.pop(&mut state)
+    }
+
+    /// Take a prime, waiting for the background threads if the pool is empty.
+    pub fn take(&self) -> Result<BigUint, PoolError> {
+        let mut state = self.shared.lock();
+        loop {
+            if let Some(prime) = self.pop(&mut state)? {
+                return Ok(prime);
+            }
+            if self.workers.is_empty() || self.shared.stop.load(Ordering::SeqCst) {
+                return Err(PoolError::Stopped);
+            }
+            state = self
+                .shared
+                .changed
+                .wait(state)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+
+    /// The prime is recorded as issued and persisted before it is returned, if persisting fails
+    /// the prime stays in the pool.
+    fn pop(&self, state: &mut PoolState) -> Result<Option<BigUint>, PoolError> {
+        let Some(prime) = state.primes.pop_front() else {
+            return Ok(None);
+        };
+        let digest = fingerprint(&prime);
+        state.issued.insert(digest);
+        if let Err(e) = self.shared.persist(state) {
+            state.issued.remove(&digest);
+            state.primes.push_front(prime);
+            return Err(e);
+        }
+        self.shared.changed.notify_all();
+        Ok(Some(prime))
+    }
+
+    /// Put back a prime that has been taken but not used, it is no longer recorded as issued.
+    fn restore(&self, prime: BigUint) {
+        let mut state = self.shared.lock();
+        state.issued.remove(&fingerprint(&prime));
+        state.primes.push_front(prime);
+        if let Err(e) = self.shared.persist(&state) {
+            warn!("The safe prime pool cannot be persisted: {e}");
+        }
+        self.shared.changed.notify_all();
+    }
+
+    /// `key_gen` with p and q taken from the pool, the modulus has `2 * bit_length` bits.
+    ///
+    /// The pool holds primes of at least \sqrt{2} 2^{bit_length - 1} only, so any two of them
+    /// give a modulus of the full length and no prime is wasted. When the key cannot be made, the
+    /// primes go back to the pool.
+    pub fn key_gen(&self, l: usize, k: usize) -> Result<RSAThresholdPrivateKey, KeyGenError> {
+        check_threshold(l, k)?;
+        let bit_length = 2 * self.bit_length();
+        check_bit_length(bit_length)?;
+        let p = self.take().map_err(|_| KeyGenError::PoolUnavailable)?;
+        let q = match self.take() {
+            Ok(q) => q,
+            Err(_) => {
+                self.restore(p);
+                return Err(KeyGenError::PoolUnavailable);
+            }
+        };
+        if (&p * &q).bits() != bit_length {
+            self.restore(q);
+            self.restore(p);
+            return Err(KeyGenError::BitLength);
+        }
+        key_from_primes(p.clone(), q.clone(), l).inspect_err(|_| {
+            self.restore(q);
+            self.restore(p);
+        })
+    }
+}
+
+impl Drop for SafePrimePool {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::SeqCst);
+        self.shared.changed.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage(name: &str, key: [u8; 32]) -> PoolStorage {
+        PoolStorage {
+            path: std::env::temp_dir().join(format!("pretzel-{name}-{}.pool", std::process::id())),
+            key: Zeroizing::new(key),
+        }
+    }
+
+    #[test]
+    fn that_pool_fills_up_and_never_hands_out_a_prime_twice() {
+        let pool = SafePrimePool::new(64, 4, 2);
+        let mut seen = HashSet::new();
+        for _ in 0..10 {
+            let prime = pool.take().unwrap();
+            assert_eq!(prime.bits(), 64);
+            assert_eq!((&prime * &prime).bits(), 128);
+            assert!(seen.insert(prime));
+        }
+        while pool.len() < 4 {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let state = pool.shared.lock();
+        assert_eq!(state.issued.len(), 10);
+        assert!(state.primes.iter().all(|p| !seen.contains(p)));
+    }
+
+    #[test]
+    fn that_key_is_generated_from_pool() {
+        let pool = SafePrimePool::new(128, 2, 2);
+        let key = pool.key_gen(3, 2).unwrap();
+        assert_eq!(pool.shared.lock().issued.len(), 2);
+        assert_eq!(key.n.bits(), 256);
+        assert_eq!(key.p.bits(), 128);
+        assert_eq!(key.q.bits(), 128);
+        assert_ne!(key.p, key.q);
+        assert_eq!(key.n, &key.p * &key.q);
+
+        // no prime is taken for a wrong threshold
+        let issued = pool.shared.lock().issued.len();
+        assert!(matches!(
+            pool.key_gen(3, 4),
+            Err(KeyGenError::InvalidThreshold)
+        ));
+        assert!(matches!(
+            pool.key_gen(3, 0),
+            Err(KeyGenError::InvalidThreshold)
+        ));
+        assert_eq!(pool.shared.lock().issued.len(), issued);
+    }
+
+    #[test]
+    fn that_prime_goes_back_when_the_key_cannot_be_made() {
+        let pool = SafePrimePool::new(64, 1, 0);
+        let prime = generate_large_safe_prime(64);
+        pool.shared.lock().primes.push_back(prime.clone());
+        assert!(matches!(
+            pool.key_gen(3, 2),
+            Err(KeyGenError::PoolUnavailable)
+        ));
+        let state = pool.shared.lock();
+        assert_eq!(state.primes, [prime]);
+        assert!(state.issued.is_empty());
+    }
+
+    #[test]
+    fn that_persisted_pool_is_reloaded_only_with_the_right_key() {
+        let key = [7u8; 32];
+        let path = storage("reload", key).path;
+        let taken = {
+            let pool = SafePrimePool::with_storage(64, 3, 1, storage("reload", key)).unwrap();
+            let taken = pool.take().unwrap();
+            while pool.len() < 3 {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            taken
+        };
+        let data = std::fs::read(&path).unwrap();
+        assert!(!String::from_utf8_lossy(&data).contains(&taken.to_string()));
+
+        assert!(matches!(
+            SafePrimePool::with_storage(64, 3, 0, storage("reload", [8u8; 32])),
+            Err(PoolError::Decryption)
+        ));
+        assert!(matches!(
+            SafePrimePool::with_storage(128, 3, 0, storage("reload", key)),
+            Err(PoolError::BitLength { .. })
+        ));
+
+        let pool = SafePrimePool::with_storage(64, 3, 0, storage("reload", key)).unwrap();
+        assert!(pool.len() >= 3);
+        assert!(pool.shared.lock().issued.contains(&fingerprint(&taken)));
+        while let Some(prime) = pool.try_take().unwrap() {
+            assert_ne!(prime, taken);
+        }
+        assert!(matches!(pool.take(), Err(PoolError::Stopped)));
+        std::fs::remove_file(path).unwrap();
+    }
+}
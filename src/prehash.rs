@@ -0,0 +1,428 @@
+// Signing a digest instead of the whole message.
+//
+// The coordinator hashes the message, e.g. streams a large artifact through `Prehash::from_reader`,
+// and the signers get only the digest with the name of the hash. The digest is encoded as in
+// RSASSA-PKCS1-v1_5 (DigestInfo) or in RSASSA-PSS and the encoded message is signed, verified and
+// combined in the raw mode (`PaddingScheme::NONE`), so the signature is the same as the one of the
+// hashed message. For PSS the salt is picked by the coordinator, the signers have to encode the
+// same message. The proofs are bound to the signing session as in `KeyContext::sign_in_session`.
+
+use crate::context::KeyContext;
+use crate::optimistic::CombineError;
+use crate::padding::emsa_pss_encode;
+use crate::session::SessionId;
+use crate::{
+    PaddingScheme, PartialMessageSignature, RsaVerificationKey, SecretPackage, SigningError,
+};
+use num_bigint::BigUint;
+use rand::RngCore;
+use rand_chacha::{rand_core::SeedableRng, ChaCha20Rng};
+use rsa::hazmat::pkcs1v15_sign_pad;
+use rsa::pkcs8::{AssociatedOid, ObjectIdentifier};
+use rsa::Pkcs1v15Sign;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use std::io::Read;
+use thiserror::Error;
+
+#[derive(Error, Debug, Serialize, Deserialize)]
+pub enum PrehashError {
+    #[error("The digest has {found} bytes, {expected} expected")]
+    DigestLength { expected: usize, found: usize },
+    #[error("Unsupported hash algorithm")]
+    UnsupportedHash,
+    #[error("The key is too short for the digest")]
+    KeyTooShort,
+    #[error("Reading the message failed: {0}")]
+    Io(String),
+}
+
+impl From<std::io::Error> for PrehashError {
+    fn from(e: std::io::Error) -> Self {
+        PrehashError::Io(e.to_string())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    pub fn output_size(&self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 => 32,
+            HashAlgorithm::Sha384 => 48,
+            HashAlgorithm::Sha512 => 64,
+        }
+    }
+
+    pub fn from_oid(oid: ObjectIdentifier) -> Option<Self> {
+        [
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Sha384,
+            HashAlgorithm::Sha512,
+        ]
+        .into_iter()
+        .find(|hash_alg| hash_alg.oid() == oid)
+    }
+
+    pub fn oid(&self) -> ObjectIdentifier {
+        match self {
+            HashAlgorithm::Sha256 => Sha256::OID,
+            HashAlgorithm::Sha384 => Sha384::OID,
+            HashAlgorithm::Sha512 => Sha512::OID,
+        }
+    }
+
+    /// The DER encoded DigestInfo without the digest
+    pub(crate) fn digest_info_prefix(&self) -> Box<[u8]> {
+        match self {
+            HashAlgorithm::Sha256 => Pkcs1v15Sign::new::<Sha256>().prefix,
+            HashAlgorithm::Sha384 => Pkcs1v15Sign::new::<Sha384>().prefix,
+            HashAlgorithm::Sha512 => Pkcs1v15Sign::new::<Sha512>().prefix,
+        }
+    }
+
+    fn digest_reader(&self, reader: impl Read) -> std::io::Result<Vec<u8>> {
+        fn stream<D: Digest + std::io::Write>(mut reader: impl Read) -> std::io::Result<Vec<u8>> {
+            let mut hasher = D::new();
+            std::io::copy(&mut reader, &mut hasher)?;
+            Ok(hasher.finalize().to_vec())
+        }
+        match self {
+            HashAlgorithm::Sha256 => stream::<Sha256>(reader),
+            HashAlgorithm::Sha384 => stream::<Sha384>(reader),
+            HashAlgorithm::Sha512 => stream::<Sha512>(reader),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PrehashPadding {
+    Pkcs1v15,
+    Pss { salt: Vec<u8> },
+}
+
+impl PrehashPadding {
+    /// A random salt as long as the digest, as `rsa::Pss::new` does.
+    pub fn random_pss(hash_alg: HashAlgorithm) -> Self {
+        let mut salt = vec![0u8; hash_alg.output_size()];
+        ChaCha20Rng::from_entropy().fill_bytes(&mut salt);
+        PrehashPadding::Pss { salt }
+    }
+}
+
+/// The digest of a message with everything the signers need for encoding it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Prehash {
+    pub hash_alg: HashAlgorithm,
+    pub digest: Vec<u8>,
+    pub padding: PrehashPadding,
+}
+
+impl Prehash {
+    pub fn new(
+        hash_alg: HashAlgorithm,
+        digest: &[u8],
+        padding: PrehashPadding,
+    ) -> Result<Self, PrehashError> {
+        if digest.len() != hash_alg.output_size() {
+            return Err(PrehashError::DigestLength {
+                expected: hash_alg.output_size(),
+                found: digest.len(),
+            });
+        }
+        Ok(Prehash {
+            hash_alg,
+            digest: digest.to_vec(),
+            padding,
+        })
+    }
+
+    /// Finalize a hasher the message was fed into.
+    pub fn from_digest<D: Digest + AssociatedOid>(
+        digest: D,
+        padding: PrehashPadding,
+    ) -> Result<Self, PrehashError> {
+        let hash_alg = HashAlgorithm::from_oid(D::OID).ok_or(PrehashError::UnsupportedHash)?;
+        Self::new(hash_alg, &digest.finalize(), padding)
+    }
+
+    /// Hash the message as it is read, it is never held in memory as a whole.
+    pub fn from_reader(
+        hash_alg: HashAlgorithm,
+        reader: impl Read,
+        padding: PrehashPadding,
+    ) -> Result<Self, PrehashError> {
+        Self::new(hash_alg, &hash_alg.digest_reader(reader)?, padding)
+    }
+
+    /// The encoded message for the modulus n, it is signed in the raw mode.
+    pub fn encode(&self, n: &BigUint) -> Result<Vec<u8>, PrehashError> {
+        let hash_alg = self.hash_alg;
+        if self.digest.len() != hash_alg.output_size() {
+            return Err(PrehashError::DigestLength {
+                expected: hash_alg.output_size(),
+                found: self.digest.len(),
+            });
+        }
+        let em_bits = n.bits() - 1;
+        match &self.padding {
+            PrehashPadding::Pkcs1v15 => pkcs1v15_sign_pad(
+                &hash_alg.digest_info_prefix(),
+                &self.digest,
+                n.bits().div_ceil(8),
+            )
+            .ok(),
+            PrehashPadding::Pss { salt } => match hash_alg {
+                HashAlgorithm::Sha256 => emsa_pss_encode::<Sha256>(&self.digest, em_bits, salt),
+                HashAlgorithm::Sha384 => emsa_pss_encode::<Sha384>(&self.digest, em_bits, salt),
+                HashAlgorithm::Sha512 => emsa_pss_encode::<Sha512>(&self.digest, em_bits, salt),
+            },
+        }
+        .ok_or(PrehashError::KeyTooShort)
+    }
+}
+
+impl SecretPackage {
+    /// `sign` of the message the digest was computed from.
+    pub fn sign_prehash(
+        &self,
+        prehash: &Prehash,
+        max_signers: u16,
+        v: BigUint,
+        vi: &RsaVerificationKey,
+    ) -> Result<PartialMessageSignature, SigningError> {
+        let encoded = prehash
+            .encode(&self.share.n)
+            .map_err(|_| SigningError::MessageCannotBeSigned)?;
+        self.sign(&encoded, max_signers, v, vi, PaddingScheme::NONE)
+    }
+}
+
+impl KeyContext {
+    /// The proof verifies in any session, see `sign_prehash_in_session`.
+    pub fn sign_prehash(
+        &self,
+        secret_pkg: &SecretPackage,
+        prehash: &Prehash,
+    ) -> Result<PartialMessageSignature, SigningError> {
+        let encoded = prehash
+            .encode(self.modulus())
+            .map_err(|_| SigningError::MessageCannotBeSigned)?;
+        self.sign(secret_pkg, &encoded, PaddingScheme::NONE)
+    }
+
+    pub fn sign_prehash_in_session(
+        &self,
+        secret_pkg: &SecretPackage,
+        prehash: &Prehash,
+        session_id: &SessionId,
+    ) -> Result<PartialMessageSignature, SigningError> {
+        let encoded = prehash
+            .encode(self.modulus())
+            .map_err(|_| SigningError::MessageCannotBeSigned)?;
+        self.sign_in_session(secret_pkg, &encoded, PaddingScheme::NONE, session_id)
+    }
+
+    #[deprecated(
+        note = "a proof without a context verifies in any session, use `verify_proof_prehash_in_session`"
+    )]
+    pub fn verify_proof_prehash(&self, prehash: &Prehash, pms: &PartialMessageSignature) -> bool {
+        match prehash.encode(self.modulus()) {
+            Ok(encoded) => self.verify_proof_with(&encoded, pms, PaddingScheme::NONE, None),
+            Err(_) => false,
+        }
+    }
+
+    pub fn verify_proof_prehash_in_session(
+        &self,
+        prehash: &Prehash,
+        pms: &PartialMessageSignature,
+        session_id: &SessionId,
+    ) -> bool {
+        match prehash.encode(self.modulus()) {
+            Ok(encoded) => {
+                self.verify_proof_in_session(&encoded, pms, PaddingScheme::NONE, session_id)
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// `combine_robust` of the encoded message, the proofs are verified in the session.
+    pub fn combine_prehash_in_session(
+        &self,
+        prehash: &Prehash,
+        partials: &[PartialMessageSignature],
+        session_id: &SessionId,
+    ) -> Result<Vec<u8>, CombineError> {
+        let encoded = prehash
+            .encode(self.modulus())
+            .map_err(|_| SigningError::MessageCannotBeSigned)?;
+        self.combine_robust(&encoded, partials, PaddingScheme::NONE, session_id)
+    }
+
+    /// Combines the shares without verifying their proofs.
+    pub fn combine_prehash(
+        &self,
+        prehash: &Prehash,
+        sign_shares: Vec<PartialMessageSignature>,
+    ) -> Result<Vec<u8>, SigningError> {
+        let encoded = prehash
+            .encode(self.modulus())
+            .map_err(|_| SigningError::MessageCannotBeSigned)?;
+        self.combine_shares(&encoded, sign_shares, PaddingScheme::NONE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::random_session_id;
+    use crate::{deal, load_key};
+    use rsa::{Pss, RsaPrivateKey};
+
+    #[test]
+    fn that_prehash_signature_is_the_regular_one() {
+        let key = load_key().unwrap();
+        let private_key = RsaPrivateKey::from(&key);
+        let (secret_pkgs, public_pkgs) = deal(&key, 3, 2);
+        let public_pkg = &public_pkgs[0];
+        let context = KeyContext::new(public_pkg).unwrap();
+        let artifact = vec![0x5au8; 1 << 20];
+
+        let streamed = Prehash::from_reader(
+            HashAlgorithm::Sha512,
+            artifact.as_slice(),
+            PrehashPadding::Pkcs1v15,
+        )
+        .unwrap();
+        let prehash =
+            Prehash::from_digest(Sha512::new_with_prefix(&artifact), PrehashPadding::Pkcs1v15)
+                .unwrap();
+        assert_eq!(streamed, prehash);
+
+        let session = random_session_id();
+        let partials: Vec<PartialMessageSignature> = secret_pkgs[1..]
+            .iter()
+            .map(|secret_pkg| {
+                context
+                    .sign_prehash_in_session(secret_pkg, &prehash, &session)
+                    .unwrap()
+            })
+            .collect();
+        assert!(partials
+            .iter()
+            .all(|pms| context.verify_proof_prehash_in_session(&prehash, pms, &session)));
+        assert!(!context.verify_proof_prehash_in_session(
+            &prehash,
+            &partials[0],
+            &random_session_id()
+        ));
+        assert!(context
+            .combine_prehash_in_session(&prehash, &partials, &random_session_id())
+            .is_err());
+        let signature = context
+            .combine_prehash_in_session(&prehash, &partials, &session)
+            .unwrap();
+        let expected = private_key
+            .sign(Pkcs1v15Sign::new::<Sha512>(), &prehash.digest)
+            .unwrap();
+        assert_eq!(signature, expected);
+
+        // the shares of the package sign the same, unverified
+        let partials = secret_pkgs[1..]
+            .iter()
+            .map(|secret_pkg| {
+                secret_pkg
+                    .sign_prehash(
+                        &prehash,
+                        3,
+                        public_pkg.v.clone(),
+                        &public_pkg.verification_keys[secret_pkg.share.id - 1],
+                    )
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(
+            context.combine_prehash(&prehash, partials).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn that_prehash_pss_signature_verifies() {
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 3, 2);
+        let public_pkg = &public_pkgs[0];
+        let context = KeyContext::new(public_pkg).unwrap();
+        let digest = Sha384::digest(b"ABC");
+        let prehash = Prehash::new(
+            HashAlgorithm::Sha384,
+            &digest,
+            PrehashPadding::random_pss(HashAlgorithm::Sha384),
+        )
+        .unwrap();
+
+        let session = random_session_id();
+        let partials: Vec<PartialMessageSignature> = secret_pkgs[..2]
+            .iter()
+            .map(|secret_pkg| {
+                context
+                    .sign_prehash_in_session(secret_pkg, &prehash, &session)
+                    .unwrap()
+            })
+            .collect();
+        let signature = context
+            .combine_prehash_in_session(&prehash, &partials, &session)
+            .unwrap();
+        assert!(public_pkg
+            .public_key
+            .verify(Pss::new::<Sha384>(), &digest, &signature)
+            .is_ok());
+
+        assert!(matches!(
+            Prehash::new(HashAlgorithm::Sha256, &digest, PrehashPadding::Pkcs1v15),
+            Err(PrehashError::DigestLength {
+                expected: 32,
+                found: 48
+            })
+        ));
+    }
+
+    #[test]
+    fn that_prehash_pss_signature_is_the_one_of_rsa_with_the_same_salt() {
+        let key = load_key().unwrap();
+        let private_key = RsaPrivateKey::from(&key);
+        let (secret_pkgs, public_pkgs) = deal(&key, 3, 2);
+        let context = KeyContext::new(&public_pkgs[0]).unwrap();
+        let digest = Sha256::digest(b"ABC");
+        // rsa draws the salt first from the generator
+        let mut rng = ChaCha20Rng::seed_from_u64(7);
+        let mut salt = vec![0u8; 32];
+        rng.clone().fill_bytes(&mut salt);
+        let expected = private_key
+            .sign_with_rng(&mut rng, Pss::new_with_salt::<Sha256>(32), &digest)
+            .unwrap();
+
+        let prehash =
+            Prehash::new(HashAlgorithm::Sha256, &digest, PrehashPadding::Pss { salt }).unwrap();
+        let session = random_session_id();
+        let partials: Vec<PartialMessageSignature> = secret_pkgs[1..]
+            .iter()
+            .map(|secret_pkg| {
+                context
+                    .sign_prehash_in_session(secret_pkg, &prehash, &session)
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(
+            context
+                .combine_prehash_in_session(&prehash, &partials, &session)
+                .unwrap(),
+            expected
+        );
+    }
+}
@@ -0,0 +1,428 @@
+// Publicly verifiable distribution of the shares.
+//
+// Every share s_i is encrypted with the Paillier key of its owner, C_i = (1 + N)^{s_i} r^N mod N^2,
+// and the dealer proves in zero knowledge that the plaintext of C_i is the discrete logarithm of
+// v_i = v^{s_i} mod n. The proof is the Fiat-Shamir transform of the sigma protocol
+//
+//   t_1 = v^rho mod n,   t_2 = (1 + N)^rho mu^N mod N^2,   c = H(..., t_1, t_2)
+//   z = rho + c s_i (over the integers),   w = mu r^c mod N
+//
+// checked by v^z = t_1 v_i^c mod n and (1 + N)^z w^N = t_2 C_i^c mod N^2. rho hides c s_i
+// statistically and N is large enough for the plaintext to be determined by z, so anyone holding
+// the `PublicPackage` and the recipient keys can audit the dealer without decrypting.
+
+use crate::{PublicPackage, RsaSecretShare, RsaVerificationKey, SecretPackage};
+use num_bigint::{BigUint, ModInverse, RandBigInt, RandPrime};
+use num_integer::Integer;
+use num_traits::One;
+use rand_chacha::{rand_core::SeedableRng, ChaCha20Rng};
+use rayon::prelude::*;
+use rsa::traits::PublicKeyParts;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use zeroize::Zeroize;
+
+const CHALLENGE_BITS: usize = 256;
+const STATISTICAL_BITS: usize = 128;
+
+#[derive(Error, Debug, Serialize, Deserialize)]
+pub enum PvssError {
+    #[error("The Paillier modulus of the recipient is too small for the RSA modulus")]
+    RecipientKeyTooSmall,
+    #[error("The numbers of packages and recipients differ")]
+    RecipientCount,
+    #[error("There is no verification key for the share {0}")]
+    MissingVerificationKey(usize),
+    #[error("The proof of the encrypted share does not verify")]
+    InvalidProof,
+    #[error("The decrypted share does not match its verification key")]
+    InconsistentShare,
+}
+
+/// Bit length of rho, it covers c s_i with s_i < n and hides it statistically.
+fn rho_bits(n: &BigUint) -> usize {
+    n.bits() + CHALLENGE_BITS + STATISTICAL_BITS
+}
+
+/// The Paillier modulus has to exceed 2^{|rho| + 2}, so that z determines the plaintext.
+pub fn required_modulus_bits(public_pkg: &PublicPackage) -> usize {
+    rho_bits(public_pkg.public_key.n()) + 3
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PvssPublicKey {
+    pub n: BigUint,
+}
+
+impl PvssPublicKey {
+    fn n_squared(&self) -> BigUint {
+        &self.n * &self.n
+    }
+}
+
+/// Paillier key with g = N + 1
+#[derive(Debug, Clone)]
+pub struct PvssSecretKey {
+    public: PvssPublicKey,
+    lambda: BigUint,
+    mu: BigUint,
+}
+
+impl Drop for PvssSecretKey {
+    fn drop(&mut self) {
+        self.lambda.zeroize();
+        self.mu.zeroize();
+    }
+}
+
+impl PvssSecretKey {
+    pub fn generate(modulus_bits: usize) -> Self {
+        let mut rng = ChaCha20Rng::from_entropy();
+        loop {
+            let p = rng.gen_prime(modulus_bits.div_ceil(2));
+            let q = rng.gen_prime(modulus_bits.div_ceil(2));
+            let n = &p * &q;
+            if p == q || n.bits() < modulus_bits {
+                continue;
+            }
+            let lambda = (&p - 1u8).lcm(&(&q - 1u8));
+            let Some(mu) = lambda
+                .clone()
+                .mod_inverse(&n)
+                .and_then(|mu| mu.to_biguint())
+            else {
+                continue;
+            };
+            return PvssSecretKey {
+                public: PvssPublicKey { n },
+                lambda,
+                mu,
+            };
+        }
+    }
+
+    /// Key large enough for the shares of the given public package
+    pub fn generate_for(public_pkg: &PublicPackage) -> Self {
+        Self::generate(required_modulus_bits(public_pkg))
+    }
+
+    pub fn public_key(&self) -> &PvssPublicKey {
+        &self.public
+    }
+
+    fn decrypt(&self, ciphertext: &BigUint) -> BigUint {
+        let n = &self.public.n;
+        let u = ciphertext.modpow(&self.lambda, &self.public.n_squared());
+        (((u - 1u8) / n) * &self.mu).mod_floor(n)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncryptedShare {
+    pub uid: usize,
+    pub gid: Option<usize>,
+    pub id: usize,
+    pub ciphertext: BigUint,
+    pub c: BigUint,
+    pub z: BigUint,
+    pub w: BigUint,
+}
+
+/// (1 + N)^x mod N^2 = 1 + xN mod N^2
+fn paillier_g_pow(x: &BigUint, n: &BigUint, n_squared: &BigUint) -> BigUint {
+    (BigUint::one() + x.mod_floor(n) * n).mod_floor(n_squared)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn challenge(
+    public_pkg: &PublicPackage,
+    vi: &BigUint,
+    recipient: &PvssPublicKey,
+    uid: usize,
+    gid: Option<usize>,
+    id: usize,
+    ciphertext: &BigUint,
+    t1: &BigUint,
+    t2: &BigUint,
+) -> BigUint {
+    let mut hasher = Sha256::new();
+    hasher.update(b"pretzel pvss v1");
+    hasher.update((uid as u64).to_be_bytes());
+    hasher.update([gid.is_some() as u8]);
+    hasher.update((gid.unwrap_or_default() as u64).to_be_bytes());
+    hasher.update((id as u64).to_be_bytes());
+    for value in [
+        public_pkg.public_key.n(),
+        &public_pkg.v,
+        vi,
+        &recipient.n,
+        ciphertext,
+        t1,
+        t2,
+    ] {
+        let bytes = value.to_bytes_be();
+        hasher.update((bytes.len() as u64).to_be_bytes());
+        hasher.update(bytes);
+    }
+    BigUint::from_bytes_be(&hasher.finalize())
+}
+
+fn verification_key(public_pkg: &PublicPackage, id: usize) -> Result<&BigUint, PvssError> {
+    public_pkg
+        .verification_keys
+        .iter()
+        .find(|vi: &&RsaVerificationKey| vi.id == id)
+        .map(|vi| &vi.key)
+        .ok_or(PvssError::MissingVerificationKey(id))
+}
+
+/// Encrypt a single package to the recipient together with the proof.
+pub fn encrypt_share(
+    secret_pkg: &SecretPackage,
+    public_pkg: &PublicPackage,
+    recipient: &PvssPublicKey,
+) -> Result<EncryptedShare, PvssError> {
+    if recipient.n.bits() < required_modulus_bits(public_pkg) {
+        return Err(PvssError::RecipientKeyTooSmall);
+    }
+    let share = &secret_pkg.share;
+    let vi = verification_key(public_pkg, share.id)?;
+    let n = public_pkg.public_key.n();
+    let big_n = &recipient.n;
+    let big_n_squared = recipient.n_squared();
+    let mut rng = ChaCha20Rng::from_entropy();
+    let mut random_unit = || loop {
+        let r = rng.gen_biguint_range(&BigUint::one(), big_n);
+        if r.gcd(big_n).is_one() {
+            return r;
+        }
+    };
+
+    let r = random_unit();
+    let ciphertext = (paillier_g_pow(&share.share, big_n, &big_n_squared)
+        * r.modpow(big_n, &big_n_squared))
+    .mod_floor(&big_n_squared);
+
+    let mut rho = ChaCha20Rng::from_entropy().gen_biguint(rho_bits(n));
+    let mu = random_unit();
+    let t1 = public_pkg.v.modpow(&rho, n);
+    let t2 = (paillier_g_pow(&rho, big_n, &big_n_squared) * mu.modpow(big_n, &big_n_squared))
+        .mod_floor(&big_n_squared);
+
+    let c = challenge(
+        public_pkg,
+        vi,
+        recipient,
+        secret_pkg.uid,
+        secret_pkg.gid,
+        share.id,
+        &ciphertext,
+        &t1,
+        &t2,
+    );
+    let z = &rho + &c * &share.share;
+    rho.zeroize();
+    let w = (mu * r.modpow(&c, big_n)).mod_floor(big_n);
+    Ok(EncryptedShare {
+        uid: secret_pkg.uid,
+        gid: secret_pkg.gid,
+        id: share.id,
+        ciphertext,
+        c,
+        z,
+        w,
+    })
+}
+
+/// The PVSS mode of the dealer, `secret_pkgs[i]` is encrypted to `recipients[i]`.
+pub fn encrypt_shares(
+    secret_pkgs: &[SecretPackage],
+    public_pkg: &PublicPackage,
+    recipients: &[PvssPublicKey],
+) -> Result<Vec<EncryptedShare>, PvssError> {
+    if secret_pkgs.len() != recipients.len() {
+        return Err(PvssError::RecipientCount);
+    }
+    secret_pkgs
+        .par_iter()
+        .zip(recipients)
+        .map(|(secret_pkg, recipient)| encrypt_share(secret_pkg, public_pkg, recipient))
+        .collect()
+}
+
+/// Check that the ciphertext holds the discrete logarithm of v_i, needs only public data.
+pub fn verify_encrypted_share(
+    public_pkg: &PublicPackage,
+    recipient: &PvssPublicKey,
+    share: &EncryptedShare,
+) -> bool {
+    if recipient.n.bits() < required_modulus_bits(public_pkg) {
+        return false;
+    }
+    let Ok(vi) = verification_key(public_pkg, share.id) else {
+        return false;
+    };
+    let n = public_pkg.public_key.n();
+    let big_n = &recipient.n;
+    let big_n_squared = recipient.n_squared();
+    if share.z.bits() > rho_bits(n) + 1
+        || share.c.bits() > CHALLENGE_BITS
+        || share.ciphertext >= big_n_squared
+        || &share.w >= big_n
+    {
+        return false;
+    }
+
+    let inverse = |value: BigUint, modulus: &BigUint| {
+        value
+            .mod_inverse(modulus)
+            .and_then(|inverse| inverse.to_biguint())
+    };
+    let Some(vi_c_inverse) = inverse(vi.modpow(&share.c, n), n) else {
+        return false;
+    };
+    let Some(ciphertext_c_inverse) = inverse(
+        share.ciphertext.modpow(&share.c, &big_n_squared),
+        &big_n_squared,
+    ) else {
+        return false;
+    };
+    let t1 = (public_pkg.v.modpow(&share.z, n) * vi_c_inverse).mod_floor(n);
+    let t2 = (paillier_g_pow(&share.z, big_n, &big_n_squared)
+        * share.w.modpow(big_n, &big_n_squared)
+        * ciphertext_c_inverse)
+        .mod_floor(&big_n_squared);
+
+    share.c
+        == challenge(
+            public_pkg,
+            vi,
+            recipient,
+            share.uid,
+            share.gid,
+            share.id,
+            &share.ciphertext,
+            &t1,
+            &t2,
+        )
+}
+
+/// Audit the whole dealer output, returns the indices of the invalid shares.
+pub fn verify_encrypted_shares(
+    public_pkg: &PublicPackage,
+    recipients: &[PvssPublicKey],
+    shares: &[EncryptedShare],
+) -> Result<(), Vec<usize>> {
+    if recipients.len() != shares.len() {
+        return Err((0..shares.len()).collect());
+    }
+    let invalid: Vec<usize> = shares
+        .par_iter()
+        .zip(recipients)
+        .enumerate()
+        .filter(|(_, (share, recipient))| !verify_encrypted_share(public_pkg, recipient, share))
+        .map(|(i, _)| i)
+        .collect();
+    if invalid.is_empty() {
+        Ok(())
+    } else {
+        Err(invalid)
+    }
+}
+
+/// Decrypt the share of the recipient, the proof is checked first.
+pub fn decrypt_share(
+    secret_key: &PvssSecretKey,
+    public_pkg: &PublicPackage,
+    share: &EncryptedShare,
+) -> Result<SecretPackage, PvssError> {
+    if !verify_encrypted_share(public_pkg, secret_key.public_key(), share) {
+        return Err(PvssError::InvalidProof);
+    }
+    let s = secret_key.decrypt(&share.ciphertext);
+    let n = public_pkg.public_key.n();
+    if &public_pkg.v.modpow(&s, n) != verification_key(public_pkg, share.id)? {
+        return Err(PvssError::InconsistentShare);
+    }
+    Ok(SecretPackage {
+        uid: share.uid,
+        gid: share.gid,
+        share: RsaSecretShare {
+            id: share.id,
+            n: n.clone(),
+            e: public_pkg.public_key.e().clone(),
+            key_bytes_size: public_pkg.public_key.size(),
+            share: s,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deal, load_key};
+
+    #[test]
+    fn that_auditor_verifies_and_recipients_decrypt_shares() {
+        let (mut secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 3, 2);
+        secret_pkgs[2].gid = Some(7);
+        let public_pkg = &public_pkgs[0];
+        let keys: Vec<PvssSecretKey> = (0..3)
+            .into_par_iter()
+            .map(|_| PvssSecretKey::generate_for(public_pkg))
+            .collect();
+        let recipients: Vec<PvssPublicKey> = keys.iter().map(|k| k.public_key().clone()).collect();
+
+        let shares = encrypt_shares(&secret_pkgs, public_pkg, &recipients).unwrap();
+        assert!(verify_encrypted_shares(public_pkg, &recipients, &shares).is_ok());
+        for (key, (share, secret_pkg)) in keys.iter().zip(shares.iter().zip(&secret_pkgs)) {
+            assert_eq!(&decrypt_share(key, public_pkg, share).unwrap(), secret_pkg);
+        }
+
+        // The group id is covered by the proof
+        let mut share = shares[2].clone();
+        share.gid = None;
+        assert!(!verify_encrypted_share(public_pkg, &recipients[2], &share));
+    }
+
+    #[test]
+    fn that_inconsistent_share_is_caught_by_the_auditor() {
+        let (mut secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 2, 2);
+        let public_pkg = &public_pkgs[0];
+        let keys: Vec<PvssSecretKey> = (0..2)
+            .into_par_iter()
+            .map(|_| PvssSecretKey::generate_for(public_pkg))
+            .collect();
+        let recipients: Vec<PvssPublicKey> = keys.iter().map(|k| k.public_key().clone()).collect();
+
+        secret_pkgs[1].share.share += 1u8;
+        let mut shares = encrypt_shares(&secret_pkgs, public_pkg, &recipients).unwrap();
+        assert_eq!(
+            verify_encrypted_shares(public_pkg, &recipients, &shares),
+            Err(vec![1])
+        );
+        assert!(matches!(
+            decrypt_share(&keys[1], public_pkg, &shares[1]),
+            Err(PvssError::InvalidProof)
+        ));
+
+        // A valid proof does not carry over to another recipient
+        shares.swap(0, 1);
+        assert!(!verify_encrypted_share(
+            public_pkg,
+            &recipients[1],
+            &shares[1]
+        ));
+        assert!(matches!(
+            encrypt_share(
+                &secret_pkgs[0],
+                public_pkg,
+                &PvssPublicKey {
+                    n: BigUint::from(35u8)
+                }
+            ),
+            Err(PvssError::RecipientKeyTooSmall)
+        ));
+    }
+}
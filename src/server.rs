@@ -0,0 +1,813 @@
+// Signer nodes and a coordinator talking over TCP.
+//
+// A `SignerNode` holds one `SecretPackage` and answers signing requests. The `Coordinator` sends
+// the request to all the signers at once, verifies the partial signatures as they arrive and
+// combines the first `threshold` valid ones. Every message is a CBOR encoded `SignedRequest` or
+// `Response` preceded by its length as a big-endian u32.
+//
+// `Coordinator::sign_optimistic` asks for the signature shares without the proofs first and falls
+// back to `Coordinator::sign` only when they do not combine, see `optimistic`.
+//
+// Every request is signed with the Ed25519 key of the coordinator, bound to the public package,
+// and a node answers only the coordinator it was configured with. It signs only the messages it
+// encodes itself (`KeyContext::check_remote_request`), never in the raw mode. The responses are
+// not encrypted, the partial signatures carry their proofs and are public anyway.
+
+use crate::context::KeyContext;
+use crate::optimistic::{CombineError, SignatureShare};
+use crate::session::{random_session_id, SessionId};
+use crate::transcript::ProofHash;
+use crate::{PaddingScheme, PartialMessageSignature, PublicPackage, SecretPackage};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use log::warn;
+use rsa::traits::PublicKeyParts;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::{spawn_blocking, JoinSet};
+use tokio::time::timeout;
+
+/// Frames above the limit are refused before reading them.
+const MAX_FRAME_LENGTH: usize = 1 << 20;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const REQUEST_CONTEXT: &[u8] = b"pretzel signing request v1";
+
+#[derive(Error, Debug, Serialize, Deserialize)]
+pub enum ServerError {
+    #[error("Network error: {0}")]
+    Io(String),
+    #[error("Malformed message: {0}")]
+    Protocol(String),
+    #[error("The signer has refused the request: {0}")]
+    Refused(String),
+    #[error("The share does not belong to the public package")]
+    ShareMismatch,
+    #[error("The request is not signed by the coordinator")]
+    Unauthenticated,
+    #[error("The threshold {threshold} is not between 1 and the {signers} signers")]
+    InvalidThreshold { threshold: usize, signers: usize },
+    #[error("Only {valid} of the {threshold} needed partial signatures were collected")]
+    NotEnoughPartialSignatures { valid: usize, threshold: usize },
+    #[error("The request has timed out")]
+    Timeout,
+    #[error("Signing failed")]
+    Signing,
+}
+
+impl From<std::io::Error> for ServerError {
+    fn from(e: std::io::Error) -> Self {
+        ServerError::Io(e.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Request {
+    /// The proof is bound to the session, it does not verify in another one
+    Sign {
+        session: SessionId,
+        message: Vec<u8>,
+        padding_scheme: PaddingScheme,
+    },
+    /// The optimistic mode, the share without the proof. The session is the one the coordinator
+    /// falls back to.
+    SignShare {
+        session: SessionId,
+        message: Vec<u8>,
+        padding_scheme: PaddingScheme,
+    },
+}
+
+/// The CBOR encoded `Request` with the signature of the coordinator over it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedRequest {
+    request: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl SignedRequest {
+    fn signed_bytes(fingerprint: &[u8; 32], request: &[u8]) -> Vec<u8> {
+        [REQUEST_CONTEXT, fingerprint, request].concat()
+    }
+
+    /// Sign the request for the signers of the key with the fingerprint.
+    pub fn new(
+        request: &Request,
+        fingerprint: &[u8; 32],
+        signing_key: &SigningKey,
+    ) -> Result<Self, ServerError> {
+        let mut encoded = Vec::new();
+        ciborium::ser::into_writer(request, &mut encoded)
+            .map_err(|e| ServerError::Protocol(e.to_string()))?;
+        let signature = signing_key.sign(&Self::signed_bytes(fingerprint, &encoded));
+        Ok(SignedRequest {
+            request: encoded,
+            signature: signature.to_vec(),
+        })
+    }
+
+    /// The request, if it is signed by the coordinator for the key with the fingerprint.
+    pub fn open(
+        &self,
+        fingerprint: &[u8; 32],
+        coordinator: &VerifyingKey,
+    ) -> Result<Request, ServerError> {
+        let signature =
+            Signature::from_slice(&self.signature).map_err(|_| ServerError::Unauthenticated)?;
+        coordinator
+            .verify(&Self::signed_bytes(fingerprint, &self.request), &signature)
+            .map_err(|_| ServerError::Unauthenticated)?;
+        ciborium::de::from_reader(self.request.as_slice())
+            .map_err(|e| ServerError::Protocol(e.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Response {
+    PartialSignature(Box<PartialMessageSignature>),
+    SignatureShare(SignatureShare),
+    Error(String),
+}
+
+pub async fn write_frame<W, T>(writer: &mut W, message: &T) -> Result<(), ServerError>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let mut body = Vec::new();
+    ciborium::ser::into_writer(message, &mut body)
+        .map_err(|e| ServerError::Protocol(e.to_string()))?;
+    if body.len() > MAX_FRAME_LENGTH {
+        return Err(ServerError::Protocol("the message is too long".into()));
+    }
+    writer.write_u32(body.len() as u32).await?;
+    writer.write_all(&body).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Returns `None` when the peer has closed the connection between two frames.
+pub async fn read_frame<R, T>(reader: &mut R) -> Result<Option<T>, ServerError>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let length = match reader.read_u32().await {
+        Ok(length) => length as usize,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if length > MAX_FRAME_LENGTH {
+        return Err(ServerError::Protocol("the message is too long".into()));
+    }
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body).await?;
+    ciborium::de::from_reader(body.as_slice())
+        .map(Some)
+        .map_err(|e| ServerError::Protocol(e.to_string()))
+}
+
+/// Holds one share and signs the messages the coordinator asks for.
+#[derive(Clone)]
+pub struct SignerNode {
+    secret_pkg: Arc<SecretPackage>,
+    context: Arc<KeyContext>,
+    coordinator: VerifyingKey,
+    timeout: Duration,
+}
+
+impl SignerNode {
+    pub fn new(
+        secret_pkg: SecretPackage,
+        public_pkg: &PublicPackage,
+        coordinator: VerifyingKey,
+    ) -> Result<Self, ServerError> {
+        let n = public_pkg.public_key.n();
+        let share = &secret_pkg.share;
+        let consistent = public_pkg
+            .verification_keys
+            .iter()
+            .any(|vi| vi.id == share.id && public_pkg.v.modpow(&share.share, n) == vi.key);
+        if !consistent || &share.n != n {
+            return Err(ServerError::ShareMismatch);
+        }
+        Ok(SignerNode {
+            context: Arc::new(KeyContext::new(public_pkg).map_err(|_| ServerError::Signing)?),
+            secret_pkg: Arc::new(secret_pkg),
+            coordinator,
+            timeout: DEFAULT_TIMEOUT,
+        })
+    }
+
+    /// Time limit for the coordinator to send the next request, an idle connection is closed.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The hash of the proofs, the coordinator has to require the same one.
+    pub fn with_proof_hash(mut self, hash: ProofHash) -> Self {
+        self.context = Arc::new(KeyContext::clone(&self.context).with_proof_hash(hash));
+        self
+    }
+
+    /// Accept connections until the listener fails, every connection is served by its own task.
+    pub async fn serve(self, listener: TcpListener) -> Result<(), ServerError> {
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let node = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = node.handle(stream).await {
+                    warn!("The connection with {peer} has failed: {e}");
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, mut stream: TcpStream) -> Result<(), ServerError> {
+        while let Some(request) = timeout(self.timeout, read_frame::<_, SignedRequest>(&mut stream))
+            .await
+            .map_err(|_| ServerError::Timeout)??
+        {
+            let request = match request.open(self.context.fingerprint(), &self.coordinator) {
+                Ok(request) => request,
+                Err(e) => {
+                    write_frame(&mut stream, &Response::Error(e.to_string())).await?;
+                    return Err(e);
+                }
+            };
+            let (Request::Sign {
+                message,
+                padding_scheme,
+                ..
+            }
+            | Request::SignShare {
+                message,
+                padding_scheme,
+                ..
+            }) = &request;
+            if let Err(e) = self.context.check_remote_request(message, *padding_scheme) {
+                write_frame(&mut stream, &Response::Error(e.to_string())).await?;
+                continue;
+            }
+            let response = match request {
+                Request::Sign {
+                    session,
+                    message,
+                    padding_scheme,
+                } => {
+                    let node = self.clone();
+                    // The exponentiations would block the other connections
+                    spawn_blocking(move || {
+                        node.context.sign_in_session(
+                            &node.secret_pkg,
+                            &message,
+                            padding_scheme,
+                            &session,
+                        )
+                    })
+                    .await
+                    .map_err(|_| ServerError::Signing)?
+                    .map_or_else(
+                        |e| Response::Error(e.to_string()),
+                        |partial| Response::PartialSignature(Box::new(partial)),
+                    )
+                }
+                Request::SignShare {
+                    message,
+                    padding_scheme,
+                    ..
+                } => {
+                    let node = self.clone();
+                    spawn_blocking(move || {
+                        node.context
+                            .sign_share(&node.secret_pkg, &message, padding_scheme)
+                    })
+                    .await
+                    .map_err(|_| ServerError::Signing)?
+                    .map_or_else(|e| Response::Error(e.to_string()), Response::SignatureShare)
+                }
+            };
+            write_frame(&mut stream, &response).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Collects the partial signatures from the signer nodes and combines them.
+pub struct Coordinator {
+    context: Arc<KeyContext>,
+    signing_key: SigningKey,
+    signers: Vec<SocketAddr>,
+    threshold: usize,
+    timeout: Duration,
+}
+
+impl Coordinator {
+    /// The nodes have to know the verifying key of `signing_key`.
+    pub fn new(
+        public_pkg: &PublicPackage,
+        signing_key: SigningKey,
+        signers: Vec<SocketAddr>,
+        threshold: usize,
+    ) -> Result<Self, ServerError> {
+        if threshold == 0 || threshold > signers.len() {
+            return Err(ServerError::InvalidThreshold {
+                threshold,
+                signers: signers.len(),
+            });
+        }
+        Ok(Coordinator {
+            context: Arc::new(KeyContext::new(public_pkg).map_err(|_| ServerError::Signing)?),
+            signing_key,
+            signers,
+            threshold,
+            timeout: DEFAULT_TIMEOUT,
+        })
+    }
+
+    fn signed(&self, request: &Request) -> Result<SignedRequest, ServerError> {
+        SignedRequest::new(request, self.context.fingerprint(), &self.signing_key)
+    }
+
+    /// The hash the proofs of the nodes have to use, the default is SHA-256.
+    pub fn with_proof_hash(mut self, hash: ProofHash) -> Self {
+        self.context = Arc::new(KeyContext::clone(&self.context).with_proof_hash(hash));
+        self
+    }
+
+    /// Time limit for a single signer, including the connection.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    async fn request(
+        address: SocketAddr,
+        request: &SignedRequest,
+    ) -> Result<Response, ServerError> {
+        let mut stream = TcpStream::connect(address).await?;
+        write_frame(&mut stream, request).await?;
+        match read_frame(&mut stream).await? {
+            Some(Response::Error(e)) => Err(ServerError::Refused(e)),
+            Some(response) => Ok(response),
+            None => Err(ServerError::Protocol(
+                "the signer has closed the connection".into(),
+            )),
+        }
+    }
+
+    async fn request_partial_signature(
+        address: SocketAddr,
+        request: &SignedRequest,
+    ) -> Result<PartialMessageSignature, ServerError> {
+        match Self::request(address, request).await? {
+            Response::PartialSignature(partial) => Ok(*partial),
+            _ => Err(ServerError::Protocol("unexpected response".into())),
+        }
+    }
+
+    async fn request_signature_share(
+        address: SocketAddr,
+        request: &SignedRequest,
+    ) -> Result<SignatureShare, ServerError> {
+        match Self::request(address, request).await? {
+            Response::SignatureShare(share) => Ok(share),
+            _ => Err(ServerError::Protocol("unexpected response".into())),
+        }
+    }
+
+    /// Combine the first `threshold` shares without their proofs, `sign` when they do not combine.
+    pub async fn sign_optimistic(
+        &self,
+        message: &[u8],
+        padding_scheme: PaddingScheme,
+    ) -> Result<Vec<u8>, ServerError> {
+        let session = random_session_id();
+        let request = Arc::new(self.signed(&Request::SignShare {
+            session,
+            message: message.to_vec(),
+            padding_scheme,
+        })?);
+        let mut requests = JoinSet::new();
+        for &address in &self.signers {
+            let request = request.clone();
+            let limit = self.timeout;
+            requests.spawn(async move {
+                timeout(limit, Self::request_signature_share(address, &request))
+                    .await
+                    .map_err(|_| ServerError::Timeout)?
+            });
+        }
+
+        let mut ids = HashSet::new();
+        let mut shares = Vec::with_capacity(self.threshold);
+        while let Some(result) = requests.join_next().await {
+            match result.map_err(|_| ServerError::Signing)? {
+                Ok(share) => {
+                    if ids.insert(share.id) {
+                        shares.push(share);
+                    }
+                }
+                Err(e) => warn!("A signer has failed: {e}"),
+            }
+            if shares.len() == self.threshold {
+                requests.abort_all();
+                break;
+            }
+        }
+        if shares.len() < self.threshold {
+            return Err(ServerError::NotEnoughPartialSignatures {
+                valid: shares.len(),
+                threshold: self.threshold,
+            });
+        }
+
+        let context = self.context.clone();
+        let encoded = message.to_vec();
+        let combined =
+            spawn_blocking(move || context.combine_optimistic(&encoded, &shares, padding_scheme))
+                .await
+                .map_err(|_| ServerError::Signing)?;
+        match combined {
+            Ok(signature) => Ok(signature),
+            Err(CombineError::InvalidSignature) => {
+                warn!("The signature shares do not combine, falling back to the proofs");
+                self.sign_in_session(session, message, padding_scheme).await
+            }
+            Err(_) => Err(ServerError::Signing),
+        }
+    }
+
+    /// Sign with the first `threshold` signers that answer with a valid partial signature, the
+    /// proofs are bound to a fresh session.
+    pub async fn sign(
+        &self,
+        message: &[u8],
+        padding_scheme: PaddingScheme,
+    ) -> Result<Vec<u8>, ServerError> {
+        self.sign_in_session(random_session_id(), message, padding_scheme)
+            .await
+    }
+
+    async fn sign_in_session(
+        &self,
+        session: SessionId,
+        message: &[u8],
+        padding_scheme: PaddingScheme,
+    ) -> Result<Vec<u8>, ServerError> {
+        let request = Arc::new(self.signed(&Request::Sign {
+            session,
+            message: message.to_vec(),
+            padding_scheme,
+        })?);
+        let mut requests = JoinSet::new();
+        for &address in &self.signers {
+            let request = request.clone();
+            let context = self.context.clone();
+            let message = message.to_vec();
+            let limit = self.timeout;
+            requests.spawn(async move {
+                let partial = timeout(limit, Self::request_partial_signature(address, &request))
+                    .await
+                    .map_err(|_| ServerError::Timeout)??;
+                let (valid, partial) = spawn_blocking(move || {
+                    (
+                        context.verify_proof_in_session(
+                            &message,
+                            &partial,
+                            padding_scheme,
+                            &session,
+                        ),
+                        partial,
+                    )
+                })
+                .await
+                .map_err(|_| ServerError::Signing)?;
+                Ok::<_, ServerError>((address, valid, partial))
+            });
+        }
+
+        let mut ids = HashSet::new();
+        let mut partials = Vec::with_capacity(self.threshold);
+        while let Some(result) = requests.join_next().await {
+            match result.map_err(|_| ServerError::Signing)? {
+                Ok((_, true, partial)) => {
+                    if ids.insert(partial.id) {
+                        partials.push(partial);
+                    }
+                }
+                Ok((address, false, _)) => {
+                    warn!("The partial signature of {address} is invalid")
+                }
+                Err(e) => warn!("A signer has failed: {e}"),
+            }
+            if partials.len() == self.threshold {
+                // The slower signers are not needed anymore
+                requests.abort_all();
+                let context = self.context.clone();
+                let message = message.to_vec();
+                return spawn_blocking(move || {
+                    context.combine_shares(&message, partials, padding_scheme)
+                })
+                .await
+                .map_err(|_| ServerError::Signing)?
+                .map_err(|_| ServerError::Signing);
+            }
+        }
+        Err(ServerError::NotEnoughPartialSignatures {
+            valid: partials.len(),
+            threshold: self.threshold,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deal, load_key};
+    use rand_chacha::{rand_core::SeedableRng, ChaCha20Rng};
+    use rsa::Pkcs1v15Sign;
+
+    fn coordinator_key() -> SigningKey {
+        SigningKey::generate(&mut ChaCha20Rng::from_entropy())
+    }
+
+    async fn spawn_signers(
+        secret_pkgs: Vec<SecretPackage>,
+        public_pkg: &PublicPackage,
+        coordinator: &SigningKey,
+    ) -> Vec<SocketAddr> {
+        let mut addresses = Vec::new();
+        for secret_pkg in secret_pkgs {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addresses.push(listener.local_addr().unwrap());
+            let node =
+                SignerNode::new(secret_pkg, public_pkg, coordinator.verifying_key()).unwrap();
+            tokio::spawn(node.serve(listener));
+        }
+        addresses
+    }
+
+    #[tokio::test]
+    async fn that_coordinator_combines_partial_signatures_from_nodes() {
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 3, 2);
+        let public_pkg = &public_pkgs[0];
+        let key = coordinator_key();
+        let signers = spawn_signers(secret_pkgs, public_pkg, &key).await;
+
+        let coordinator = Coordinator::new(public_pkg, key, signers, 2).unwrap();
+        let message = b"ABC";
+        let signature = coordinator
+            .sign(message, PaddingScheme::PKCS1v15)
+            .await
+            .unwrap();
+        assert!(public_pkg
+            .public_key
+            .verify(Pkcs1v15Sign::new_unprefixed(), message, &signature)
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn that_optimistic_coordinator_falls_back_to_the_proofs() {
+        let (mut secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 3, 2);
+        let public_pkg = &public_pkgs[0];
+        let key = coordinator_key();
+        let signers = spawn_signers(secret_pkgs.clone(), public_pkg, &key).await;
+        let coordinator = Coordinator::new(public_pkg, key.clone(), signers, 2).unwrap();
+        let signature = coordinator
+            .sign_optimistic(b"ABC", PaddingScheme::PKCS1v15)
+            .await
+            .unwrap();
+        assert!(public_pkg
+            .public_key
+            .verify(Pkcs1v15Sign::new_unprefixed(), b"ABC", &signature)
+            .is_ok());
+
+        // A wrong share spoils the combination, the proofs then find it
+        let mut cheater = secret_pkgs.remove(0);
+        cheater.share.share += 1u8;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut signers = vec![listener.local_addr().unwrap()];
+        tokio::spawn(
+            SignerNode {
+                secret_pkg: Arc::new(cheater),
+                context: Arc::new(KeyContext::new(public_pkg).unwrap()),
+                coordinator: key.verifying_key(),
+                timeout: DEFAULT_TIMEOUT,
+            }
+            .serve(listener),
+        );
+        signers.extend(spawn_signers(vec![secret_pkgs[0].clone()], public_pkg, &key).await);
+        // The last signer answers late, so the share of the cheater is among the first two and
+        // the fallback has to leave it out
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        signers.push(listener.local_addr().unwrap());
+        let late =
+            SignerNode::new(secret_pkgs[1].clone(), public_pkg, key.verifying_key()).unwrap();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            late.serve(listener).await
+        });
+        let coordinator = Coordinator::new(public_pkg, key.clone(), signers.clone(), 2).unwrap();
+        let signature = coordinator
+            .sign_optimistic(b"ABC", PaddingScheme::PKCS1v15)
+            .await
+            .unwrap();
+        assert!(public_pkg
+            .public_key
+            .verify(Pkcs1v15Sign::new_unprefixed(), b"ABC", &signature)
+            .is_ok());
+
+        // Without the late signer only one honest one is left
+        signers.pop();
+        let coordinator = Coordinator::new(public_pkg, key, signers, 2).unwrap();
+        assert!(matches!(
+            coordinator
+                .sign_optimistic(b"ABC", PaddingScheme::PKCS1v15)
+                .await,
+            Err(ServerError::NotEnoughPartialSignatures {
+                valid: 1,
+                threshold: 2
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn that_coordinator_requires_its_proof_hash() {
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 3, 2);
+        let public_pkg = &public_pkgs[0];
+        let key = coordinator_key();
+        let mut signers = Vec::new();
+        for secret_pkg in secret_pkgs {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            signers.push(listener.local_addr().unwrap());
+            let node = SignerNode::new(secret_pkg, public_pkg, key.verifying_key())
+                .unwrap()
+                .with_proof_hash(ProofHash::Sha3_256);
+            tokio::spawn(node.serve(listener));
+        }
+
+        let coordinator = Coordinator::new(public_pkg, key.clone(), signers.clone(), 2)
+            .unwrap()
+            .with_proof_hash(ProofHash::Sha3_256);
+        assert!(coordinator
+            .sign(b"ABC", PaddingScheme::PKCS1v15)
+            .await
+            .is_ok());
+        let coordinator = Coordinator::new(public_pkg, key, signers, 2).unwrap();
+        assert!(matches!(
+            coordinator.sign(b"ABC", PaddingScheme::PKCS1v15).await,
+            Err(ServerError::NotEnoughPartialSignatures {
+                valid: 0,
+                threshold: 2
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn that_coordinator_tolerates_unreachable_signers() {
+        let (mut secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 3, 2);
+        let public_pkg = &public_pkgs[0];
+        secret_pkgs.pop();
+        let key = coordinator_key();
+        let mut signers = spawn_signers(secret_pkgs, public_pkg, &key).await;
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        signers.push(closed.local_addr().unwrap());
+        drop(closed);
+
+        let coordinator = Coordinator::new(public_pkg, key.clone(), signers.clone(), 2)
+            .unwrap()
+            .with_timeout(Duration::from_secs(10));
+        assert!(coordinator
+            .sign(b"ABC", PaddingScheme::PKCS1v15)
+            .await
+            .is_ok());
+
+        let coordinator = Coordinator::new(public_pkg, key, signers, 3).unwrap();
+        assert!(matches!(
+            coordinator.sign(b"ABC", PaddingScheme::PKCS1v15).await,
+            Err(ServerError::NotEnoughPartialSignatures {
+                valid: 2,
+                threshold: 3
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn that_idle_connections_and_impossible_thresholds_are_refused() {
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 2, 2);
+        let public_pkg = &public_pkgs[0];
+        let key = coordinator_key();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let node = SignerNode::new(secret_pkgs[0].clone(), public_pkg, key.verifying_key())
+            .unwrap()
+            .with_timeout(Duration::from_millis(100));
+        tokio::spawn(node.serve(listener));
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let mut buffer = [0u8; 1];
+        let closed = timeout(Duration::from_secs(10), stream.read(&mut buffer)).await;
+        assert!(matches!(closed, Ok(Ok(0)) | Ok(Err(_))));
+
+        for threshold in [0, 2] {
+            assert!(matches!(
+                Coordinator::new(public_pkg, key.clone(), vec![address], threshold),
+                Err(ServerError::InvalidThreshold { signers: 1, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn that_foreign_share_is_refused() {
+        let (secret_pkgs, _) = deal(&load_key().unwrap(), 2, 2);
+        let (_, other_public_pkgs) = deal(&load_key().unwrap(), 2, 2);
+        assert!(matches!(
+            SignerNode::new(
+                secret_pkgs[0].clone(),
+                &other_public_pkgs[0],
+                coordinator_key().verifying_key()
+            ),
+            Err(ServerError::ShareMismatch)
+        ));
+    }
+
+    #[tokio::test]
+    async fn that_node_signs_only_encoded_messages_of_its_coordinator() {
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 2, 2);
+        let (_, other_public_pkgs) = deal(&load_key().unwrap(), 2, 2);
+        let key = coordinator_key();
+        let signer = spawn_signers(secret_pkgs, &public_pkgs[0], &key).await[0];
+        let fingerprint = public_pkgs[0].fingerprint();
+        let too_long = vec![1; public_pkgs[0].public_key.size() - 10];
+        let request = |message: &[u8], padding_scheme| Request::Sign {
+            session: random_session_id(),
+            message: message.to_vec(),
+            padding_scheme,
+        };
+        let send = |request: Request, fingerprint: [u8; 32], key: SigningKey| async move {
+            let request = SignedRequest::new(&request, &fingerprint, &key).unwrap();
+            Coordinator::request(signer, &request).await
+        };
+
+        let session = random_session_id();
+        let sign = Request::Sign {
+            session,
+            message: b"ABC".to_vec(),
+            padding_scheme: PaddingScheme::PKCS1v15,
+        };
+        let Ok(Response::PartialSignature(partial)) = send(sign, fingerprint, key.clone()).await
+        else {
+            panic!("the request has been refused");
+        };
+        // The proof is bound to the session
+        let context = KeyContext::new(&public_pkgs[0]).unwrap();
+        let verify = |session| {
+            context.verify_proof_in_session(b"ABC", &partial, PaddingScheme::PKCS1v15, &session)
+        };
+        assert!(verify(session));
+        assert!(!verify(random_session_id()));
+        // The raw mode would be an RSA oracle
+        assert!(matches!(
+            send(
+                request(&[1; 64], PaddingScheme::NONE),
+                fingerprint,
+                key.clone()
+            )
+            .await,
+            Err(ServerError::Refused(_))
+        ));
+        assert!(matches!(
+            send(
+                request(&too_long, PaddingScheme::PKCS1v15),
+                fingerprint,
+                key.clone()
+            )
+            .await,
+            Err(ServerError::Refused(_))
+        ));
+        assert!(matches!(
+            send(
+                request(b"ABC", PaddingScheme::PKCS1v15),
+                other_public_pkgs[0].fingerprint(),
+                key
+            )
+            .await,
+            Err(ServerError::Refused(_))
+        ));
+        assert!(matches!(
+            send(
+                request(b"ABC", PaddingScheme::PKCS1v15),
+                fingerprint,
+                coordinator_key()
+            )
+            .await,
+            Err(ServerError::Refused(_))
+        ));
+    }
+}
@@ -0,0 +1,260 @@
+// Signing sessions, the proofs of correctness bound to the key, the session and the signer.
+//
+// The `SigningContext` is hashed in front of the values of the Fiat-Shamir challenge, so a proof
+// made for one key, session or signer does not verify for another one. The verifier builds the
+// context on its own from the public package and the session id agreed for the signing.
+
+use crate::transcript::{ProofPolicy, ProofVersion};
+use crate::{
+    check_raw_proof, digest_msg, prove_raw_share, PaddingScheme, PartialMessageSignature,
+    PublicPackage, RsaSecretShare, RsaVerificationKey, SigningError,
+};
+use num_bigint::BigUint;
+use rand::RngCore;
+use rand_chacha::{rand_core::SeedableRng, ChaCha20Rng};
+use rsa::traits::PublicKeyParts;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub const PROTOCOL_VERSION: u16 = 1;
+const DOMAIN: &[u8; 20] = b"pretzel-shoup-proof\0";
+
+pub type SessionId = [u8; 32];
+
+/// A fresh random session id, the coordinator hands it to all the signers.
+pub fn random_session_id() -> SessionId {
+    let mut session_id = [0u8; 32];
+    ChaCha20Rng::from_entropy().fill_bytes(&mut session_id);
+    session_id
+}
+
+impl PublicPackage {
+    /// SHA-256 over the modulus, the exponents, v and all the verification keys.
+    pub fn fingerprint(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(b"pretzel-key-fingerprint");
+        let mut update = |value: &BigUint| {
+            let bytes = value.to_bytes_be();
+            hasher.update((bytes.len() as u64).to_be_bytes());
+            hasher.update(bytes);
+        };
+        update(self.public_key.n());
+        update(self.public_key.e());
+        update(&self.v);
+        for vi in &self.verification_keys {
+            update(&BigUint::from(vi.id));
+            update(&vi.key);
+        }
+        hasher.update((self.group_size as u64).to_be_bytes());
+        hasher.finalize().into()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SigningContext {
+    pub key_fingerprint: [u8; 32],
+    pub session_id: SessionId,
+    /// The id of the share, i.e. `RsaSecretShare::id`
+    pub signer_id: usize,
+    pub protocol_version: u16,
+}
+
+impl SigningContext {
+    pub fn new(public_pkg: &PublicPackage, session_id: SessionId, signer_id: usize) -> Self {
+        Self::with_fingerprint(public_pkg.fingerprint(), session_id, signer_id)
+    }
+
+    /// For callers that keep the fingerprint around, see `KeyContext`.
+    pub fn with_fingerprint(
+        key_fingerprint: [u8; 32],
+        session_id: SessionId,
+        signer_id: usize,
+    ) -> Self {
+        SigningContext {
+            key_fingerprint,
+            session_id,
+            signer_id,
+            protocol_version: PROTOCOL_VERSION,
+        }
+    }
+
+    /// domain | version | fingerprint | session id | signer id, all of a fixed length
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(DOMAIN.len() + 2 + 32 + 32 + 8);
+        bytes.extend_from_slice(DOMAIN);
+        bytes.extend_from_slice(&self.protocol_version.to_be_bytes());
+        bytes.extend_from_slice(&self.key_fingerprint);
+        bytes.extend_from_slice(&self.session_id);
+        bytes.extend_from_slice(&(self.signer_id as u64).to_be_bytes());
+        bytes
+    }
+}
+
+/// `sign_with_share` with the proof bound to the context.
+pub fn sign_with_share_in_context(
+    msg: &[u8],
+    delta: usize,
+    share: &RsaSecretShare,
+    v: &BigUint,
+    vi: &RsaVerificationKey,
+    scheme: PaddingScheme,
+    context: &SigningContext,
+) -> Result<PartialMessageSignature, SigningError> {
+    if context.signer_id != share.id || vi.id != share.id {
+        return Err(SigningError::SigningError);
+    }
+    let x = digest_msg(msg, scheme, &share.n, share.key_bytes_size);
+    Ok(prove_raw_share(
+        &x,
+        delta,
+        share,
+        v,
+        vi,
+        Some(context),
+        ProofVersion::CURRENT,
+    ))
+}
+
+/// `verify_proof` of a proof made by `sign_with_share_in_context`.
+#[allow(clippy::too_many_arguments)]
+pub fn verify_proof_in_context(
+    msg: &[u8],
+    v: &BigUint,
+    delta: usize,
+    vi: &RsaVerificationKey,
+    pms: &PartialMessageSignature,
+    n: &BigUint,
+    key_bytes_size: usize,
+    scheme: PaddingScheme,
+    context: &SigningContext,
+) -> bool {
+    if context.protocol_version != PROTOCOL_VERSION
+        || context.signer_id != pms.id
+        || vi.id != pms.id
+    {
+        return false;
+    }
+    let x = digest_msg(msg, scheme, n, key_bytes_size);
+    check_raw_proof(
+        &x,
+        v,
+        delta,
+        vi,
+        pms,
+        n,
+        Some(context),
+        &ProofPolicy::default(),
+    )
+}
+
+#[cfg(test)]
+#[allow(deprecated)]
+mod tests {
+    use super::*;
+    use crate::context::KeyContext;
+    use crate::{deal, factorial, load_key, verify_proof};
+
+    #[test]
+    fn that_proof_verifies_only_in_its_session() {
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 3, 2);
+        let public_pkg = &public_pkgs[0];
+        let share = &secret_pkgs[1].share;
+        let vi = &public_pkg.verification_keys[1];
+        let delta = factorial(3);
+        let n = public_pkg.public_key.n();
+        let size = public_pkg.public_key.size();
+        let session_id = random_session_id();
+        let context = SigningContext::new(public_pkg, session_id, share.id);
+
+        let pms = sign_with_share_in_context(
+            b"ABC",
+            delta,
+            share,
+            &public_pkg.v,
+            vi,
+            PaddingScheme::PKCS1v15,
+            &context,
+        )
+        .unwrap();
+        let verify = |context: &SigningContext| {
+            verify_proof_in_context(
+                b"ABC",
+                &public_pkg.v,
+                delta,
+                vi,
+                &pms,
+                n,
+                size,
+                PaddingScheme::PKCS1v15,
+                context,
+            )
+        };
+        assert!(verify(&context));
+        assert!(!verify(&SigningContext::new(
+            public_pkg,
+            random_session_id(),
+            share.id
+        )));
+        assert!(!verify(&SigningContext::with_fingerprint(
+            [0u8; 32], session_id, share.id
+        )));
+        // Nor is it a proof without any context
+        assert!(!verify_proof(
+            b"ABC",
+            &public_pkg.v,
+            delta,
+            vi,
+            &pms,
+            n,
+            size,
+            PaddingScheme::PKCS1v15
+        ));
+    }
+
+    #[test]
+    fn that_signer_id_is_bound() {
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 3, 2);
+        let public_pkg = &public_pkgs[0];
+        let session_id = random_session_id();
+        let context = SigningContext::new(public_pkg, session_id, 2);
+        assert!(sign_with_share_in_context(
+            b"ABC",
+            factorial(3),
+            &secret_pkgs[0].share,
+            &public_pkg.v,
+            &public_pkg.verification_keys[0],
+            PaddingScheme::PKCS1v15,
+            &context,
+        )
+        .is_err());
+
+        let key_context = KeyContext::new(public_pkg).unwrap();
+        let mut pms = key_context
+            .sign_in_session(
+                &secret_pkgs[1],
+                b"ABC",
+                PaddingScheme::PKCS1v15,
+                &session_id,
+            )
+            .unwrap();
+        assert!(key_context.verify_proof_in_session(
+            b"ABC",
+            &pms,
+            PaddingScheme::PKCS1v15,
+            &session_id
+        ));
+        assert!(!key_context.verify_proof_in_session(
+            b"ABC",
+            &pms,
+            PaddingScheme::PKCS1v15,
+            &random_session_id()
+        ));
+        pms.id = 3;
+        assert!(!key_context.verify_proof_in_session(
+            b"ABC",
+            &pms,
+            PaddingScheme::PKCS1v15,
+            &session_id
+        ));
+    }
+}
@@ -0,0 +1,400 @@
+// Confidential distribution of the dealer output through an untrusted relay.
+//
+// Every `SecretPackage` is encrypted to the X25519 key of its owner with HPKE (RFC 9180) in the
+// base mode with DHKEM(X25519, HKDF-SHA256), HKDF-SHA256 and ChaCha20-Poly1305. The bundle of the
+// ciphertexts and the `PublicPackage` is signed with the Ed25519 key of the dealer.
+
+use crate::format::{FormatError, Versioned};
+use crate::{PublicPackage, SecretPackage};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use hkdf::Hkdf;
+use rand_chacha::{rand_core::SeedableRng, ChaCha20Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+const BUNDLE_VERSION: u16 = 1;
+const HPKE_INFO: &[u8] = b"pretzel share transport v1";
+const SIGNATURE_CONTEXT: &[u8] = b"pretzel share bundle v1";
+
+#[derive(Error, Debug, Serialize, Deserialize)]
+pub enum ShareBundleError {
+    #[error("The numbers of packages and recipients differ")]
+    RecipientCount,
+    #[error("Unsupported version {0} of the share bundle")]
+    UnsupportedVersion(u16),
+    #[error("The bundle is not signed by the dealer")]
+    InvalidSignature,
+    #[error("The bundle holds no package for the recipient")]
+    NoPackageForRecipient,
+    #[error("The package cannot be encrypted to the recipient key")]
+    Encryption,
+    #[error("The package cannot be decrypted")]
+    Decryption,
+    #[error("The package does not belong to the signer it was sent to")]
+    MismatchedPackage,
+    #[error(transparent)]
+    Format(#[from] FormatError),
+}
+
+/// RFC 9180 in the base mode for the single suite used here.
+mod hpke {
+    use super::*;
+
+    const KEM_ID: u16 = 0x0020;
+    const KDF_ID: u16 = 0x0001;
+    const AEAD_ID: u16 = 0x0003;
+
+    fn kem_suite_id() -> Vec<u8> {
+        [b"KEM".as_slice(), &KEM_ID.to_be_bytes()].concat()
+    }
+
+    fn hpke_suite_id() -> Vec<u8> {
+        [
+            b"HPKE".as_slice(),
+            &KEM_ID.to_be_bytes(),
+            &KDF_ID.to_be_bytes(),
+            &AEAD_ID.to_be_bytes(),
+        ]
+        .concat()
+    }
+
+    fn labeled_extract(suite_id: &[u8], salt: &[u8], label: &[u8], ikm: &[u8]) -> Vec<u8> {
+        let labeled_ikm = Zeroizing::new([b"HPKE-v1".as_slice(), suite_id, label, ikm].concat());
+        Hkdf::<Sha256>::extract(Some(salt), &labeled_ikm).0.to_vec()
+    }
+
+    fn labeled_expand(
+        suite_id: &[u8],
+        prk: &[u8],
+        label: &[u8],
+        info: &[u8],
+        length: usize,
+    ) -> Zeroizing<Vec<u8>> {
+        let labeled_info = [
+            &(length as u16).to_be_bytes(),
+            b"HPKE-v1".as_slice(),
+            suite_id,
+            label,
+            info,
+        ]
+        .concat();
+        let mut okm = Zeroizing::new(vec![0u8; length]);
+        Hkdf::<Sha256>::from_prk(prk)
+            .expect("the PRK has the length of the hash")
+            .expand(&labeled_info, &mut okm)
+            .expect("the length is valid for HKDF-SHA256");
+        okm
+    }
+
+    fn shared_secret(dh: &[u8; 32], enc: &[u8; 32], pk_r: &[u8; 32]) -> Zeroizing<Vec<u8>> {
+        let suite_id = kem_suite_id();
+        let eae_prk = Zeroizing::new(labeled_extract(&suite_id, b"", b"eae_prk", dh));
+        let kem_context = [enc.as_slice(), pk_r].concat();
+        labeled_expand(&suite_id, &eae_prk, b"shared_secret", &kem_context, 32)
+    }
+
+    /// The key and the nonce of the first message, the only one sent in a context here
+    fn key_schedule(shared_secret: &[u8], info: &[u8]) -> (Zeroizing<Vec<u8>>, Vec<u8>) {
+        let suite_id = hpke_suite_id();
+        let psk_id_hash = labeled_extract(&suite_id, b"", b"psk_id_hash", b"");
+        let info_hash = labeled_extract(&suite_id, b"", b"info_hash", info);
+        let context = [[0u8].as_slice(), &psk_id_hash, &info_hash].concat();
+        let secret = Zeroizing::new(labeled_extract(&suite_id, shared_secret, b"secret", b""));
+        let key = labeled_expand(&suite_id, &secret, b"key", &context, 32);
+        let nonce = labeled_expand(&suite_id, &secret, b"base_nonce", &context, 12).to_vec();
+        (key, nonce)
+    }
+
+    /// Returns the encapsulated key and the ciphertext.
+    pub(super) fn seal(
+        pk_r: &PublicKey,
+        info: &[u8],
+        aad: &[u8],
+        plaintext: &[u8],
+    ) -> Option<([u8; 32], Vec<u8>)> {
+        let sk_e = StaticSecret::random_from_rng(ChaCha20Rng::from_entropy());
+        let enc = PublicKey::from(&sk_e).to_bytes();
+        let dh = sk_e.diffie_hellman(pk_r);
+        if !dh.was_contributory() {
+            return None;
+        }
+        let shared_secret = shared_secret(dh.as_bytes(), &enc, pk_r.as_bytes());
+        let (key, nonce) = key_schedule(&shared_secret, info);
+        let ciphertext = ChaCha20Poly1305::new(key.as_slice().into())
+            .encrypt(
+                nonce.as_slice().into(),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .ok()?;
+        Some((enc, ciphertext))
+    }
+
+    pub(super) fn open(
+        sk_r: &StaticSecret,
+        enc: &[u8; 32],
+        info: &[u8],
+        aad: &[u8],
+        ciphertext: &[u8],
+    ) -> Option<Zeroizing<Vec<u8>>> {
+        let dh = sk_r.diffie_hellman(&PublicKey::from(*enc));
+        if !dh.was_contributory() {
+            return None;
+        }
+        let pk_r = PublicKey::from(sk_r);
+        let shared_secret = shared_secret(dh.as_bytes(), enc, pk_r.as_bytes());
+        let (key, nonce) = key_schedule(&shared_secret, info);
+        ChaCha20Poly1305::new(key.as_slice().into())
+            .decrypt(
+                nonce.as_slice().into(),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .ok()
+            .map(Zeroizing::new)
+    }
+}
+
+/// X25519 key of a signer, the public part is given to the dealer beforehand.
+pub struct RecipientSecretKey(StaticSecret);
+
+impl RecipientSecretKey {
+    pub fn generate() -> Self {
+        RecipientSecretKey(StaticSecret::random_from_rng(ChaCha20Rng::from_entropy()))
+    }
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        RecipientSecretKey(StaticSecret::from(bytes))
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        PublicKey::from(&self.0).to_bytes()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SealedPackage {
+    pub uid: u64,
+    pub recipient: [u8; 32],
+    pub enc: [u8; 32],
+    pub ciphertext: Vec<u8>,
+}
+
+/// Everything the relay passes from the dealer to the signers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShareBundle {
+    pub version: u16,
+    /// The `PublicPackage` in the versioned CBOR format
+    pub public_package: Vec<u8>,
+    pub packages: Vec<SealedPackage>,
+    pub signature: Vec<u8>,
+}
+
+/// The packages are bound to the public package and to the uid through the associated data.
+fn package_aad(public_package: &[u8], uid: u64) -> Vec<u8> {
+    [
+        Sha256::digest(public_package).as_slice(),
+        &uid.to_be_bytes(),
+    ]
+    .concat()
+}
+
+impl ShareBundle {
+    fn signed_message(&self) -> Vec<u8> {
+        let mut message = SIGNATURE_CONTEXT.to_vec();
+        message.extend_from_slice(&self.version.to_be_bytes());
+        message.extend_from_slice(&(self.public_package.len() as u64).to_be_bytes());
+        message.extend_from_slice(&self.public_package);
+        message.extend_from_slice(&(self.packages.len() as u64).to_be_bytes());
+        for package in &self.packages {
+            message.extend_from_slice(&package.uid.to_be_bytes());
+            message.extend_from_slice(&package.recipient);
+            message.extend_from_slice(&package.enc);
+            message.extend_from_slice(&(package.ciphertext.len() as u64).to_be_bytes());
+            message.extend_from_slice(&package.ciphertext);
+        }
+        message
+    }
+
+    /// Encrypt `secret_pkgs[i]` to `recipients[i]` and sign the bundle.
+    pub fn seal(
+        secret_pkgs: &[SecretPackage],
+        public_pkg: &PublicPackage,
+        recipients: &[[u8; 32]],
+        dealer: &SigningKey,
+    ) -> Result<Self, ShareBundleError> {
+        if secret_pkgs.len() != recipients.len() {
+            return Err(ShareBundleError::RecipientCount);
+        }
+        let public_package = public_pkg.to_cbor()?;
+        let packages = secret_pkgs
+            .iter()
+            .zip(recipients)
+            .map(|(secret_pkg, recipient)| {
+                let uid = secret_pkg.uid as u64;
+                let plaintext = Zeroizing::new(secret_pkg.to_cbor()?);
+                let (enc, ciphertext) = hpke::seal(
+                    &PublicKey::from(*recipient),
+                    HPKE_INFO,
+                    &package_aad(&public_package, uid),
+                    &plaintext,
+                )
+                .ok_or(ShareBundleError::Encryption)?;
+                Ok(SealedPackage {
+                    uid,
+                    recipient: *recipient,
+                    enc,
+                    ciphertext,
+                })
+            })
+            .collect::<Result<_, ShareBundleError>>()?;
+
+        let mut bundle = ShareBundle {
+            version: BUNDLE_VERSION,
+            public_package,
+            packages,
+            signature: vec![],
+        };
+        bundle.signature = dealer.sign(&bundle.signed_message()).to_vec();
+        Ok(bundle)
+    }
+
+    /// Check the signature of the dealer and decrypt the package of the recipient.
+    pub fn open(
+        &self,
+        dealer: &VerifyingKey,
+        recipient: &RecipientSecretKey,
+    ) -> Result<(SecretPackage, PublicPackage), ShareBundleError> {
+        if self.version != BUNDLE_VERSION {
+            return Err(ShareBundleError::UnsupportedVersion(self.version));
+        }
+        let signature = Signature::from_slice(&self.signature)
+            .map_err(|_| ShareBundleError::InvalidSignature)?;
+        dealer
+            .verify(&self.signed_message(), &signature)
+            .map_err(|_| ShareBundleError::InvalidSignature)?;
+
+        let public_key = recipient.public_key();
+        let sealed = self
+            .packages
+            .iter()
+            .find(|package| package.recipient == public_key)
+            .ok_or(ShareBundleError::NoPackageForRecipient)?;
+        let plaintext = hpke::open(
+            &recipient.0,
+            &sealed.enc,
+            HPKE_INFO,
+            &package_aad(&self.public_package, sealed.uid),
+            &sealed.ciphertext,
+        )
+        .ok_or(ShareBundleError::Decryption)?;
+
+        let secret_pkg = SecretPackage::from_cbor(&plaintext)?;
+        if secret_pkg.uid as u64 != sealed.uid {
+            return Err(ShareBundleError::MismatchedPackage);
+        }
+        Ok((secret_pkg, PublicPackage::from_cbor(&self.public_package)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deal, load_key};
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn that_hpke_opens_ciphertext_of_another_implementation() {
+        // Produced by the HPKE of pyca/cryptography (OpenSSL), enc || ciphertext
+        let sk_r = StaticSecret::from(core::array::from_fn::<u8, 32, _>(|i| i as u8 + 1));
+        assert_eq!(
+            PublicKey::from(&sk_r).as_bytes().to_vec(),
+            hex("07a37cbc142093c8b755dc1b10e86cb426374ad16aa853ed0bdfc0b2b86d1c7c")
+        );
+        let output = hex(
+            "36906c1756e6c4f31c27643f8440c48f1f811ad8d2209a03d298915b36495343\
+             54ea91e3c0c0f4584d36c27660a827615680995a6a58b468526bfb62dd43fb",
+        );
+        let (enc, ciphertext) = output.split_at(32);
+        let plaintext = hpke::open(
+            &sk_r,
+            enc.try_into().unwrap(),
+            b"pretzel test info",
+            b"pretzel test aad",
+            ciphertext,
+        )
+        .unwrap();
+        assert_eq!(plaintext.as_slice(), b"threshold share");
+    }
+
+    #[test]
+    fn that_each_recipient_opens_only_its_package() {
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 3, 2);
+        let dealer = SigningKey::generate(&mut ChaCha20Rng::from_entropy());
+        let recipients: Vec<RecipientSecretKey> =
+            (0..3).map(|_| RecipientSecretKey::generate()).collect();
+        let public_keys: Vec<[u8; 32]> = recipients.iter().map(|r| r.public_key()).collect();
+
+        let bundle =
+            ShareBundle::seal(&secret_pkgs, &public_pkgs[0], &public_keys, &dealer).unwrap();
+        for (recipient, secret_pkg) in recipients.iter().zip(&secret_pkgs) {
+            let (opened, public_pkg) = bundle.open(&dealer.verifying_key(), recipient).unwrap();
+            assert_eq!(&opened, secret_pkg);
+            assert_eq!(public_pkg, public_pkgs[0]);
+        }
+        assert!(matches!(
+            bundle.open(&dealer.verifying_key(), &RecipientSecretKey::generate()),
+            Err(ShareBundleError::NoPackageForRecipient)
+        ));
+    }
+
+    #[test]
+    fn that_relay_cannot_alter_the_bundle() {
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 2, 2);
+        let dealer = SigningKey::generate(&mut ChaCha20Rng::from_entropy());
+        let recipients = [
+            RecipientSecretKey::generate(),
+            RecipientSecretKey::generate(),
+        ];
+        let public_keys = [recipients[0].public_key(), recipients[1].public_key()];
+        let bundle =
+            ShareBundle::seal(&secret_pkgs, &public_pkgs[0], &public_keys, &dealer).unwrap();
+
+        let mut swapped = bundle.clone();
+        swapped.packages.swap(0, 1);
+        assert!(matches!(
+            swapped.open(&dealer.verifying_key(), &recipients[0]),
+            Err(ShareBundleError::InvalidSignature)
+        ));
+
+        let other_dealer = SigningKey::generate(&mut ChaCha20Rng::from_entropy());
+        assert!(matches!(
+            bundle.open(&other_dealer.verifying_key(), &recipients[0]),
+            Err(ShareBundleError::InvalidSignature)
+        ));
+
+        // Re-signed by a relay that knows some signing key, the package is still bound to uid
+        let mut forged = bundle.clone();
+        forged.packages[0].uid = 1;
+        forged.signature = other_dealer.sign(&forged.signed_message()).to_vec();
+        assert!(matches!(
+            forged.open(&other_dealer.verifying_key(), &recipients[0]),
+            Err(ShareBundleError::Decryption)
+        ));
+    }
+}
@@ -0,0 +1,366 @@
+// CMS SignedData (RFC 5652) for document and firmware signing.
+//
+// The coordinator computes the digest of the content, builds the signed attributes around it and
+// hands them to the quorum as an `x509::Unsigned`, i.e. the signers sign the DER encoded SET OF
+// the attributes. The combined signature goes into a single SignerInfo identified by the issuer
+// and serial number of the certificate of the threshold key, the certificate is included. The
+// content is detached unless it is given by `SignedDataBuilder::attached_content`.
+//
+//   openssl cms -verify -binary -inform DER -in document.txt.p7s -content document.txt \
+//       -CAfile signer.pem
+
+use crate::prehash::{HashAlgorithm, Prehash, PrehashPadding};
+use crate::x509::{ToBeSigned, Unsigned, X509Error};
+use cms::cert::{CertificateChoices, IssuerAndSerialNumber};
+use cms::content_info::{CmsVersion, ContentInfo};
+use cms::signed_data::{
+    CertificateSet, EncapsulatedContentInfo, SignedAttributes, SignedData, SignerIdentifier,
+    SignerInfo, SignerInfos,
+};
+use rsa::pkcs8::ObjectIdentifier;
+use std::io::Read;
+use x509_cert::attr::Attribute;
+use x509_cert::der::asn1::{Any, BitString, OctetString, SetOfVec};
+use x509_cert::der::pem::LineEnding;
+use x509_cert::der::{Encode, Length, Tag, Writer};
+use x509_cert::spki::AlgorithmIdentifierOwned;
+use x509_cert::time::Time;
+use x509_cert::Certificate;
+
+const ID_DATA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.7.1");
+const ID_SIGNED_DATA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.7.2");
+const ID_CONTENT_TYPE: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.3");
+const ID_MESSAGE_DIGEST: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.4");
+const ID_SIGNING_TIME: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.5");
+
+pub struct SignedDataBuilder {
+    certificate: Certificate,
+    hash_alg: HashAlgorithm,
+    digest: Option<Vec<u8>>,
+    content: Option<Vec<u8>>,
+    signing_time: Option<Time>,
+}
+
+impl SignedDataBuilder {
+    /// The certificate of the threshold key, it goes into the output.
+    pub fn new(certificate: Certificate, hash_alg: HashAlgorithm) -> Self {
+        SignedDataBuilder {
+            certificate,
+            hash_alg,
+            digest: None,
+            content: None,
+            signing_time: None,
+        }
+    }
+
+    /// A detached signature of the content, it is hashed as it is read.
+    pub fn content(mut self, content: impl Read) -> Result<Self, X509Error> {
+        let prehash = Prehash::from_reader(self.hash_alg, content, PrehashPadding::Pkcs1v15)?;
+        self.digest = Some(prehash.digest);
+        Ok(self)
+    }
+
+    /// A detached signature of the content hashed elsewhere.
+    pub fn digest(mut self, digest: &[u8]) -> Result<Self, X509Error> {
+        let prehash = Prehash::new(self.hash_alg, digest, PrehashPadding::Pkcs1v15)?;
+        self.digest = Some(prehash.digest);
+        Ok(self)
+    }
+
+    /// The content is encapsulated in the SignedData.
+    pub fn attached_content(self, content: &[u8]) -> Result<Self, X509Error> {
+        let mut builder = self.content(content)?;
+        builder.content = Some(content.to_vec());
+        Ok(builder)
+    }
+
+    pub fn signing_time(mut self, signing_time: Time) -> Self {
+        self.signing_time = Some(signing_time);
+        self
+    }
+
+    pub fn build(self) -> Result<Unsigned<PendingSignedData>, X509Error> {
+        let digest = self.digest.ok_or(X509Error::MissingContent)?;
+        let mut signed_attrs = vec![
+            attribute(ID_CONTENT_TYPE, Any::encode_from(&ID_DATA)?)?,
+            attribute(
+                ID_MESSAGE_DIGEST,
+                Any::encode_from(&OctetString::new(digest)?)?,
+            )?,
+        ];
+        if let Some(signing_time) = self.signing_time {
+            signed_attrs.push(attribute(
+                ID_SIGNING_TIME,
+                Any::encode_from(&signing_time)?,
+            )?);
+        }
+        Ok(Unsigned::new(
+            PendingSignedData {
+                signed_attrs: SetOfVec::try_from(signed_attrs)?,
+                certificate: self.certificate,
+                hash_alg: self.hash_alg,
+                content: self.content,
+            },
+            self.hash_alg,
+        ))
+    }
+}
+
+fn attribute(oid: ObjectIdentifier, value: Any) -> Result<Attribute, X509Error> {
+    Ok(Attribute {
+        oid,
+        values: SetOfVec::try_from(vec![value])?,
+    })
+}
+
+/// The signed attributes waiting for the signature, they encode as the SET OF that is signed.
+#[derive(Debug, Clone)]
+pub struct PendingSignedData {
+    signed_attrs: SignedAttributes,
+    certificate: Certificate,
+    hash_alg: HashAlgorithm,
+    content: Option<Vec<u8>>,
+}
+
+impl Encode for PendingSignedData {
+    fn encoded_len(&self) -> x509_cert::der::Result<Length> {
+        self.signed_attrs.encoded_len()
+    }
+
+    fn encode(&self, writer: &mut impl Writer) -> x509_cert::der::Result<()> {
+        self.signed_attrs.encode(writer)
+    }
+}
+
+impl ToBeSigned for PendingSignedData {
+    type Signed = ContentInfo;
+
+    /// The signed attributes do not name it, `build` gives the hash to both.
+    fn algorithm(&self) -> Option<&AlgorithmIdentifierOwned> {
+        None
+    }
+
+    fn assemble(
+        self,
+        algorithm: AlgorithmIdentifierOwned,
+        signature: BitString,
+    ) -> Result<ContentInfo, X509Error> {
+        let tbs = &self.certificate.tbs_certificate;
+        let digest_alg = AlgorithmIdentifierOwned {
+            oid: self.hash_alg.oid(),
+            parameters: None,
+        };
+        let signer_info = SignerInfo {
+            version: CmsVersion::V1,
+            sid: SignerIdentifier::IssuerAndSerialNumber(IssuerAndSerialNumber {
+                issuer: tbs.issuer.clone(),
+                serial_number: tbs.serial_number.clone(),
+            }),
+            digest_alg: digest_alg.clone(),
+            signed_attrs: Some(self.signed_attrs),
+            signature_algorithm: algorithm,
+            signature: OctetString::new(signature.raw_bytes())?,
+            unsigned_attrs: None,
+        };
+        let econtent = match self.content {
+            Some(content) => Some(Any::new(Tag::OctetString, content)?),
+            None => None,
+        };
+        let signed_data = SignedData {
+            version: CmsVersion::V1,
+            digest_algorithms: SetOfVec::try_from(vec![digest_alg])?,
+            encap_content_info: EncapsulatedContentInfo {
+                econtent_type: ID_DATA,
+                econtent,
+            },
+            certificates: Some(CertificateSet::from(SetOfVec::try_from(vec![
+                CertificateChoices::Certificate(self.certificate),
+            ])?)),
+            crls: None,
+            signer_infos: SignerInfos::from(SetOfVec::try_from(vec![signer_info])?),
+        };
+        Ok(ContentInfo {
+            content_type: ID_SIGNED_DATA,
+            content: Any::encode_from(&signed_data)?,
+        })
+    }
+}
+
+/// PEM with the "CMS" label of RFC 7468
+pub fn to_pem(content_info: &ContentInfo) -> Result<String, X509Error> {
+    let der = content_info.to_der()?;
+    Ok(
+        x509_cert::der::pem::encode_string("CMS", LineEnding::LF, &der)
+            .map_err(x509_cert::der::Error::from)?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x509::{certificate, quorum_sign, subject_public_key_info};
+    use crate::{deal, load_key, PublicPackage, SecretPackage};
+    use rsa::pkcs1v15::{Signature, VerifyingKey};
+    use rsa::signature::Verifier;
+    use sha2::{Digest, Sha256};
+    use std::path::PathBuf;
+    use std::str::FromStr;
+    use x509_cert::der::asn1::UtcTime;
+    use x509_cert::der::oid::AssociatedOid;
+    use x509_cert::der::{DateTime, Decode, DecodePem, EncodePem};
+    use x509_cert::ext::pkix::BasicConstraints;
+    use x509_cert::ext::Extension;
+    use x509_cert::name::Name;
+    use x509_cert::serial_number::SerialNumber;
+    use x509_cert::time::Validity;
+
+    // The samples verify with `openssl cms -verify`, see `that_samples_verify_with_openssl`.
+    fn sample(name: &str) -> PathBuf {
+        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("resources/test/cms");
+        path.push(name);
+        path
+    }
+
+    fn utc(year: u16, month: u8, day: u8) -> Time {
+        Time::UtcTime(
+            UtcTime::from_date_time(DateTime::new(year, month, day, 0, 0, 0).unwrap()).unwrap(),
+        )
+    }
+
+    /// The self-signed certificate of the test key in signer.pem
+    fn signer_certificate(
+        public_pkg: &PublicPackage,
+        secret_pkgs: &[SecretPackage],
+    ) -> Certificate {
+        let name = Name::from_str("CN=Pretzel Test Signer,O=Pretzel").unwrap();
+        let basic_constraints = BasicConstraints {
+            ca: true,
+            path_len_constraint: None,
+        };
+        let unsigned = certificate(
+            name.clone(),
+            name,
+            subject_public_key_info(public_pkg).unwrap(),
+            SerialNumber::new(&[0x50, 0x72]).unwrap(),
+            Validity {
+                not_before: utc(2026, 1, 1),
+                not_after: utc(2046, 1, 1),
+            },
+            vec![Extension {
+                extn_id: BasicConstraints::OID,
+                critical: true,
+                extn_value: OctetString::new(basic_constraints.to_der().unwrap()).unwrap(),
+            }],
+            HashAlgorithm::Sha256,
+        );
+        quorum_sign(public_pkg, secret_pkgs, unsigned)
+    }
+
+    #[test]
+    fn that_detached_signature_is_the_sample() {
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 3, 2);
+        let public_pkg = &public_pkgs[0];
+        let signer = signer_certificate(public_pkg, &secret_pkgs[..2]);
+        let document = std::fs::read(sample("document.txt")).unwrap();
+
+        let unsigned = SignedDataBuilder::new(signer.clone(), HashAlgorithm::Sha256)
+            .content(std::fs::File::open(sample("document.txt")).unwrap())
+            .unwrap()
+            .signing_time(utc(2026, 10, 18))
+            .build()
+            .unwrap();
+        let signed_attrs = unsigned.to_be_signed().unwrap();
+        let content_info = quorum_sign(public_pkg, &secret_pkgs[1..], unsigned);
+
+        let pem = std::fs::read_to_string(sample("signer.pem")).unwrap();
+        assert_eq!(Certificate::from_pem(pem).unwrap(), signer);
+        assert_eq!(
+            content_info.to_der().unwrap(),
+            std::fs::read(sample("document.txt.p7s")).unwrap()
+        );
+
+        let signed_data = content_info.content.decode_as::<SignedData>().unwrap();
+        assert!(signed_data.encap_content_info.econtent.is_none());
+        let signer_info = signed_data.signer_infos.0.get(0).unwrap();
+        let key = VerifyingKey::<Sha256>::new(public_pkg.public_key.clone());
+        let signature = Signature::try_from(signer_info.signature.as_bytes()).unwrap();
+        assert!(key.verify(&signed_attrs, &signature).is_ok());
+
+        // The digest computed elsewhere gives the same signed attributes
+        let unsigned = SignedDataBuilder::new(signer, HashAlgorithm::Sha256)
+            .digest(&Sha256::digest(&document))
+            .unwrap()
+            .signing_time(utc(2026, 10, 18))
+            .build()
+            .unwrap();
+        assert_eq!(unsigned.to_be_signed().unwrap(), signed_attrs);
+    }
+
+    #[test]
+    fn that_attached_signature_is_the_sample() {
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 3, 2);
+        let public_pkg = &public_pkgs[0];
+        let signer = signer_certificate(public_pkg, &secret_pkgs[1..]);
+        let document = std::fs::read(sample("document.txt")).unwrap();
+
+        let unsigned = SignedDataBuilder::new(signer.clone(), HashAlgorithm::Sha384)
+            .attached_content(&document)
+            .unwrap()
+            .build()
+            .unwrap();
+        let content_info = quorum_sign(public_pkg, &secret_pkgs[..2], unsigned);
+        let pem = to_pem(&content_info).unwrap();
+
+        assert_eq!(
+            pem,
+            std::fs::read_to_string(sample("document.txt.pem")).unwrap()
+        );
+        let signed_data = content_info.content.decode_as::<SignedData>().unwrap();
+        assert_eq!(
+            signed_data.encap_content_info.econtent.unwrap().value(),
+            document.as_slice()
+        );
+
+        assert!(matches!(
+            SignedDataBuilder::new(signer.clone(), HashAlgorithm::Sha384).build(),
+            Err(X509Error::MissingContent)
+        ));
+        assert!(SignedDataBuilder::new(signer, HashAlgorithm::Sha384)
+            .digest(&Sha256::digest(&document))
+            .is_err());
+    }
+
+    // The tests above make the samples byte for byte, this checks them with OpenSSL
+    #[test]
+    #[ignore = "needs the openssl binary"]
+    fn that_samples_verify_with_openssl() {
+        let verify = |input: PathBuf, inform: &str, content: Option<PathBuf>| {
+            let mut command = std::process::Command::new("openssl");
+            command
+                .args(["cms", "-verify", "-binary", "-inform", inform, "-in"])
+                .arg(input)
+                .arg("-CAfile")
+                .arg(sample("signer.pem"))
+                .args(["-out", if cfg!(windows) { "NUL" } else { "/dev/null" }]);
+            if let Some(content) = content {
+                command.arg("-content").arg(content);
+            }
+            command.output().unwrap().status.success()
+        };
+        assert!(verify(
+            sample("document.txt.p7s"),
+            "DER",
+            Some(sample("document.txt"))
+        ));
+        assert!(verify(sample("document.txt.pem"), "PEM", None));
+
+        let mut document = std::fs::read(sample("document.txt")).unwrap();
+        document[0] ^= 1;
+        let tampered = std::env::temp_dir().join(format!("pretzel-cms-{}", std::process::id()));
+        std::fs::write(&tampered, document).unwrap();
+        let verified = verify(sample("document.txt.p7s"), "DER", Some(tampered.clone()));
+        std::fs::remove_file(tampered).unwrap();
+        assert!(!verified);
+    }
+}
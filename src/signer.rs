@@ -0,0 +1,275 @@
+// The RustCrypto `signature` traits, a threshold key in place of a single RSA key.
+//
+// `QuorumSigner` holds a quorum of the shares on one host and signs with all of them, the
+// signatures are RSASSA-PKCS1-v1_5 with the digest `D` as those of `rsa::pkcs1v15::SigningKey<D>`.
+// The shares are combined in the optimistic mode, a wrong share is caught by the check of the
+// final signature. `RandomizedSigner` blinds the message before it is given to the shares.
+// `ThresholdVerifyingKey<D>` verifies the signatures with the public package alone.
+
+use crate::context::KeyContext;
+use crate::{digest_msg, PaddingScheme, PublicPackage, SecretPackage, SigningError};
+use num_bigint::{BigUint, ModInverse, RandBigInt};
+use num_integer::Integer;
+use num_traits::One;
+use rand_core::CryptoRngCore;
+use rayon::prelude::*;
+use rsa::hazmat::uint_to_zeroizing_be_pad;
+use rsa::pkcs1v15::{Signature, VerifyingKey};
+use rsa::pkcs8::AssociatedOid;
+use rsa::signature::{
+    DigestSigner, DigestVerifier, Error, Keypair, RandomizedSigner, SignatureEncoding, Signer,
+    Verifier,
+};
+use rsa::traits::PublicKeyParts;
+use rsa::Pkcs1v15Sign;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::marker::PhantomData;
+
+pub struct QuorumSigner<D = Sha256> {
+    public_pkg: PublicPackage,
+    context: KeyContext,
+    secret_pkgs: Vec<SecretPackage>,
+    phantom: PhantomData<fn() -> D>,
+}
+
+impl<D> QuorumSigner<D>
+where
+    D: Digest + AssociatedOid,
+{
+    /// At least the threshold of the shares, otherwise the signing fails.
+    pub fn new(
+        public_pkg: &PublicPackage,
+        secret_pkgs: Vec<SecretPackage>,
+    ) -> Result<Self, SigningError> {
+        let mut ids = HashSet::new();
+        if secret_pkgs.is_empty() || !secret_pkgs.iter().all(|pkg| ids.insert(pkg.share.id)) {
+            return Err(SigningError::SigningError);
+        }
+        Ok(QuorumSigner {
+            public_pkg: public_pkg.clone(),
+            context: KeyContext::new(public_pkg)?,
+            secret_pkgs,
+            phantom: PhantomData,
+        })
+    }
+
+    /// x^d of an encoded message x < n
+    fn sign_raw(&self, x: &BigUint) -> Result<BigUint, Error> {
+        let encoded = x.to_bytes_be();
+        let shares = self
+            .secret_pkgs
+            .par_iter()
+            .map(|secret_pkg| {
+                self.context
+                    .sign_share(secret_pkg, &encoded, PaddingScheme::NONE)
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Error::from_source)?;
+        let signature = self
+            .context
+            .combine_optimistic(&encoded, &shares, PaddingScheme::NONE)
+            .map_err(Error::from_source)?;
+        Ok(BigUint::from_bytes_be(&signature))
+    }
+
+    fn encode(&self, digest: D) -> BigUint {
+        let mut digest_info = Pkcs1v15Sign::new::<D>().prefix.into_vec();
+        digest_info.extend_from_slice(&digest.finalize());
+        let n = self.public_pkg.public_key.n();
+        digest_msg(
+            &digest_info,
+            PaddingScheme::PKCS1v15,
+            n,
+            self.public_pkg.public_key.size(),
+        )
+    }
+
+    fn to_signature(&self, signature: BigUint) -> Result<Signature, Error> {
+        let bytes = uint_to_zeroizing_be_pad(signature, self.public_pkg.public_key.size())
+            .map_err(Error::from_source)?;
+        Signature::try_from(bytes.as_slice())
+    }
+}
+
+impl<D> DigestSigner<D, Signature> for QuorumSigner<D>
+where
+    D: Digest + AssociatedOid,
+{
+    fn try_sign_digest(&self, digest: D) -> Result<Signature, Error> {
+        self.to_signature(self.sign_raw(&self.encode(digest))?)
+    }
+}
+
+impl<D> Signer<Signature> for QuorumSigner<D>
+where
+    D: Digest + AssociatedOid,
+{
+    fn try_sign(&self, msg: &[u8]) -> Result<Signature, Error> {
+        self.try_sign_digest(D::new_with_prefix(msg))
+    }
+}
+
+impl<D> RandomizedSigner<Signature> for QuorumSigner<D>
+where
+    D: Digest + AssociatedOid,
+{
+    /// The shares sign x r^e, the signature is unblinded by r^{-1}.
+    fn try_sign_with_rng(
+        &self,
+        rng: &mut impl CryptoRngCore,
+        msg: &[u8],
+    ) -> Result<Signature, Error> {
+        let n = self.public_pkg.public_key.n();
+        let e = self.public_pkg.public_key.e();
+        let (r, r_inverse) = loop {
+            let r = rng.gen_biguint_range(&BigUint::one(), n);
+            if let Some(inverse) = r.clone().mod_inverse(n).and_then(|i| i.to_biguint()) {
+                break (r, inverse);
+            }
+        };
+        let x = self.encode(D::new_with_prefix(msg));
+        let blinded = (x * r.modpow(e, n)).mod_floor(n);
+        let signature = (self.sign_raw(&blinded)? * r_inverse).mod_floor(n);
+        self.to_signature(signature)
+    }
+}
+
+impl<D> Keypair for QuorumSigner<D>
+where
+    D: Digest + AssociatedOid,
+{
+    type VerifyingKey = VerifyingKey<D>;
+
+    fn verifying_key(&self) -> VerifyingKey<D> {
+        VerifyingKey::new(self.public_pkg.public_key.clone())
+    }
+}
+
+/// The digest is given by the caller, messages are verified by `ThresholdVerifyingKey<D>`.
+impl<D> DigestVerifier<D, Signature> for PublicPackage
+where
+    D: Digest + AssociatedOid,
+{
+    fn verify_digest(&self, digest: D, signature: &Signature) -> Result<(), Error> {
+        self.public_key
+            .verify(
+                Pkcs1v15Sign::new::<D>(),
+                &digest.finalize(),
+                &signature.to_bytes(),
+            )
+            .map_err(|_| Error::new())
+    }
+}
+
+/// The public package as a `Verifier` of the messages hashed with `D`, SHA-256 unless named.
+#[derive(Debug, Clone)]
+pub struct ThresholdVerifyingKey<D = Sha256> {
+    public_pkg: PublicPackage,
+    phantom: PhantomData<fn() -> D>,
+}
+
+impl<D> ThresholdVerifyingKey<D>
+where
+    D: Digest + AssociatedOid,
+{
+    pub fn new(public_pkg: &PublicPackage) -> Self {
+        ThresholdVerifyingKey {
+            public_pkg: public_pkg.clone(),
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn public_pkg(&self) -> &PublicPackage {
+        &self.public_pkg
+    }
+}
+
+impl<D> DigestVerifier<D, Signature> for ThresholdVerifyingKey<D>
+where
+    D: Digest + AssociatedOid,
+{
+    fn verify_digest(&self, digest: D, signature: &Signature) -> Result<(), Error> {
+        self.public_pkg.verify_digest(digest, signature)
+    }
+}
+
+impl<D> Verifier<Signature> for ThresholdVerifyingKey<D>
+where
+    D: Digest + AssociatedOid,
+{
+    fn verify(&self, msg: &[u8], signature: &Signature) -> Result<(), Error> {
+        self.verify_digest(D::new_with_prefix(msg), signature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deal, load_key};
+    use rand_chacha::{rand_core::SeedableRng, ChaCha20Rng};
+    use sha2::Sha384;
+
+    fn sign_generic<S: Signer<Signature>>(signer: &S, msg: &[u8]) -> Signature {
+        signer.sign(msg)
+    }
+
+    fn verify_generic<V: Verifier<Signature>>(
+        verifier: &V,
+        msg: &[u8],
+        signature: &Signature,
+    ) -> bool {
+        verifier.verify(msg, signature).is_ok()
+    }
+
+    #[test]
+    fn that_quorum_signs_like_a_single_key() {
+        let key = load_key().unwrap();
+        let (secret_pkgs, public_pkgs) = deal(&key, 5, 3);
+        let public_pkg = &public_pkgs[0];
+        let signer: QuorumSigner =
+            QuorumSigner::new(public_pkg, secret_pkgs[1..4].to_vec()).unwrap();
+
+        let signature = sign_generic(&signer, b"ABC");
+        let verifying_key = signer.verifying_key();
+        assert!(verifying_key.verify(b"ABC", &signature).is_ok());
+        assert!(verifying_key.verify(b"ABD", &signature).is_err());
+        assert!(public_pkg
+            .verify_digest(Sha256::new_with_prefix(b"ABC"), &signature)
+            .is_ok());
+        let threshold_key: ThresholdVerifyingKey = ThresholdVerifyingKey::new(public_pkg);
+        assert!(verify_generic(&threshold_key, b"ABC", &signature));
+        assert!(!verify_generic(&threshold_key, b"ABD", &signature));
+        // The signature is unique, the blinding does not change it
+        let randomized = signer.sign_with_rng(&mut ChaCha20Rng::from_entropy(), b"ABC");
+        assert_eq!(randomized, signature);
+
+        let signer: QuorumSigner<Sha384> =
+            QuorumSigner::new(public_pkg, secret_pkgs[2..].to_vec()).unwrap();
+        let signature = signer.sign(b"ABC");
+        assert!(public_pkg
+            .verify_digest(Sha384::new_with_prefix(b"ABC"), &signature)
+            .is_ok());
+        assert!(signer.verifying_key().verify(b"ABC", &signature).is_ok());
+        assert!(verifying_key.verify(b"ABC", &signature).is_err());
+        assert!(verify_generic(
+            &ThresholdVerifyingKey::<Sha384>::new(public_pkg),
+            b"ABC",
+            &signature
+        ));
+        assert!(!verify_generic(&threshold_key, b"ABC", &signature));
+    }
+
+    #[test]
+    fn that_too_few_shares_fail() {
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 5, 3);
+        let signer: QuorumSigner =
+            QuorumSigner::new(&public_pkgs[0], secret_pkgs[..2].to_vec()).unwrap();
+        assert!(signer.try_sign(b"ABC").is_err());
+        assert!(QuorumSigner::<Sha256>::new(
+            &public_pkgs[0],
+            vec![secret_pkgs[0].clone(), secret_pkgs[0].clone()]
+        )
+        .is_err());
+    }
+}
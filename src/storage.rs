@@ -0,0 +1,291 @@
+// Password protected storage of the `SecretPackage`.
+//
+// The key is derived from the password with Argon2id and the package, in the versioned CBOR format,
+// is encrypted with XChaCha20-Poly1305. The file layout is
+//
+//   magic "PRZS" | version (1 byte) | m_cost | t_cost | p_cost (4 bytes each, big-endian)
+//   | salt (16 bytes) | nonce (24 bytes) | ciphertext
+//
+// Everything before the ciphertext is the associated data, so the parameters cannot be altered.
+
+use crate::format::{FormatError, Versioned};
+use crate::SecretPackage;
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::XChaCha20Poly1305;
+use rand::RngCore;
+use rand_chacha::{rand_core::SeedableRng, ChaCha20Rng};
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::path::Path;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+const MAGIC: &[u8; 4] = b"PRZS";
+const STORAGE_VERSION: u8 = 1;
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 24;
+const HEADER_LENGTH: usize = MAGIC.len() + 1 + 3 * 4 + SALT_LENGTH + NONCE_LENGTH;
+/// Files asking for more memory, passes or lanes are refused, they could exhaust the memory or
+/// the CPU of the reader.
+const MAX_M_COST: u32 = 4 * 1024 * 1024;
+const MAX_T_COST: u32 = 32;
+const MAX_P_COST: u32 = 16;
+
+#[derive(Error, Debug, Serialize, Deserialize)]
+pub enum StorageError {
+    #[error("The storage cannot be accessed: {0}")]
+    Io(String),
+    #[error("The data is not an encrypted secret package")]
+    InvalidFormat,
+    #[error("Unsupported version {0} of the encrypted secret package")]
+    UnsupportedVersion(u8),
+    #[error("Invalid key derivation parameters: {0}")]
+    Kdf(String),
+    #[error("Wrong password or corrupted data")]
+    Decryption,
+    #[error(transparent)]
+    Format(#[from] FormatError),
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(e: std::io::Error) -> Self {
+        StorageError::Io(e.to_string())
+    }
+}
+
+/// Argon2id parameters, `m_cost` is in KiB.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    /// 64 MiB, 3 passes, 4 lanes (RFC 9106, the second recommended option)
+    fn default() -> Self {
+        KdfParams {
+            m_cost: 64 * 1024,
+            t_cost: 3,
+            p_cost: 4,
+        }
+    }
+}
+
+fn derive_key(
+    password: &[u8],
+    salt: &[u8],
+    params: &KdfParams,
+) -> Result<Zeroizing<[u8; 32]>, StorageError> {
+    for (name, cost, max) in [
+        ("m_cost", params.m_cost, MAX_M_COST),
+        ("t_cost", params.t_cost, MAX_T_COST),
+        ("p_cost", params.p_cost, MAX_P_COST),
+    ] {
+        if cost > max {
+            return Err(StorageError::Kdf(format!("{name} {cost} is above {max}")));
+        }
+    }
+    let argon2_params = Params::new(params.m_cost, params.t_cost, params.p_cost, Some(32))
+        .map_err(|e| StorageError::Kdf(e.to_string()))?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, argon2_params)
+        .hash_password_into(password, salt, key.as_mut())
+        .map_err(|e| StorageError::Kdf(e.to_string()))?;
+    Ok(key)
+}
+
+impl SecretPackage {
+    /// Encrypt the package under the password and write it into `writer`.
+    pub fn write_encrypted<W: Write>(
+        &self,
+        mut writer: W,
+        password: &[u8],
+        params: KdfParams,
+    ) -> Result<(), StorageError> {
+        let mut rng = ChaCha20Rng::from_entropy();
+        let mut header = Vec::with_capacity(HEADER_LENGTH);
+        header.extend_from_slice(MAGIC);
+        header.push(STORAGE_VERSION);
+        for cost in [params.m_cost, params.t_cost, params.p_cost] {
+            header.extend_from_slice(&cost.to_be_bytes());
+        }
+        let mut salt = [0u8; SALT_LENGTH];
+        rng.fill_bytes(&mut salt);
+        header.extend_from_slice(&salt);
+        let mut nonce = [0u8; NONCE_LENGTH];
+        rng.fill_bytes(&mut nonce);
+        header.extend_from_slice(&nonce);
+
+        let key = derive_key(password, &salt, &params)?;
+        let plaintext = Zeroizing::new(self.to_cbor()?);
+        let ciphertext = XChaCha20Poly1305::new(key.as_ref().into())
+            .encrypt(
+                (&nonce).into(),
+                Payload {
+                    msg: &plaintext,
+                    aad: &header,
+                },
+            )
+            .map_err(|_| StorageError::InvalidFormat)?;
+
+        writer.write_all(&header)?;
+        writer.write_all(&ciphertext)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Read a package written by `write_encrypted`.
+    pub fn read_encrypted<R: Read>(mut reader: R, password: &[u8]) -> Result<Self, StorageError> {
+        let mut header = [0u8; HEADER_LENGTH];
+        reader.read_exact(&mut header).map_err(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => StorageError::InvalidFormat,
+            _ => e.into(),
+        })?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(StorageError::InvalidFormat);
+        }
+        let version = header[MAGIC.len()];
+        if version != STORAGE_VERSION {
+            return Err(StorageError::UnsupportedVersion(version));
+        }
+        let cost = |i: usize| {
+            let offset = MAGIC.len() + 1 + 4 * i;
+            u32::from_be_bytes(header[offset..offset + 4].try_into().unwrap())
+        };
+        let params = KdfParams {
+            m_cost: cost(0),
+            t_cost: cost(1),
+            p_cost: cost(2),
+        };
+        let salt_offset = MAGIC.len() + 1 + 3 * 4;
+        let salt = &header[salt_offset..salt_offset + SALT_LENGTH];
+        let nonce = &header[salt_offset + SALT_LENGTH..];
+
+        let mut ciphertext = Vec::new();
+        reader.read_to_end(&mut ciphertext)?;
+
+        let key = derive_key(password, salt, &params)?;
+        let plaintext = Zeroizing::new(
+            XChaCha20Poly1305::new(key.as_ref().into())
+                .decrypt(
+                    nonce.into(),
+                    Payload {
+                        msg: &ciphertext,
+                        aad: &header,
+                    },
+                )
+                .map_err(|_| StorageError::Decryption)?,
+        );
+        Ok(Self::from_cbor(&plaintext)?)
+    }
+
+    pub fn export_encrypted(
+        &self,
+        password: &[u8],
+        params: KdfParams,
+    ) -> Result<Vec<u8>, StorageError> {
+        let mut data = Vec::new();
+        self.write_encrypted(&mut data, password, params)?;
+        Ok(data)
+    }
+
+    pub fn import_encrypted(data: &[u8], password: &[u8]) -> Result<Self, StorageError> {
+        Self::read_encrypted(data, password)
+    }
+
+    /// Write the encrypted package into a new file, readable only by the owner on Unix.
+    pub fn save_encrypted<P: AsRef<Path>>(
+        &self,
+        path: P,
+        password: &[u8],
+        params: KdfParams,
+    ) -> Result<(), StorageError> {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        self.write_encrypted(options.open(path)?, password, params)
+    }
+
+    pub fn load_encrypted<P: AsRef<Path>>(path: P, password: &[u8]) -> Result<Self, StorageError> {
+        Self::read_encrypted(std::fs::File::open(path)?, password)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deal, load_key};
+
+    const CHEAP: KdfParams = KdfParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    #[test]
+    fn that_package_roundtrips_only_with_the_right_password() {
+        let (secret_pkgs, _) = deal(&load_key().unwrap(), 2, 2);
+        let data = secret_pkgs[0]
+            .export_encrypted(b"correct horse", CHEAP)
+            .unwrap();
+        assert_eq!(
+            SecretPackage::import_encrypted(&data, b"correct horse").unwrap(),
+            secret_pkgs[0]
+        );
+        assert!(matches!(
+            SecretPackage::import_encrypted(&data, b"battery staple"),
+            Err(StorageError::Decryption)
+        ));
+    }
+
+    #[test]
+    fn that_tampered_header_is_detected() {
+        let (secret_pkgs, _) = deal(&load_key().unwrap(), 2, 2);
+        let mut data = secret_pkgs[0].export_encrypted(b"pw", CHEAP).unwrap();
+        // t_cost 1 -> 2
+        data[MAGIC.len() + 1 + 7] = 2;
+        assert!(matches!(
+            SecretPackage::import_encrypted(&data, b"pw"),
+            Err(StorageError::Decryption)
+        ));
+        assert!(matches!(
+            SecretPackage::import_encrypted(&data[..10], b"pw"),
+            Err(StorageError::InvalidFormat)
+        ));
+    }
+
+    #[test]
+    fn that_excessive_costs_in_the_header_are_refused() {
+        let (secret_pkgs, _) = deal(&load_key().unwrap(), 2, 2);
+        let data = secret_pkgs[0].export_encrypted(b"pw", CHEAP).unwrap();
+        // m_cost, t_cost and p_cost, each set to u32::MAX
+        for offset in [MAGIC.len() + 1, MAGIC.len() + 5, MAGIC.len() + 9] {
+            let mut data = data.clone();
+            data[offset..offset + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+            assert!(matches!(
+                SecretPackage::import_encrypted(&data, b"pw"),
+                Err(StorageError::Kdf(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn that_package_is_saved_to_an_arbitrary_path() {
+        let (secret_pkgs, _) = deal(&load_key().unwrap(), 2, 2);
+        let path = std::env::temp_dir().join(format!("pretzel-share-{}.bin", std::process::id()));
+        secret_pkgs[1].save_encrypted(&path, b"pw", CHEAP).unwrap();
+        assert!(secret_pkgs[1].save_encrypted(&path, b"pw", CHEAP).is_err());
+        assert_eq!(
+            SecretPackage::load_encrypted(&path, b"pw").unwrap(),
+            secret_pkgs[1]
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,489 @@
+// Fiat-Shamir transcripts.
+//
+// Every value is absorbed into the hash together with its label and both are prefixed by their
+// lengths, so two different sequences of values never hash the same. A challenge of any length is
+// squeezed from the state in counter mode, the state is then ratcheted so that further challenges
+// are independent. The hash function is chosen by `ProofHash`.
+
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use sha3::{Sha3_256, Sha3_384, Sha3_512};
+
+pub const DEFAULT_CHALLENGE_BITS: usize = 256;
+/// Shorter challenges give less than 128 bits of soundness.
+pub const MIN_CHALLENGE_BITS: usize = 128;
+pub const MAX_CHALLENGE_BITS: usize = 1024;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ProofHash {
+    #[default]
+    Sha256,
+    Sha384,
+    Sha512,
+    Sha3_256,
+    Sha3_384,
+    Sha3_512,
+    Blake3,
+}
+
+impl ProofHash {
+    pub const ALL: [ProofHash; 7] = [
+        ProofHash::Sha256,
+        ProofHash::Sha384,
+        ProofHash::Sha512,
+        ProofHash::Sha3_256,
+        ProofHash::Sha3_384,
+        ProofHash::Sha3_512,
+        ProofHash::Blake3,
+    ];
+
+    /// The name in the serialized documents, see `format`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProofHash::Sha256 => "sha256",
+            ProofHash::Sha384 => "sha384",
+            ProofHash::Sha512 => "sha512",
+            ProofHash::Sha3_256 => "sha3-256",
+            ProofHash::Sha3_384 => "sha3-384",
+            ProofHash::Sha3_512 => "sha3-512",
+            ProofHash::Blake3 => "blake3",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|hash| hash.name() == name)
+    }
+
+    pub fn output_bits(&self) -> usize {
+        match self {
+            ProofHash::Sha256 | ProofHash::Sha3_256 | ProofHash::Blake3 => 256,
+            ProofHash::Sha384 | ProofHash::Sha3_384 => 384,
+            ProofHash::Sha512 | ProofHash::Sha3_512 => 512,
+        }
+    }
+
+    fn hasher(&self) -> Box<dyn TranscriptHasher> {
+        match self {
+            ProofHash::Sha256 => Box::new(Sha256::new()),
+            ProofHash::Sha384 => Box::new(Sha384::new()),
+            ProofHash::Sha512 => Box::new(Sha512::new()),
+            ProofHash::Sha3_256 => Box::new(Sha3_256::new()),
+            ProofHash::Sha3_384 => Box::new(Sha3_384::new()),
+            ProofHash::Sha3_512 => Box::new(Sha3_512::new()),
+            ProofHash::Blake3 => Box::new(Blake3(blake3::Hasher::new())),
+        }
+    }
+}
+
+/// The few operations the transcript needs, BLAKE3 does not implement the RustCrypto traits.
+trait TranscriptHasher: Send + Sync {
+    fn update(&mut self, data: &[u8]);
+    fn finalize(&self) -> Vec<u8>;
+    fn box_clone(&self) -> Box<dyn TranscriptHasher>;
+}
+
+impl<D: Digest + Clone + Send + Sync + 'static> TranscriptHasher for D {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(self, data);
+    }
+
+    fn finalize(&self) -> Vec<u8> {
+        self.clone().finalize().to_vec()
+    }
+
+    fn box_clone(&self) -> Box<dyn TranscriptHasher> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Clone)]
+struct Blake3(blake3::Hasher);
+
+impl TranscriptHasher for Blake3 {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finalize(&self) -> Vec<u8> {
+        self.0.finalize().as_bytes().to_vec()
+    }
+
+    fn box_clone(&self) -> Box<dyn TranscriptHasher> {
+        Box::new(self.clone())
+    }
+}
+
+/// How the challenge of a proof of correctness is derived.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProofVersion {
+    /// SHA-256 over the concatenated values, the proofs made before the transcripts
+    Legacy,
+    Transcript {
+        hash: ProofHash,
+        challenge_bits: usize,
+    },
+}
+
+impl ProofVersion {
+    pub const CURRENT: ProofVersion = ProofVersion::Transcript {
+        hash: ProofHash::Sha256,
+        challenge_bits: DEFAULT_CHALLENGE_BITS,
+    };
+
+    /// Transcript proofs with the challenge as long as the output of the hash.
+    pub fn with_hash(hash: ProofHash) -> Self {
+        ProofVersion::Transcript {
+            hash,
+            challenge_bits: hash.output_bits(),
+        }
+    }
+
+    /// The proofs serialized without a version are the legacy ones.
+    pub(crate) fn legacy() -> Self {
+        ProofVersion::Legacy
+    }
+
+    pub fn challenge_bits(&self) -> usize {
+        match self {
+            ProofVersion::Legacy => 256,
+            ProofVersion::Transcript { challenge_bits, .. } => *challenge_bits,
+        }
+    }
+
+    pub fn hash(&self) -> ProofHash {
+        match self {
+            ProofVersion::Legacy => ProofHash::Sha256,
+            ProofVersion::Transcript { hash, .. } => *hash,
+        }
+    }
+
+    pub fn is_supported(&self) -> bool {
+        match self {
+            ProofVersion::Legacy => true,
+            ProofVersion::Transcript { challenge_bits, .. } => {
+                (MIN_CHALLENGE_BITS..=MAX_CHALLENGE_BITS).contains(challenge_bits)
+            }
+        }
+    }
+}
+
+/// The proof versions a verifier accepts. The prover writes the version into the proof, without a
+/// policy it could always pick the weakest one. The default accepts `ProofVersion::CURRENT` only.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProofPolicy {
+    accepted: Vec<ProofVersion>,
+}
+
+impl Default for ProofPolicy {
+    fn default() -> Self {
+        Self::only(ProofVersion::CURRENT)
+    }
+}
+
+impl ProofPolicy {
+    pub fn only(version: ProofVersion) -> Self {
+        ProofPolicy {
+            accepted: vec![version],
+        }
+    }
+
+    /// Accept the version as well, e.g. `ProofVersion::Legacy` while the signers migrate.
+    pub fn and(mut self, version: ProofVersion) -> Self {
+        if !self.accepted.contains(&version) {
+            self.accepted.push(version);
+        }
+        self
+    }
+
+    pub fn accepts(&self, version: &ProofVersion) -> bool {
+        version.is_supported() && self.accepted.contains(version)
+    }
+
+    /// The longest challenge of the accepted versions
+    pub(crate) fn max_challenge_bits(&self) -> usize {
+        self.accepted
+            .iter()
+            .map(ProofVersion::challenge_bits)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+pub struct Transcript {
+    hash: ProofHash,
+    hasher: Box<dyn TranscriptHasher>,
+}
+
+impl Clone for Transcript {
+    fn clone(&self) -> Self {
+        Transcript {
+            hash: self.hash,
+            hasher: self.hasher.box_clone(),
+        }
+    }
+}
+
+impl Transcript {
+    /// A SHA-256 transcript
+    pub fn new(domain: &[u8]) -> Self {
+        Self::with_hash(domain, ProofHash::Sha256)
+    }
+
+    pub fn with_hash(domain: &[u8], hash: ProofHash) -> Self {
+        let mut transcript = Transcript {
+            hash,
+            hasher: hash.hasher(),
+        };
+        transcript.append_message(b"domain", domain);
+        transcript
+    }
+
+    pub fn append_message(&mut self, label: &[u8], message: &[u8]) {
+        self.hasher.update(&(label.len() as u64).to_be_bytes());
+        self.hasher.update(label);
+        self.hasher.update(&(message.len() as u64).to_be_bytes());
+        self.hasher.update(message);
+    }
+
+    pub fn append_u64(&mut self, label: &[u8], value: u64) {
+        self.append_message(label, &value.to_be_bytes());
+    }
+
+    /// The minimal big-endian encoding, zero is the empty string.
+    pub fn append_biguint(&mut self, label: &[u8], value: &BigUint) {
+        let bytes = if value.bits() == 0 {
+            Vec::new()
+        } else {
+            value.to_bytes_be()
+        };
+        self.append_message(label, &bytes);
+    }
+
+    /// A challenge c < 2^bits, the length is absorbed before squeezing.
+    pub fn challenge(&mut self, label: &[u8], bits: usize) -> BigUint {
+        self.append_u64(label, bits as u64);
+        let state = self.hasher.finalize();
+
+        let length = bits.div_ceil(8);
+        let mut output = Vec::with_capacity(length + state.len());
+        let mut counter = 0u32;
+        while output.len() < length {
+            let mut block = self.hash.hasher();
+            block.update(&state);
+            block.update(&counter.to_be_bytes());
+            output.extend_from_slice(&block.finalize());
+            counter += 1;
+        }
+        output.truncate(length);
+        let excess = (8 - bits % 8) % 8;
+        output[0] &= 0xff >> excess;
+
+        self.hasher.update(&state);
+        BigUint::from_bytes_be(&output)
+    }
+}
+
+#[cfg(test)]
+#[allow(deprecated)]
+mod tests {
+    use super::*;
+    use crate::context::KeyContext;
+    use crate::format::Versioned;
+    use crate::{
+        deal, digest_msg, factorial, load_key, prove_raw_share, sign_with_share_versioned,
+        verify_proof, PaddingScheme, PartialMessageSignature,
+    };
+    use rsa::traits::PublicKeyParts;
+    use std::collections::HashSet;
+
+    #[test]
+    fn that_encoding_is_unambiguous() {
+        let challenge = |messages: &[&[u8]]| {
+            let mut transcript = Transcript::new(b"test");
+            for message in messages {
+                transcript.append_message(b"m", message);
+            }
+            transcript.challenge(b"c", 256)
+        };
+        // the plain concatenation of both is 0x010203
+        assert_ne!(challenge(&[&[1], &[2, 3]]), challenge(&[&[1, 2], &[3]]));
+        assert_eq!(challenge(&[&[1], &[2, 3]]), challenge(&[&[1], &[2, 3]]));
+    }
+
+    #[test]
+    fn that_challenge_has_the_requested_length() {
+        for bits in [128, 255, 256, 300, 1024] {
+            let mut transcript = Transcript::new(b"test");
+            let c = transcript.challenge(b"c", bits);
+            assert!(c.bits() <= bits);
+            assert!(c.bits() > bits - 32);
+            assert_ne!(c, transcript.challenge(b"c", bits));
+        }
+        assert!(!ProofVersion::Transcript {
+            hash: ProofHash::Sha512,
+            challenge_bits: 64
+        }
+        .is_supported());
+        assert!(ProofVersion::CURRENT.is_supported());
+    }
+
+    #[test]
+    fn that_other_versions_verify_only_when_accepted() {
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 3, 2);
+        let public_pkg = &public_pkgs[0];
+        let share = &secret_pkgs[0].share;
+        let vi = &public_pkg.verification_keys[0];
+        let n = public_pkg.public_key.n();
+        let size = public_pkg.public_key.size();
+        let delta = factorial(3);
+        let verify = |pms: &PartialMessageSignature| {
+            verify_proof(
+                b"ABC",
+                &public_pkg.v,
+                delta,
+                vi,
+                pms,
+                n,
+                size,
+                PaddingScheme::PKCS1v15,
+            )
+        };
+        let long_version = ProofVersion::Transcript {
+            hash: ProofHash::Sha256,
+            challenge_bits: 512,
+        };
+        let short_version = ProofVersion::Transcript {
+            hash: ProofHash::Sha256,
+            challenge_bits: 128,
+        };
+        let strict = KeyContext::new(public_pkg).unwrap();
+        let migrating = KeyContext::new(public_pkg).unwrap().with_proof_policy(
+            ProofPolicy::default()
+                .and(ProofVersion::Legacy)
+                .and(long_version),
+        );
+        let accepted = |context: &KeyContext, pms: &PartialMessageSignature| {
+            context.verify_proof(b"ABC", pms, PaddingScheme::PKCS1v15)
+        };
+
+        let x = digest_msg(b"ABC", PaddingScheme::PKCS1v15, n, size);
+        let mut legacy = prove_raw_share(
+            &x,
+            delta,
+            share,
+            &public_pkg.v,
+            vi,
+            None,
+            ProofVersion::Legacy,
+        );
+        assert!(!verify(&legacy));
+        assert!(!accepted(&strict, &legacy));
+        assert!(accepted(&migrating, &legacy));
+        // A proof serialized without the version is read as a legacy one
+        let mut json: serde_json::Value = serde_json::to_value(&legacy).unwrap();
+        json.as_object_mut().unwrap().remove("proof_version");
+        let unversioned: PartialMessageSignature = serde_json::from_value(json).unwrap();
+        assert!(!accepted(&strict, &unversioned));
+        assert!(accepted(&migrating, &unversioned));
+        legacy.proof_version = ProofVersion::CURRENT;
+        assert!(!accepted(&migrating, &legacy));
+
+        let signed = |version| {
+            sign_with_share_versioned(
+                b"ABC",
+                delta,
+                share,
+                &public_pkg.v,
+                vi,
+                PaddingScheme::PKCS1v15,
+                version,
+            )
+            .unwrap()
+        };
+        let long = signed(long_version);
+        assert!(long.c.bits() > 256);
+        assert!(!verify(&long));
+        assert!(!accepted(&strict, &long));
+        assert!(accepted(&migrating, &long));
+        let short = signed(short_version);
+        assert!(!accepted(&strict, &short));
+        assert!(!accepted(&migrating, &short));
+
+        let context = KeyContext::new(public_pkg)
+            .unwrap()
+            .with_proof_version(long_version)
+            .unwrap();
+        let pms = context
+            .sign(&secret_pkgs[1], b"ABC", PaddingScheme::PKCS1v15)
+            .unwrap();
+        assert_eq!(pms.proof_version, long_version);
+        assert!(accepted(&context, &pms));
+        assert!(accepted(&context, &long));
+        assert!(!accepted(&context, &signed(ProofVersion::CURRENT)));
+    }
+
+    #[test]
+    fn that_proofs_verify_with_every_hash() {
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 3, 2);
+        let public_pkg = &public_pkgs[0];
+        let strict = KeyContext::new(public_pkg).unwrap();
+        let mut challenges = HashSet::new();
+        for hash in [
+            ProofHash::Sha256,
+            ProofHash::Sha384,
+            ProofHash::Sha512,
+            ProofHash::Sha3_256,
+            ProofHash::Sha3_384,
+            ProofHash::Sha3_512,
+            ProofHash::Blake3,
+        ] {
+            let version = ProofVersion::with_hash(hash);
+            let context = KeyContext::new(public_pkg)
+                .unwrap()
+                .with_proof_policy(ProofPolicy::only(version));
+            let mut pms = sign_with_share_versioned(
+                b"ABC",
+                context.delta(),
+                &secret_pkgs[2].share,
+                &public_pkg.v,
+                &public_pkg.verification_keys[2],
+                PaddingScheme::PKCS1v15,
+                version,
+            )
+            .unwrap();
+            assert!(pms.c.bits() > hash.output_bits() - 32);
+            assert!(context.verify_proof(b"ABC", &pms, PaddingScheme::PKCS1v15));
+            // Only SHA-256 is accepted by default
+            assert_eq!(
+                strict.verify_proof(b"ABC", &pms, PaddingScheme::PKCS1v15),
+                hash == ProofHash::Sha256
+            );
+            // The hash is recorded in the serialized proof
+            let json = pms.to_json().unwrap();
+            assert_eq!(
+                PartialMessageSignature::from_json(&json)
+                    .unwrap()
+                    .proof_version,
+                version
+            );
+            challenges.insert(pms.c.clone());
+
+            pms.proof_version = ProofVersion::Transcript {
+                hash: if hash == ProofHash::Sha512 {
+                    ProofHash::Sha3_512
+                } else {
+                    ProofHash::Sha512
+                },
+                challenge_bits: hash.output_bits(),
+            };
+            assert!(!context.verify_proof(b"ABC", &pms, PaddingScheme::PKCS1v15));
+        }
+        assert_eq!(challenges.len(), 7);
+        // The hash is part of the version
+        assert!(
+            serde_json::from_str::<ProofVersion>(r#"{"Transcript":{"challenge_bits":256}}"#)
+                .is_err()
+        );
+    }
+}
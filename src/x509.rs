@@ -0,0 +1,500 @@
+// Certificates, certification requests and revocation lists signed by the threshold key.
+//
+// The coordinator builds the to-be-signed part, e.g. `certificate` for a CA run with a split key,
+// and hands its DER encoding, or just the `Prehash` of it, to the signers together with a session
+// id. The partial signatures are combined by `Unsigned::finish` into the signed structure, only the
+// proofs made in the session count. The signature is
+// sha*WithRSAEncryption, i.e. RSASSA-PKCS1-v1_5, so any X.509 implementation verifies it.
+
+use crate::context::KeyContext;
+use crate::optimistic::CombineError;
+use crate::prehash::{HashAlgorithm, Prehash, PrehashError, PrehashPadding};
+use crate::session::SessionId;
+use crate::{PartialMessageSignature, PublicPackage, SecretPackage, SigningError};
+use rsa::pkcs8::{EncodePublicKey, ObjectIdentifier};
+use rsa::Pkcs1v15Sign;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use x509_cert::attr::Attributes;
+use x509_cert::crl::{CertificateList, RevokedCert, TbsCertList};
+use x509_cert::der::asn1::{Any, BitString};
+use x509_cert::der::{Decode, Encode};
+use x509_cert::ext::Extension;
+use x509_cert::name::Name;
+use x509_cert::request::{CertReq, CertReqInfo};
+use x509_cert::serial_number::SerialNumber;
+use x509_cert::spki::{AlgorithmIdentifierOwned, SubjectPublicKeyInfoOwned};
+use x509_cert::time::{Time, Validity};
+use x509_cert::{Certificate, TbsCertificate, Version};
+
+/// RFC 4055
+const SHA_256_WITH_RSA_ENCRYPTION: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.11");
+const SHA_384_WITH_RSA_ENCRYPTION: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.12");
+const SHA_512_WITH_RSA_ENCRYPTION: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.13");
+
+#[derive(Error, Debug, Serialize, Deserialize)]
+pub enum X509Error {
+    #[error("DER error: {0}")]
+    Der(String),
+    #[error("Cannot sign: {0}")]
+    Prehash(String),
+    #[error("Signing error: {0}")]
+    Signing(String),
+    #[error("The to-be-signed part names another signature algorithm than the one used")]
+    AlgorithmMismatch,
+    #[error("Combining the partial signatures failed: {0}")]
+    Combine(String),
+    #[error("The signature does not verify with the threshold key")]
+    InvalidSignature,
+    #[error("Neither the content nor its digest was given")]
+    MissingContent,
+}
+
+impl From<x509_cert::der::Error> for X509Error {
+    fn from(e: x509_cert::der::Error) -> Self {
+        X509Error::Der(e.to_string())
+    }
+}
+
+impl From<x509_cert::spki::Error> for X509Error {
+    fn from(e: x509_cert::spki::Error) -> Self {
+        X509Error::Der(e.to_string())
+    }
+}
+
+impl From<PrehashError> for X509Error {
+    fn from(e: PrehashError) -> Self {
+        X509Error::Prehash(e.to_string())
+    }
+}
+
+impl From<SigningError> for X509Error {
+    fn from(e: SigningError) -> Self {
+        X509Error::Signing(e.to_string())
+    }
+}
+
+impl From<CombineError> for X509Error {
+    fn from(e: CombineError) -> Self {
+        X509Error::Combine(e.to_string())
+    }
+}
+
+/// sha*WithRSAEncryption with the NULL parameters
+pub fn signature_algorithm(hash_alg: HashAlgorithm) -> AlgorithmIdentifierOwned {
+    AlgorithmIdentifierOwned {
+        oid: match hash_alg {
+            HashAlgorithm::Sha256 => SHA_256_WITH_RSA_ENCRYPTION,
+            HashAlgorithm::Sha384 => SHA_384_WITH_RSA_ENCRYPTION,
+            HashAlgorithm::Sha512 => SHA_512_WITH_RSA_ENCRYPTION,
+        },
+        parameters: Some(Any::null()),
+    }
+}
+
+pub fn subject_public_key_info(
+    public_pkg: &PublicPackage,
+) -> Result<SubjectPublicKeyInfoOwned, X509Error> {
+    let der = public_pkg.public_key.to_public_key_der()?;
+    Ok(SubjectPublicKeyInfoOwned::from_der(der.as_bytes())?)
+}
+
+/// The to-be-signed part of a certificate, a certification request or a CRL.
+pub trait ToBeSigned: Encode {
+    type Signed;
+
+    /// The copy of the signature algorithm inside the signed bytes, e.g. a request has none.
+    fn algorithm(&self) -> Option<&AlgorithmIdentifierOwned>;
+
+    fn assemble(
+        self,
+        algorithm: AlgorithmIdentifierOwned,
+        signature: BitString,
+    ) -> Result<Self::Signed, X509Error>;
+}
+
+impl ToBeSigned for TbsCertificate {
+    type Signed = Certificate;
+
+    fn algorithm(&self) -> Option<&AlgorithmIdentifierOwned> {
+        Some(&self.signature)
+    }
+
+    fn assemble(
+        self,
+        algorithm: AlgorithmIdentifierOwned,
+        signature: BitString,
+    ) -> Result<Certificate, X509Error> {
+        Ok(Certificate {
+            tbs_certificate: self,
+            signature_algorithm: algorithm,
+            signature,
+        })
+    }
+}
+
+impl ToBeSigned for CertReqInfo {
+    type Signed = CertReq;
+
+    fn algorithm(&self) -> Option<&AlgorithmIdentifierOwned> {
+        None
+    }
+
+    fn assemble(
+        self,
+        algorithm: AlgorithmIdentifierOwned,
+        signature: BitString,
+    ) -> Result<CertReq, X509Error> {
+        Ok(CertReq {
+            info: self,
+            algorithm,
+            signature,
+        })
+    }
+}
+
+impl ToBeSigned for TbsCertList {
+    type Signed = CertificateList;
+
+    fn algorithm(&self) -> Option<&AlgorithmIdentifierOwned> {
+        Some(&self.signature)
+    }
+
+    fn assemble(
+        self,
+        algorithm: AlgorithmIdentifierOwned,
+        signature: BitString,
+    ) -> Result<CertificateList, X509Error> {
+        Ok(CertificateList {
+            tbs_cert_list: self,
+            signature_algorithm: algorithm,
+            signature,
+        })
+    }
+}
+
+/// Waits for the signature of the threshold key.
+#[derive(Debug, Clone)]
+pub struct Unsigned<T> {
+    tbs: T,
+    hash_alg: HashAlgorithm,
+}
+
+impl<T: ToBeSigned> Unsigned<T> {
+    /// The algorithm named in `tbs` has to be `signature_algorithm(hash_alg)`, otherwise the
+    /// signing fails.
+    pub fn new(tbs: T, hash_alg: HashAlgorithm) -> Self {
+        Unsigned { tbs, hash_alg }
+    }
+
+    pub fn tbs(&self) -> &T {
+        &self.tbs
+    }
+
+    fn check_algorithm(&self) -> Result<(), X509Error> {
+        match self.tbs.algorithm() {
+            Some(algorithm) if algorithm != &signature_algorithm(self.hash_alg) => {
+                Err(X509Error::AlgorithmMismatch)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The bytes the signers sign, for them to review.
+    pub fn to_be_signed(&self) -> Result<Vec<u8>, X509Error> {
+        Ok(self.tbs.to_der()?)
+    }
+
+    /// The signers need only this, see `prehash`.
+    pub fn prehash(&self) -> Result<Prehash, X509Error> {
+        self.check_algorithm()?;
+        Ok(Prehash::from_reader(
+            self.hash_alg,
+            self.to_be_signed()?.as_slice(),
+            PrehashPadding::Pkcs1v15,
+        )?)
+    }
+
+    pub fn sign(
+        &self,
+        context: &KeyContext,
+        secret_pkg: &SecretPackage,
+        session_id: &SessionId,
+    ) -> Result<PartialMessageSignature, X509Error> {
+        Ok(context.sign_prehash_in_session(secret_pkg, &self.prehash()?, session_id)?)
+    }
+
+    /// Combine the partial signatures, those with an invalid proof or made in another session are
+    /// left out.
+    pub fn finish(
+        self,
+        context: &KeyContext,
+        partials: &[PartialMessageSignature],
+        session_id: &SessionId,
+    ) -> Result<T::Signed, X509Error> {
+        let signature =
+            context.combine_prehash_in_session(&self.prehash()?, partials, session_id)?;
+        self.assemble(&signature)
+    }
+
+    /// For a signature combined elsewhere, it is verified with the public key first.
+    pub fn finish_with_signature(
+        self,
+        public_pkg: &PublicPackage,
+        signature: &[u8],
+    ) -> Result<T::Signed, X509Error> {
+        let prehash = self.prehash()?;
+        let scheme = Pkcs1v15Sign {
+            hash_len: Some(prehash.digest.len()),
+            prefix: self.hash_alg.digest_info_prefix(),
+        };
+        public_pkg
+            .public_key
+            .verify(scheme, &prehash.digest, signature)
+            .map_err(|_| X509Error::InvalidSignature)?;
+        self.assemble(signature)
+    }
+
+    fn assemble(self, signature: &[u8]) -> Result<T::Signed, X509Error> {
+        self.check_algorithm()?;
+        let algorithm = signature_algorithm(self.hash_alg);
+        self.tbs
+            .assemble(algorithm, BitString::from_bytes(signature)?)
+    }
+}
+
+/// A certificate issued by the threshold key, `subject_public_key_info` of the threshold key
+/// itself and the same name as the issuer give the self-signed root of the CA.
+pub fn certificate(
+    issuer: Name,
+    subject: Name,
+    subject_public_key_info: SubjectPublicKeyInfoOwned,
+    serial_number: SerialNumber,
+    validity: Validity,
+    extensions: Vec<Extension>,
+    hash_alg: HashAlgorithm,
+) -> Unsigned<TbsCertificate> {
+    Unsigned::new(
+        TbsCertificate {
+            version: Version::V3,
+            serial_number,
+            signature: signature_algorithm(hash_alg),
+            issuer,
+            validity,
+            subject,
+            subject_public_key_info,
+            issuer_unique_id: None,
+            subject_unique_id: None,
+            extensions: (!extensions.is_empty()).then_some(extensions),
+        },
+        hash_alg,
+    )
+}
+
+/// A PKCS#10 request for a certificate of the threshold key.
+pub fn certification_request(
+    public_pkg: &PublicPackage,
+    subject: Name,
+    attributes: Attributes,
+    hash_alg: HashAlgorithm,
+) -> Result<Unsigned<CertReqInfo>, X509Error> {
+    Ok(Unsigned::new(
+        CertReqInfo {
+            version: x509_cert::request::Version::V1,
+            subject,
+            public_key: subject_public_key_info(public_pkg)?,
+            attributes,
+        },
+        hash_alg,
+    ))
+}
+
+pub fn certificate_revocation_list(
+    issuer: Name,
+    this_update: Time,
+    next_update: Option<Time>,
+    revoked_certificates: Vec<RevokedCert>,
+    extensions: Vec<Extension>,
+    hash_alg: HashAlgorithm,
+) -> Unsigned<TbsCertList> {
+    Unsigned::new(
+        TbsCertList {
+            version: Version::V2,
+            signature: signature_algorithm(hash_alg),
+            issuer,
+            this_update,
+            next_update,
+            revoked_certificates: (!revoked_certificates.is_empty())
+                .then_some(revoked_certificates),
+            crl_extensions: (!extensions.is_empty()).then_some(extensions),
+        },
+        hash_alg,
+    )
+}
+
+/// Every share of `secret_pkgs` signs in a fresh session, for the tests of the signed structures.
+#[cfg(test)]
+use crate::session::random_session_id;
+
+#[cfg(test)]
+pub(crate) fn quorum_sign<T: ToBeSigned>(
+    public_pkg: &PublicPackage,
+    secret_pkgs: &[SecretPackage],
+    unsigned: Unsigned<T>,
+) -> T::Signed {
+    let context = KeyContext::new(public_pkg).unwrap();
+    let session_id = random_session_id();
+    let partials: Vec<PartialMessageSignature> = secret_pkgs
+        .iter()
+        .map(|secret_pkg| unsigned.sign(&context, secret_pkg, &session_id).unwrap())
+        .collect();
+    unsigned.finish(&context, &partials, &session_id).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deal, load_key};
+    use rsa::pkcs1v15::{Signature, VerifyingKey};
+    use rsa::pkcs8::DecodePublicKey;
+    use rsa::signature::Verifier;
+    use rsa::RsaPublicKey;
+    use sha2::{Sha256, Sha384};
+    use std::str::FromStr;
+    use std::time::Duration;
+    use x509_cert::crl::RevokedCert;
+    use x509_cert::der::asn1::OctetString;
+    use x509_cert::der::oid::AssociatedOid;
+    use x509_cert::ext::pkix::BasicConstraints;
+
+    fn public_key(spki: &SubjectPublicKeyInfoOwned) -> RsaPublicKey {
+        RsaPublicKey::from_public_key_der(&spki.to_der().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn that_self_signed_certificate_verifies() {
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 3, 2);
+        let public_pkg = &public_pkgs[0];
+        let name = Name::from_str("CN=Pretzel Root CA,O=Pretzel").unwrap();
+        let basic_constraints = BasicConstraints {
+            ca: true,
+            path_len_constraint: None,
+        };
+        let unsigned = certificate(
+            name.clone(),
+            name,
+            subject_public_key_info(&public_pkgs[0]).unwrap(),
+            SerialNumber::new(&[1]).unwrap(),
+            Validity::from_now(Duration::from_secs(3600)).unwrap(),
+            vec![Extension {
+                extn_id: BasicConstraints::OID,
+                critical: true,
+                extn_value: OctetString::new(basic_constraints.to_der().unwrap()).unwrap(),
+            }],
+            HashAlgorithm::Sha256,
+        );
+        // The certificate would name SHA-256 and be signed with SHA-384
+        let mismatched = Unsigned::new(unsigned.tbs().clone(), HashAlgorithm::Sha384);
+        assert!(matches!(
+            mismatched.prehash(),
+            Err(X509Error::AlgorithmMismatch)
+        ));
+        let certificate = quorum_sign(public_pkg, &secret_pkgs[1..], unsigned);
+        assert!(matches!(
+            mismatched.finish_with_signature(public_pkg, certificate.signature.raw_bytes()),
+            Err(X509Error::AlgorithmMismatch)
+        ));
+
+        let certificate = Certificate::from_der(&certificate.to_der().unwrap()).unwrap();
+        let tbs = &certificate.tbs_certificate;
+        let key = VerifyingKey::<Sha256>::new(public_key(&tbs.subject_public_key_info));
+        let signature = Signature::try_from(certificate.signature.raw_bytes()).unwrap();
+        assert!(key.verify(&tbs.to_der().unwrap(), &signature).is_ok());
+        assert_eq!(
+            certificate.signature_algorithm,
+            signature_algorithm(HashAlgorithm::Sha256)
+        );
+    }
+
+    #[test]
+    fn that_certification_request_verifies() {
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 3, 2);
+        let public_pkg = &public_pkgs[0];
+        let unsigned = certification_request(
+            &public_pkgs[0],
+            Name::from_str("CN=pretzel.example").unwrap(),
+            Attributes::new(),
+            HashAlgorithm::Sha256,
+        )
+        .unwrap();
+        let request = quorum_sign(public_pkg, &secret_pkgs[1..], unsigned);
+
+        let request = CertReq::from_der(&request.to_der().unwrap()).unwrap();
+        let key = VerifyingKey::<Sha256>::new(public_key(&request.info.public_key));
+        let signature = Signature::try_from(request.signature.raw_bytes()).unwrap();
+        assert!(key
+            .verify(&request.info.to_der().unwrap(), &signature)
+            .is_ok());
+    }
+
+    #[test]
+    fn that_revocation_list_verifies() {
+        let (secret_pkgs, public_pkgs) = deal(&load_key().unwrap(), 3, 2);
+        let public_pkg = &public_pkgs[0];
+        let issuer = Name::from_str("CN=Pretzel Root CA,O=Pretzel").unwrap();
+        let validity = Validity::from_now(Duration::from_secs(3600)).unwrap();
+        let unsigned = certificate_revocation_list(
+            issuer,
+            validity.not_before,
+            Some(validity.not_after),
+            vec![RevokedCert {
+                serial_number: SerialNumber::new(&[2]).unwrap(),
+                revocation_date: validity.not_before,
+                crl_entry_extensions: None,
+            }],
+            Vec::new(),
+            HashAlgorithm::Sha384,
+        );
+        let unsigned_copy = unsigned.clone();
+
+        // The partial signatures of another session are left out
+        let context = KeyContext::new(public_pkg).unwrap();
+        let partials: Vec<PartialMessageSignature> = secret_pkgs[1..]
+            .iter()
+            .map(|secret_pkg| {
+                unsigned
+                    .sign(&context, secret_pkg, &random_session_id())
+                    .unwrap()
+            })
+            .collect();
+        assert!(matches!(
+            unsigned
+                .clone()
+                .finish(&context, &partials, &random_session_id()),
+            Err(X509Error::Combine(_))
+        ));
+        let crl = quorum_sign(public_pkg, &secret_pkgs[1..], unsigned);
+
+        let crl = CertificateList::from_der(&crl.to_der().unwrap()).unwrap();
+        let key = VerifyingKey::<Sha384>::new(public_pkg.public_key.clone());
+        let signature = Signature::try_from(crl.signature.raw_bytes()).unwrap();
+        assert!(key
+            .verify(&crl.tbs_cert_list.to_der().unwrap(), &signature)
+            .is_ok());
+
+        // A signature combined elsewhere is checked before it is attached
+        assert!(unsigned_copy
+            .clone()
+            .finish_with_signature(public_pkg, crl.signature.raw_bytes())
+            .is_ok());
+        let mut tampered = crl.signature.raw_bytes().to_vec();
+        tampered[0] ^= 1;
+        assert!(matches!(
+            unsigned_copy.finish_with_signature(public_pkg, &tampered),
+            Err(X509Error::InvalidSignature)
+        ));
+    }
+}
@@ -0,0 +1,159 @@
+// The signing ceremony run through the binary, with the files of every role in a temporary
+// directory.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn pretzel(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_pretzel"))
+        .current_dir(dir)
+        .env("PRETZEL_PASSWORD", "correct horse")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+fn ceremony_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("pretzel-cli-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn that_ceremony_runs_through_the_binary() {
+    let dir = ceremony_dir();
+    fs::write(dir.join("message.txt"), b"release 1.0").unwrap();
+    fs::write(dir.join("other.txt"), b"release 1.1").unwrap();
+
+    let keygen = stdout(&pretzel(
+        &dir,
+        &[
+            "keygen", "--n", "3", "--k", "2", "--bits", "512", "--out", "ceremony",
+        ],
+    ));
+    assert_eq!(keygen.lines().count(), 4);
+    assert!(dir.join("ceremony/public.json").exists());
+
+    let session = stdout(&pretzel(&dir, &["session"])).trim().to_owned();
+    assert_eq!(session.len(), 64);
+    let other_session = stdout(&pretzel(&dir, &["session"])).trim().to_owned();
+    assert_ne!(session, other_session);
+
+    let verify_partial = |message: &str, session: &str, partial: &str| {
+        pretzel(
+            &dir,
+            &[
+                "verify-partial",
+                "--public",
+                "ceremony/public.json",
+                "--message",
+                message,
+                "--session",
+                session,
+                "--partial",
+                partial,
+            ],
+        )
+    };
+    for id in ["1", "3"] {
+        let share = format!("ceremony/share-{id}.enc");
+        let partial = stdout(&pretzel(
+            &dir,
+            &[
+                "sign",
+                "--share",
+                &share,
+                "--public",
+                "ceremony/public.json",
+                "--message",
+                "message.txt",
+                "--session",
+                &session,
+            ],
+        ));
+        let partial = partial.trim();
+        assert_eq!(partial, format!("partial-{id}.json"));
+
+        let verified = verify_partial("message.txt", &session, partial);
+        assert_eq!(stdout(&verified).trim(), "OK");
+        let rejected = verify_partial("other.txt", &session, partial);
+        assert_eq!(rejected.status.code(), Some(1));
+        // The proof does not carry over to another session
+        let replayed = verify_partial("message.txt", &other_session, partial);
+        assert_eq!(replayed.status.code(), Some(1));
+        // The verifier picks the hash of the proofs, not the signer
+        let other_hash = pretzel(
+            &dir,
+            &[
+                "verify-partial",
+                "--public",
+                "ceremony/public.json",
+                "--message",
+                "message.txt",
+                "--session",
+                &session,
+                "--proof-hash",
+                "sha3-256",
+                "--partial",
+                partial,
+            ],
+        );
+        assert_eq!(other_hash.status.code(), Some(1));
+    }
+
+    let combine = |session: &str, partials: &[&str]| {
+        let mut args = vec![
+            "combine",
+            "--public",
+            "ceremony/public.json",
+            "--message",
+            "message.txt",
+            "--session",
+            session,
+        ];
+        args.extend(partials);
+        pretzel(&dir, &args)
+    };
+    // One partial signature is below the threshold
+    assert_eq!(
+        combine(&session, &["partial-1.json"]).status.code(),
+        Some(2)
+    );
+    assert_eq!(
+        combine(&other_session, &["partial-1.json", "partial-3.json"])
+            .status
+            .code(),
+        Some(2)
+    );
+    stdout(&combine(&session, &["partial-1.json", "partial-3.json"]));
+
+    let verify = |message: &str| {
+        pretzel(
+            &dir,
+            &[
+                "verify",
+                "--public",
+                "ceremony/public.json",
+                "--message",
+                message,
+                "--signature",
+                "signature.bin",
+            ],
+        )
+    };
+    assert_eq!(stdout(&verify("message.txt")).trim(), "OK");
+    assert_eq!(verify("other.txt").status.code(), Some(1));
+
+    fs::remove_dir_all(dir).unwrap();
+}